@@ -1,15 +1,305 @@
-use actix_session::SessionExt as _;
+use std::{
+	fmt::Display,
+	io::Write as _,
+	path::{Path, PathBuf},
+	pin::Pin,
+	rc::Rc,
+	str::FromStr,
+	sync::LazyLock,
+};
+
+use actix_session::{Session, SessionExt as _};
 use actix_web::{
-	HttpResponse,
+	FromRequest, HttpMessage as _, HttpRequest, HttpResponse,
 	body::{BoxBody, EitherBody},
 	dev::{Service, ServiceRequest, ServiceResponse, Transform},
+	error::*,
+	http::header,
+};
+use argon2::{
+	Argon2, PasswordHasher as _, PasswordVerifier as _,
+	password_hash::{SaltString, rand_core::OsRng},
 };
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Local;
 use futures_util::future::{LocalBoxFuture, Ready, ok};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool, prelude::FromRow};
 
 const ADMIN_KEY: &str = "admin";
-const AUTHORIZE: &str = "Authorize";
 
-pub struct AdminGuardMiddleware(pub String);
+/// 管理者の権限　下位の権限でできることは上位でもできる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	/// 閲覧のみ
+	Viewer,
+	/// ユーザー・投稿の編集、利用停止など
+	Moderator,
+	/// サーバー状態・設定・管理者アカウントの管理
+	Owner,
+}
+impl Display for Role {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Viewer => "viewer",
+			Self::Moderator => "moderator",
+			Self::Owner => "owner",
+		})
+	}
+}
+impl FromStr for Role {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"viewer" => Self::Viewer,
+			"moderator" => Self::Moderator,
+			"owner" => Self::Owner,
+			_ => return Err(()),
+		})
+	}
+}
+
+/// セッションに保持する管理者ログイン
+#[derive(Serialize, Deserialize)]
+struct AdminSession {
+	name: String,
+	/// ログイン時の`admin.stamp`　資格情報が変わったら一致しなくなる
+	stamp: String,
+	expiry: i64,
+}
+
+/// ログイン中の管理者
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table admin
+///     name text pk
+///     password text          # ハッシュ化済み
+///     role text              # owner, moderator, viewer
+///     stamp text             # パスワード・権限を変更するたびに作り直す　古い管理セッションを無効にするため
+///     created timestamp
+/// ```
+#[derive(Clone, FromRow, Serialize)]
+pub struct Admin {
+	pub name: String,
+	#[sqlx(try_from = "String")]
+	pub role: Role,
+}
+impl TryFrom<String> for Role {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		Role::from_str(&value).map_err(|_| format!("invalid role: {value}"))
+	}
+}
+
+impl Admin {
+	/// 管理セッションの有効期間(秒)　ユーザーのセッションとは別に切れる
+	pub const TTL: i64 = 8 * 3600;
+
+	/// 名前とパスワードを照合し、管理セッションを開始する
+	pub async fn login(pool: &SqlitePool, session: &Session, name: &str, password: &str) -> Result<Option<Self>, actix_web::Error> {
+		let Some((role, stamp)) = Self::authenticate(pool, name, password).await? else {
+			return Ok(None);
+		};
+		let role = Role::from_str(&role).map_err(|_| ErrorInternalServerError("権限が不正です"))?;
+		session.renew();
+		session.insert(
+			ADMIN_KEY,
+			AdminSession {
+				name: name.into(),
+				stamp,
+				expiry: Local::now().timestamp() + Self::TTL,
+			},
+		)?;
+		Ok(Some(Self { name: name.into(), role }))
+	}
+	/// パスワードが正しいか（パスワード変更時の本人確認用）
+	pub async fn verify_password(pool: &SqlitePool, name: &str, password: &str) -> Result<bool, actix_web::Error> {
+		Ok(Self::authenticate(pool, name, password).await?.is_some())
+	}
+	/// 照合に成功したら権限とスタンプを返す
+	async fn authenticate(pool: &SqlitePool, name: &str, password: &str) -> Result<Option<(String, String)>, actix_web::Error> {
+		// 存在しない名前でも同じだけ時間をかけるためのダミー
+		static DUMMY: LazyLock<String> = LazyLock::new(|| hash("dummy").unwrap_or_default());
+
+		let record = sqlx::query_as::<_, (String, String, String)>("SELECT password,role,stamp FROM admin WHERE name=?")
+			.bind(name)
+			.fetch_optional(pool)
+			.await
+			.map_err(ErrorInternalServerError)?;
+		let verified = verify(password, record.as_ref().map_or(DUMMY.as_str(), |x| &x.0));
+		Ok(record.filter(|_| verified).map(|(_, role, stamp)| (role, stamp)))
+	}
+	/// 管理セッションを終了する（ユーザーとしてのログインはそのまま）
+	pub fn logout(session: &Session) {
+		session.remove(ADMIN_KEY);
+	}
+
+	/// 管理者一覧
+	pub async fn list(pool: &SqlitePool) -> Result<Vec<Self>, sqlx::Error> {
+		sqlx::query_as("SELECT name,role FROM admin ORDER BY name ASC").fetch_all(pool).await
+	}
+	/// 管理者を作成する
+	pub async fn create(pool: &SqlitePool, name: &str, password: &str, role: Role) -> Result<(), actix_web::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query("INSERT INTO admin(name,password,role,stamp,created) VALUES(?,?,?,?,?)")
+			.bind(name)
+			.bind(hash(password)?)
+			.bind(role.to_string())
+			.bind(stamp())
+			.bind(now)
+			.execute(pool)
+			.await
+			.map_err(|err| match err {
+				sqlx::Error::Database(err) if err.is_unique_violation() => ErrorConflict("管理者名が重複しています"),
+				err => ErrorInternalServerError(err),
+			})?;
+		Ok(())
+	}
+	/// パスワードを変更する　その管理者の他の管理セッションは無効になる
+	pub async fn set_password(pool: &SqlitePool, name: &str, password: &str) -> Result<bool, actix_web::Error> {
+		let result = sqlx::query("UPDATE admin SET password=?,stamp=? WHERE name=?")
+			.bind(hash(password)?)
+			.bind(stamp())
+			.bind(name)
+			.execute(pool)
+			.await
+			.map_err(ErrorInternalServerError)?;
+		Ok(result.rows_affected() > 0)
+	}
+	/// 権限を変更し、変更前の権限を返す（存在しなければNone）　その管理者の管理セッションは無効になる
+	///
+	/// 変更前の値を読んでから書き換えるので、トランザクション内で呼ぶこと
+	pub async fn set_role(conn: &mut SqliteConnection, name: &str, role: Role) -> Result<Option<String>, sqlx::Error> {
+		let Some(before) = sqlx::query_scalar("SELECT role FROM admin WHERE name=?").bind(name).fetch_optional(&mut *conn).await? else {
+			return Ok(None);
+		};
+		sqlx::query("UPDATE admin SET role=?,stamp=? WHERE name=?").bind(role.to_string()).bind(stamp()).bind(name).execute(&mut *conn).await?;
+		Ok(Some(before))
+	}
+	/// 管理者を削除する
+	pub async fn delete(pool: &SqlitePool, name: &str) -> Result<bool, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM admin WHERE name=?").bind(name).execute(pool).await?;
+		Ok(result.rows_affected() > 0)
+	}
+	/// 管理者が1人もいなければ初期のオーナー`admin`を作成し、パスワードを書き出したファイルのパスを返す
+	///
+	/// パスワードはログに残らないよう、標準出力ではなく所有者のみ読めるファイル(0600)に書き出す
+	/// 保存先は環境変数`ADMIN_PASSWORD_FILE`（既定はDBと同じディレクトリのadmin-password.txt）　ログインしたら削除すること
+	pub async fn bootstrap(pool: &SqlitePool, db_url: &str) -> Result<Option<PathBuf>, actix_web::Error> {
+		let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM admin").fetch_one(pool).await.map_err(ErrorInternalServerError)?;
+		if count > 0 {
+			return Ok(None);
+		}
+		let path = std::env::var("ADMIN_PASSWORD_FILE").map(PathBuf::from).unwrap_or_else(|_| {
+			let db = crate::backup::database_path(db_url).unwrap_or_default();
+			db.parent().unwrap_or(Path::new(".")).join("admin-password.txt")
+		});
+		let password = BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 18]>());
+		// 書き出せなければパスワードが分からなくなるので、作成より先に書く
+		write_secret(&path, &format!("name=admin\npassword={password}\n")).map_err(ErrorInternalServerError)?;
+		Self::create(pool, "admin", &password, Role::Owner).await?;
+		Ok(Some(path))
+	}
+
+	/// セッションから管理者を読み込む　期限切れや資格情報の変更があればNone
+	async fn load(pool: &SqlitePool, session: &Session) -> Result<Option<Self>, actix_web::Error> {
+		let Some(admin) = session.get::<AdminSession>(ADMIN_KEY).ok().flatten() else {
+			return Ok(None);
+		};
+		if admin.expiry <= Local::now().timestamp() {
+			session.remove(ADMIN_KEY);
+			return Ok(None);
+		}
+		let record = sqlx::query_as::<_, (String, String)>("SELECT role,stamp FROM admin WHERE name=?")
+			.bind(&admin.name)
+			.fetch_optional(pool)
+			.await
+			.map_err(ErrorInternalServerError)?;
+		match record {
+			Some((role, stamp)) if constant_time_eq(stamp.as_bytes(), admin.stamp.as_bytes()) => Ok(Some(Self {
+				name: admin.name,
+				role: Role::from_str(&role).map_err(|_| ErrorInternalServerError("権限が不正です"))?,
+			})),
+			_ => {
+				session.remove(ADMIN_KEY);
+				Ok(None)
+			}
+		}
+	}
+	/// 権限が足りているか
+	pub fn require(&self, role: Role) -> Result<(), actix_web::Error> {
+		if self.role >= role {
+			Ok(())
+		} else {
+			Err(ErrorForbidden(format!("{role}以上の権限が必要です")))
+		}
+	}
+}
+
+/// `AdminGuardMiddleware`の内側ではリクエストから、外側ではセッションから読み込む
+impl FromRequest for Admin {
+	type Error = actix_web::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+	fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+		let admin = req.extensions().get::<Self>().cloned();
+		let pool = req.app_data::<actix_web::web::Data<SqlitePool>>().cloned();
+		let session = req.get_session();
+		Box::pin(async move {
+			if let Some(admin) = admin {
+				return Ok(admin);
+			}
+			let pool = pool.ok_or(ErrorInternalServerError("DBが未定義"))?;
+			Self::load(pool.as_ref(), &session).await?.ok_or(ErrorUnauthorized("管理者としてログインしてください"))
+		})
+	}
+}
+
+fn hash(password: &str) -> Result<String, actix_web::Error> {
+	Ok(Argon2::default()
+		.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+		.map_err(ErrorInternalServerError)?
+		.to_string())
+}
+fn verify(password: &str, hashed: &str) -> bool {
+	argon2::PasswordHash::new(hashed).is_ok_and(|x| Argon2::default().verify_password(password.as_bytes(), &x).is_ok())
+}
+fn stamp() -> String {
+	BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>())
+}
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+/// 所有者のみ読み書きできるファイルとして書き出す　既にあれば作り直す（権限を引き継がないように）
+fn write_secret(path: &Path, content: &str) -> std::io::Result<()> {
+	match std::fs::remove_file(path) {
+		Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+		_ => {}
+	}
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	options.open(path)?.write_all(content.as_bytes())
+}
+
+/// 管理画面全体に掛けるガード
+///
+/// 管理者としてログインしていなければログイン画面(`{login}`)へ送る
+/// 権限は既定でGET/HEADならviewer、それ以外はmoderatorを要求する　それ以上が必要なルートは`RequireRole`を付ける
+pub struct AdminGuardMiddleware {
+	pool: SqlitePool,
+	login: &'static str,
+}
+impl AdminGuardMiddleware {
+	pub fn new(pool: SqlitePool, login: &'static str) -> Self {
+		Self { pool, login }
+	}
+}
 
 impl<S, B> Transform<S, ServiceRequest> for AdminGuardMiddleware
 where
@@ -23,13 +313,18 @@ where
 	type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
 	fn new_transform(&self, service: S) -> Self::Future {
-		ok(AdminGuardMiddlewareImpl { service, key: self.0.clone() })
+		ok(AdminGuardMiddlewareImpl {
+			service: Rc::new(service),
+			pool: self.pool.clone(),
+			login: self.login,
+		})
 	}
 }
 
 pub struct AdminGuardMiddlewareImpl<S> {
-	service: S,
-	key: String,
+	service: Rc<S>,
+	pool: SqlitePool,
+	login: &'static str,
 }
 
 impl<S, B> Service<ServiceRequest> for AdminGuardMiddlewareImpl<S>
@@ -46,16 +341,81 @@ where
 	}
 
 	fn call(&self, req: ServiceRequest) -> Self::Future {
-		let session = req.get_session();
-		if let Some(Ok(value)) = req.headers().get(AUTHORIZE).map(|x| x.to_str()) {
-			let _ = session.insert(ADMIN_KEY, value);
-		}
-		if let Ok(Some(admin)) = session.get::<String>(ADMIN_KEY) {
-			if admin == self.key {
-				let fut = self.service.call(req);
-				return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+		let service = self.service.clone();
+		let pool = self.pool.clone();
+		let login = self.login;
+		Box::pin(async move {
+			let session = req.get_session();
+			let Some(admin) = Admin::load(&pool, &session).await? else {
+				let res = if req.method().is_safe() {
+					HttpResponse::SeeOther().insert_header((header::LOCATION, login)).finish()
+				} else {
+					HttpResponse::Unauthorized().body("管理者としてログインしてください")
+				};
+				return Ok(req.into_response(res.map_into_right_body()));
+			};
+			let role = if req.method().is_safe() { Role::Viewer } else { Role::Moderator };
+			if let Err(err) = admin.require(role) {
+				return Ok(req.error_response(err).map_into_right_body());
 			}
+			req.extensions_mut().insert(admin);
+			Ok(service.call(req).await?.map_into_left_body())
+		})
+	}
+}
+
+/// ルート単位で必要な権限を宣言する　`AdminGuardMiddleware`の内側で使う
+///
+/// # Example
+/// ```ignore
+/// cfg.service(web::resource("state").get(confirm).route(web::post().to(state).wrap(RequireRole(Role::Owner))));
+/// ```
+pub struct RequireRole(pub Role);
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type InitError = ();
+	type Transform = RequireRoleImpl<S>;
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(RequireRoleImpl { service, role: self.0 })
+	}
+}
+
+pub struct RequireRoleImpl<S> {
+	service: S,
+	role: Role,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleImpl<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&self, ctx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+		self.service.poll_ready(ctx)
+	}
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		// ガードの外で使われた場合も通さない
+		let result = match req.extensions().get::<Admin>() {
+			Some(admin) => admin.require(self.role),
+			None => Err(ErrorUnauthorized("管理者としてログインしてください")),
+		};
+		if let Err(err) = result {
+			return Box::pin(async move { Ok(req.error_response(err).map_into_right_body()) });
 		}
-		Box::pin(async { Ok(req.into_response(HttpResponse::Forbidden().body("Access denied.").map_into_right_body())) })
+		let fut = self.service.call(req);
+		Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
 	}
 }
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use actix_web::{HttpResponse, Responder, error::*, http::StatusCode, web};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use validation::Validation;
+
+use super::{AdminPage, redirect};
+use crate::{Admin, Auditor, Role};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).post(create));
+	cfg.service(web::resource("{name}/role").post(role));
+	cfg.service(web::resource("{name}/password").post(password));
+	cfg.service(web::resource("{name}/delete").post(delete));
+}
+
+/// 自分自身の権限変更・削除はさせない（オーナーが居なくなるのを防ぐ）
+fn not_self(admin: &Admin, name: &str) -> Result<(), actix_web::Error> {
+	if admin.name == name {
+		Err(ErrorForbidden("自分自身は変更できません"))
+	} else {
+		Ok(())
+	}
+}
+
+fn parse_role(role: &str) -> Result<Role, actix_web::Error> {
+	Role::from_str(role).map_err(|_| ErrorBadRequest("無効な権限が指定されました"))
+}
+
+// 管理者一覧
+async fn list(admin: Admin, page: web::Data<AdminPage>, pool: web::Data<SqlitePool>) -> Result<impl Responder, actix_web::Error> {
+	let admins = Admin::list(pool.as_ref()).await.map_err(ErrorInternalServerError)?;
+	page.render(
+		StatusCode::OK,
+		false,
+		include_str!("../../template/admin/account.html"),
+		&liquid::object!({ "admins": &admins, "me": &admin.name }),
+	)
+}
+
+// 作成
+#[derive(Deserialize, Validation)]
+struct Create {
+	#[validation(name = "管理者名", min = 1, max = 32)]
+	name: String,
+	#[validation(name = "パスワード", min = 8)]
+	password: String,
+	role: String,
+}
+async fn create(web::Form(info): web::Form<Create>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> Result<HttpResponse, actix_web::Error> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let role = parse_role(&info.role)?;
+	Admin::create(pool.as_ref(), &info.name, &info.password, role).await?;
+	auditor
+		.admin(&admin, "admin.create", &info.name)
+		.change(None, Some(&role.to_string()))
+		.record(pool.as_ref())
+		.await
+		.map_err(ErrorInternalServerError)?;
+	Ok(redirect("/admin/account"))
+}
+
+// 権限変更
+#[derive(Deserialize)]
+struct Name {
+	name: String,
+}
+#[derive(Deserialize)]
+struct RoleForm {
+	role: String,
+}
+async fn role(path: web::Path<Name>, web::Form(info): web::Form<RoleForm>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> Result<HttpResponse, actix_web::Error> {
+	let name = path.into_inner().name;
+	not_self(&admin, &name)?;
+	let role = parse_role(&info.role)?;
+	let mut tx = pool.begin().await.map_err(ErrorInternalServerError)?;
+	let Some(before) = Admin::set_role(&mut tx, &name, role).await.map_err(ErrorInternalServerError)? else {
+		return Err(ErrorNotFound("管理者が存在しません"));
+	};
+	auditor
+		.admin(&admin, "admin.role", &name)
+		.change(Some(&before), Some(&role.to_string()))
+		.record(&mut *tx)
+		.await
+		.map_err(ErrorInternalServerError)?;
+	tx.commit().await.map_err(ErrorInternalServerError)?;
+	Ok(redirect("/admin/account"))
+}
+
+// パスワード再設定
+#[derive(Deserialize, Validation)]
+struct Password {
+	#[validation(name = "パスワード", min = 8)]
+	password: String,
+}
+async fn password(path: web::Path<Name>, web::Form(info): web::Form<Password>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> Result<HttpResponse, actix_web::Error> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let name = path.into_inner().name;
+	// 自分のパスワードは現在のパスワードを確認する画面から変更する
+	not_self(&admin, &name)?;
+	if !Admin::set_password(pool.as_ref(), &name, &info.password).await? {
+		return Err(ErrorNotFound("管理者が存在しません"));
+	}
+	auditor.admin(&admin, "admin.password", &name).record(pool.as_ref()).await.map_err(ErrorInternalServerError)?;
+	Ok(redirect("/admin/account"))
+}
+
+// 削除
+async fn delete(path: web::Path<Name>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> Result<HttpResponse, actix_web::Error> {
+	let name = path.into_inner().name;
+	not_self(&admin, &name)?;
+	if Admin::delete(pool.as_ref(), &name).await.map_err(ErrorInternalServerError)? {
+		auditor.admin(&admin, "admin.delete", &name).record(pool.as_ref()).await.map_err(ErrorInternalServerError)?;
+	}
+	Ok(redirect("/admin/account"))
+}
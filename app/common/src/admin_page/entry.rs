@@ -0,0 +1,94 @@
+use actix_session::Session;
+use actix_web::{HttpRequest, HttpResponse, Responder, error::*, http::StatusCode, web};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use validation::Validation;
+
+use super::{AdminPage, redirect};
+use crate::{
+	Admin, Auditor, Lockout,
+	client_addr::client_ip,
+	lockout::too_many_requests,
+};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(index).post(login));
+	cfg.service(web::resource("logout").post(logout));
+	cfg.service(web::resource("password").get(password_page).post(password));
+}
+
+/// ログイン画面
+fn login_page(page: &AdminPage, status: StatusCode, error: Option<&str>) -> Result<HttpResponse, actix_web::Error> {
+	page.render(status, true, include_str!("../../template/admin/entry.html"), &liquid::object!({ "error": &error }))
+}
+
+// ログイン画面
+async fn index(admin: Option<Admin>, page: web::Data<AdminPage>) -> Result<impl Responder, actix_web::Error> {
+	if admin.is_some() {
+		return Ok(redirect("/admin"));
+	}
+	login_page(&page, StatusCode::OK, None)
+}
+
+// ログイン
+#[derive(Deserialize)]
+struct Login {
+	name: String,
+	password: String,
+}
+async fn login(
+	req: HttpRequest,
+	web::Form(info): web::Form<Login>,
+	session: Session,
+	page: web::Data<AdminPage>,
+	lockout: web::Data<Lockout>,
+	pool: web::Data<SqlitePool>,
+) -> Result<impl Responder, actix_web::Error> {
+	let ip = format!("ip:{}", client_ip(&req));
+	let account = format!("admin:{}", info.name);
+	let keys = [ip.as_str(), account.as_str()];
+	if let Err(wait) = lockout.begin(&keys) {
+		return Ok(too_many_requests(wait));
+	}
+	match Admin::login(pool.as_ref(), &session, &info.name, &info.password).await? {
+		Some(admin) => {
+			lockout.success(&keys, Some(&account));
+			println!("admin login: {} ({})", admin.name, admin.role);
+			Ok(redirect("/admin"))
+		}
+		None => login_page(&page, StatusCode::UNAUTHORIZED, Some("管理者名またはパスワードが異なります")),
+	}
+}
+
+// ログアウト
+async fn logout(session: Session) -> impl Responder {
+	Admin::logout(&session);
+	redirect("/admin/entry")
+}
+
+// 自分のパスワード変更
+async fn password_page(admin: Admin, page: web::Data<AdminPage>) -> Result<impl Responder, actix_web::Error> {
+	page.render(StatusCode::OK, false, include_str!("../../template/admin/password.html"), &liquid::object!({ "name": &admin.name }))
+}
+#[derive(Deserialize, Validation)]
+struct Password {
+	now: String,
+	#[validation(name = "パスワード", min = 8)]
+	new: String,
+}
+async fn password(web::Form(info): web::Form<Password>, admin: Admin, auditor: Auditor, lockout: web::Data<Lockout>, pool: web::Data<SqlitePool>) -> Result<HttpResponse, actix_web::Error> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let key = format!("admin:{}", admin.name);
+	if let Err(wait) = lockout.begin(&[&key]) {
+		return Ok(too_many_requests(wait));
+	}
+	let pool = pool.as_ref();
+	if !Admin::verify_password(pool, &admin.name, &info.now).await? {
+		return Err(ErrorUnauthorized("現在のパスワードが異なります"));
+	}
+	lockout.success(&[&key], Some(&key));
+	// 管理セッションは無効になるので、ログインし直してもらう
+	Admin::set_password(pool, &admin.name, &info.new).await?;
+	auditor.admin(&admin, "admin.password", &admin.name).record(pool).await.map_err(ErrorInternalServerError)?;
+	Ok(redirect("/admin/entry"))
+}
@@ -0,0 +1,58 @@
+mod account;
+mod entry;
+
+use actix_web::{
+	HttpResponse,
+	error::*,
+	http::{StatusCode, header},
+	mime, web,
+};
+
+/// 管理画面の共通部分（ログイン・自分のパスワード変更・管理者アカウント）
+///
+/// 本文はcommonのテンプレートで描画し、外側のレイアウトは各アプリのテンプレートで描画するため`app_data`に登録しておく
+/// ログインの失敗は`Lockout`で制限するので、それも`app_data`に登録しておくこと
+///
+/// # Example
+/// ```ignore
+/// .app_data(web::Data::new(AdminPage { plain: template::admin_plain, layout: template::admin_layout }))
+/// .service(web::scope("admin/entry").configure(admin_page::entry))
+/// .service(web::scope("admin").wrap(AdminGuardMiddleware::new(pool, "/admin/entry")).configure(admin::cfg))
+///
+/// // admin::cfg
+/// cfg.service(web::scope("account").wrap(RequireRole(Role::Owner)).configure(admin_page::account));
+/// ```
+pub struct AdminPage {
+	/// ナビゲーション無しのレイアウト（ログイン画面）　本文のHTMLを受け取る
+	pub plain: fn(&str) -> Result<String, liquid::Error>,
+	/// 管理画面のナビゲーション付きレイアウト　本文のHTMLを受け取る
+	pub layout: fn(&str) -> Result<String, liquid::Error>,
+}
+
+impl AdminPage {
+	/// 本文を描画し、レイアウトで包む
+	fn render(&self, status: StatusCode, plain: bool, template: &str, globals: &liquid::Object) -> Result<HttpResponse, actix_web::Error> {
+		let main = liquid::ParserBuilder::with_stdlib()
+			.build()
+			.and_then(|x| x.parse(template))
+			.and_then(|x| x.render(globals))
+			.map_err(ErrorInternalServerError)?;
+		let html = if plain { (self.plain)(&main) } else { (self.layout)(&main) }.map_err(ErrorInternalServerError)?;
+		Ok(HttpResponse::build(status).content_type(mime::TEXT_HTML).body(html))
+	}
+}
+
+/// ログイン画面など、`AdminGuardMiddleware`の外に置くもの　`admin/entry`に登録する
+pub fn entry(cfg: &mut web::ServiceConfig) {
+	entry::cfg(cfg);
+}
+
+/// 管理者アカウントの管理　`admin/account`に`RequireRole(Role::Owner)`付きで登録する
+pub fn account(cfg: &mut web::ServiceConfig) {
+	account::cfg(cfg);
+}
+
+/// 操作後に一覧などへ戻す
+fn redirect(location: &str) -> HttpResponse {
+	HttpResponse::SeeOther().insert_header((header::LOCATION, location)).finish()
+}
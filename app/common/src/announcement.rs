@@ -0,0 +1,110 @@
+use std::{
+	sync::{LazyLock, RwLock},
+	time::Duration,
+};
+
+use actix_web::web;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use crate::Portal;
+
+/// 終了していないお知らせのキャッシュ（優先度順）
+static CACHE: LazyLock<RwLock<Vec<Announcement>>> = LazyLock::new(|| RwLock::new(Vec::new()));
+
+/// お知らせ
+///
+/// ポータルのDBで管理し、各アプリは`Announcement::follow`でポータルから取得する
+/// 表示中のものはプロセス内にキャッシュし、レイアウトからはキャッシュを読む
+///
+/// ポータルのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table announcement
+///     id int pk
+///     title text
+///     body text              # CommonTag記法　未エスケープ
+///     priority int default(0)
+///     since timestamp
+///     until timestamp?       # NULLなら無期限
+///     apps text default('')  # 表示するアプリ　空白区切り、空なら全て
+///     created timestamp
+///     updated timestamp?
+/// ```
+#[derive(Clone, FromRow, Serialize, Deserialize)]
+pub struct Announcement {
+	pub id: i64,
+	pub title: String,
+	/// CommonTag記法　未エスケープ
+	pub body: String,
+	/// 大きいほど上に表示する
+	pub priority: i64,
+	/// 表示開始
+	pub since: i64,
+	/// 表示終了
+	pub until: Option<i64>,
+	/// 表示するアプリ（パッケージ名）　空白区切り、空なら全て
+	pub apps: String,
+}
+
+impl Announcement {
+	/// `app`に表示するものか
+	pub fn targets(&self, app: &str) -> bool {
+		let mut apps = self.apps.split_whitespace().peekable();
+		apps.peek().is_none() || apps.any(|x| x == app)
+	}
+	/// 表示期間内か
+	pub fn is_active(&self, now: i64) -> bool {
+		self.since <= now && self.until.is_none_or(|x| now < x)
+	}
+
+	/// 今`app`に表示するもの（優先度順）
+	pub fn active(app: &str) -> Vec<Self> {
+		let now = Local::now().timestamp();
+		CACHE
+			.read()
+			.map(|x| x.iter().filter(|x| x.targets(app) && x.is_active(now)).cloned().collect())
+			.unwrap_or_default()
+	}
+
+	/// DBからキャッシュを読み直す（ポータル用）　起動時と変更した時に呼ぶ
+	pub async fn reload(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+		let now = Local::now().timestamp();
+		let list: Vec<Self> = sqlx::query_as(
+			"SELECT id,title,body,priority,since,until,apps FROM announcement WHERE until IS NULL OR until>? ORDER BY priority DESC,since DESC,id DESC",
+		)
+		.bind(now)
+		.fetch_all(pool)
+		.await?;
+		if let Ok(mut cache) = CACHE.write() {
+			*cache = list;
+		}
+		Ok(())
+	}
+
+	/// ポータルから定期的に取得してキャッシュを差し替える（アプリ用）　`app`はこのアプリのパッケージ名
+	///
+	/// # Example
+	/// ```ignore
+	/// Announcement::follow(app.portal.clone(), env!("CARGO_PKG_NAME"));
+	/// ```
+	pub fn follow(portal: web::Data<Portal>, app: &'static str) {
+		// 取得間隔(秒)
+		const INTERVAL: u64 = 60;
+
+		actix_web::rt::spawn(async move {
+			loop {
+				// 取得に失敗したら前回の内容を出し続ける
+				match portal.announcements(app).await {
+					Ok(list) => {
+						if let Ok(mut cache) = CACHE.write() {
+							*cache = list;
+						}
+					}
+					Err(err) => eprintln!("{err}"),
+				}
+				tokio::time::sleep(Duration::from_secs(INTERVAL)).await;
+			}
+		});
+	}
+}
@@ -0,0 +1,263 @@
+use std::{ops::Deref, pin::Pin};
+
+use actix_session::SessionExt as _;
+use actix_web::{FromRequest, HttpResponse, Responder, error::*, http::header, mime, web};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Local;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest as _, Sha256};
+use sqlx::{SqliteExecutor, SqlitePool, prelude::FromRow};
+use validation::Validation;
+
+use crate::{
+	Auditor, Identity,
+	identity::{from_user_key, user_key},
+};
+
+/// 読み取り系APIの権限
+pub const READ: &str = "read";
+/// 書き込み系APIの権限
+pub const WRITE: &str = "write";
+/// 発行可能なスコープ
+pub const SCOPES: &[&str] = &[READ, WRITE];
+
+/// 個人用APIトークン
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table api_token
+///     id int pk
+///     user text              # identity::user_keyの形式
+///     name text
+///     scopes text            # 空白区切り
+///     token text             # SHA-256でハッシュ化して保持する
+///     created timestamp
+///     last_used timestamp?
+///     expiry timestamp?      # NULLなら無期限
+/// ```
+pub struct ApiToken;
+
+impl ApiToken {
+	/// トークンの接頭辞　漏洩時に検出しやすくするため
+	pub const PREFIX: &str = "utk_";
+
+	/// トークンを発行し、平文を返す（平文はこの時しか得られない）
+	pub async fn create(pool: &SqlitePool, user: &str, name: &str, scopes: &[String], expiry: Option<i64>) -> Result<String, sqlx::Error> {
+		let token = format!("{}{}", Self::PREFIX, BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()));
+		let now = Local::now().timestamp();
+		sqlx::query("INSERT INTO api_token(user,name,scopes,token,created,expiry) VALUES(?,?,?,?,?,?)")
+			.bind(user)
+			.bind(name)
+			.bind(scopes.join(" "))
+			.bind(Self::hash(&token))
+			.bind(now)
+			.bind(expiry)
+			.execute(pool)
+			.await?;
+		Ok(token)
+	}
+	/// ユーザーのトークン一覧
+	pub async fn list(pool: &SqlitePool, user: &str) -> Result<Vec<Record>, sqlx::Error> {
+		sqlx::query_as("SELECT id,name,scopes,created,last_used,expiry FROM api_token WHERE user=? ORDER BY id DESC")
+			.bind(user)
+			.fetch_all(pool)
+			.await
+	}
+	/// トークンを1つ破棄する
+	pub async fn revoke(pool: &SqlitePool, user: &str, id: i64) -> Result<bool, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM api_token WHERE user=? AND id=?").bind(user).bind(id).execute(pool).await?;
+		Ok(result.rows_affected() > 0)
+	}
+	/// ユーザーのトークンを全て破棄する
+	pub async fn revoke_all(executor: impl SqliteExecutor<'_>, user: &str) -> Result<(), sqlx::Error> {
+		sqlx::query("DELETE FROM api_token WHERE user=?").bind(user).execute(executor).await?;
+		Ok(())
+	}
+
+	/// トークンを照合し、ユーザーとスコープを返す
+	async fn verify(pool: &SqlitePool, token: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query_as("UPDATE api_token SET last_used=?1 WHERE token=?2 AND (expiry IS NULL OR expiry>?1) RETURNING user,scopes")
+			.bind(now)
+			.bind(Self::hash(token))
+			.fetch_optional(pool)
+			.await
+	}
+	/// 十分に長いランダム値なので、パスワードと違い高速なハッシュで良い
+	fn hash(token: &str) -> String {
+		BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+	}
+}
+
+/// トークン一覧の1件
+#[derive(FromRow, Serialize)]
+pub struct Record {
+	pub id: i64,
+	pub name: String,
+	pub scopes: String,
+	pub created: i64,
+	pub last_used: Option<i64>,
+	pub expiry: Option<i64>,
+}
+
+/// セッションまたは`Authorization: Bearer`で認証済みのユーザー
+///
+/// `Identity<T>`と異なりトークンでも通るので、トークン管理やパスワード変更などには使わないこと
+/// 利用停止中のユーザーの書き込みは`Identity<T>`同様に拒否する
+/// セッションの場合、なりすまし中ならなりすまし先のユーザーになる
+///
+/// # Example
+/// ```ignore
+/// async fn handler(user: Authorized<String>) -> MessageResult<impl Responder> {
+///     let name = user.require(api_token::READ)?;
+///     ...
+/// }
+/// ```
+pub struct Authorized<T> {
+	user: T,
+	/// トークン認証の場合のスコープ　セッションならNone
+	scopes: Option<Vec<String>>,
+}
+
+impl<T> Authorized<T> {
+	/// トークン認証ならスコープを確認する（セッションでは全て許可）
+	pub fn require(&self, scope: &str) -> Result<&T, actix_web::Error> {
+		match &self.scopes {
+			Some(scopes) if !scopes.iter().any(|x| x == scope) => Err(ErrorForbidden(format!("トークンに{scope}の権限がありません"))),
+			_ => Ok(&self.user),
+		}
+	}
+	pub fn is_token(&self) -> bool {
+		self.scopes.is_some()
+	}
+}
+impl<T> Deref for Authorized<T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target {
+		&self.user
+	}
+}
+impl<T: DeserializeOwned + 'static> FromRequest for Authorized<T> {
+	type Error = actix_web::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+	fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+		let bearer = req
+			.headers()
+			.get(header::AUTHORIZATION)
+			.map(|x| x.to_str().ok().and_then(|x| x.strip_prefix("Bearer ")).map(|x| x.trim().to_string()));
+		let pool = req.app_data::<web::Data<SqlitePool>>().cloned();
+		let session = req.get_session();
+		let guard = crate::suspension::Guard::new(req);
+		Box::pin(async move {
+			let (user, scopes) = match bearer {
+				// ヘッダーがあればトークン認証のみ
+				Some(token) => {
+					let token = token.ok_or(ErrorUnauthorized("Authorizationヘッダーが不正です"))?;
+					let pool = pool.ok_or(ErrorInternalServerError("DBが未定義"))?;
+					let (user, scopes) = ApiToken::verify(pool.as_ref(), &token)
+						.await
+						.map_err(ErrorInternalServerError)?
+						.ok_or(ErrorUnauthorized("トークンが無効です"))?;
+					(user, Some(scopes.split_whitespace().map(String::from).collect()))
+				}
+				None => match crate::impersonation::identity(&session) {
+					Ok(Some(value)) => (user_key(&value).map_err(ErrorBadRequest)?, None),
+					Ok(None) => return Err(ErrorUnauthorized("ログインしてください")),
+					Err(err) => return Err(ErrorBadRequest(err)),
+				},
+			};
+			guard.check(&user).await?;
+			Ok(Self {
+				user: from_user_key(&user).ok_or(ErrorUnauthorized("認証情報が不正です"))?,
+				scopes,
+			})
+		})
+	}
+}
+
+/// トークン管理（一覧・発行・破棄）のリソース　各アプリの`profile`スコープに登録する
+///
+/// トークン管理はトークンでは行えないよう、セッション(`Identity<T>`)のみ受け付ける
+/// 発行・破棄は監査ログに残すので、各アプリのdatabase.schに`audit_log`も定義しておくこと
+///
+/// # Example
+/// ```ignore
+/// cfg.service(api_token::resource::<String>("token"));
+/// ```
+pub fn resource<T: DeserializeOwned + Serialize + 'static>(path: &str) -> actix_web::Resource {
+	web::resource(path).get(list::<T>).post(create::<T>).delete(revoke::<T>)
+}
+
+// 発行済みトークン一覧
+async fn list<T: DeserializeOwned + Serialize>(user: Identity<T>, pool: web::Data<SqlitePool>) -> Result<impl Responder, actix_web::Error> {
+	let list = ApiToken::list(pool.as_ref(), &user_key(&*user)?).await.map_err(ErrorInternalServerError)?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&list)?))
+}
+
+// 発行
+#[derive(Deserialize, Validation)]
+struct Create {
+	#[validation(name = "トークン名", min = 1, max = 50)]
+	name: String,
+	scopes: Vec<String>,
+	/// 有効日数　無ければ無期限
+	days: Option<i64>,
+}
+#[derive(Serialize)]
+struct Created {
+	token: String,
+}
+async fn create<T: DeserializeOwned + Serialize>(
+	web::Json(info): web::Json<Create>,
+	user: Identity<T>,
+	auditor: Auditor,
+	pool: web::Data<SqlitePool>,
+) -> Result<impl Responder, actix_web::Error> {
+	// 1ユーザーあたりの発行上限
+	const LIMIT: usize = 20;
+
+	info.validate().map_err(ErrorBadRequest)?;
+	if info.scopes.is_empty() || info.scopes.iter().any(|x| !SCOPES.contains(&x.as_str())) {
+		return Err(ErrorBadRequest("スコープが不正です"));
+	}
+	let expiry = match info.days {
+		Some(days) if !(1..=365).contains(&days) => return Err(ErrorBadRequest("有効日数は1〜365日で指定してください")),
+		Some(days) => Some(Local::now().timestamp() + days * 86400),
+		None => None,
+	};
+	let pool = pool.as_ref();
+	let user = user_key(&*user)?;
+	if ApiToken::list(pool, &user).await.map_err(ErrorInternalServerError)?.len() >= LIMIT {
+		return Err(ErrorConflict(format!("トークンは{LIMIT}個まで発行できます")));
+	}
+	let token = ApiToken::create(pool, &user, &info.name, &info.scopes, expiry).await.map_err(ErrorInternalServerError)?;
+	auditor
+		.user(&user, "token.create", &info.name)
+		.change(None, Some(&info.scopes.join(" ")))
+		.record(pool)
+		.await
+		.map_err(ErrorInternalServerError)?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&Created { token })?))
+}
+
+// 破棄
+#[derive(Deserialize)]
+struct Revoke {
+	id: i64,
+}
+async fn revoke<T: DeserializeOwned + Serialize>(
+	web::Query(info): web::Query<Revoke>,
+	user: Identity<T>,
+	auditor: Auditor,
+	pool: web::Data<SqlitePool>,
+) -> Result<impl Responder, actix_web::Error> {
+	let pool = pool.as_ref();
+	let user = user_key(&*user)?;
+	if !ApiToken::revoke(pool, &user, info.id).await.map_err(ErrorInternalServerError)? {
+		return Err(ErrorNotFound("トークンが存在しません"));
+	}
+	auditor.user(&user, "token.revoke", &info.id.to_string()).record(pool).await.map_err(ErrorInternalServerError)?;
+	Ok(HttpResponse::NoContent().finish())
+}
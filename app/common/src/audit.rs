@@ -0,0 +1,175 @@
+use actix_web::{FromRequest, HttpRequest};
+use chrono::{Local, NaiveDate, TimeZone as _};
+use futures_util::future::{Ready, ok};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqliteExecutor, SqlitePool, prelude::FromRow};
+
+use crate::{Admin, client_addr::client_ip};
+
+/// 監査ログの記録元
+///
+/// 接続元IPをリクエストから取るためのエクストラクタ　記録する内容は`Entry`で組み立てる
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table audit_log
+///     id int pk
+///     actor text             # admin:管理者名 / user:identity::user_keyの形式 / system
+///     action text            # state.change, user.delete など「対象.操作」
+///     target text
+///     before text?
+///     after text?
+///     ip text?
+///     created timestamp
+/// ```
+///
+/// # Example
+/// ```ignore
+/// async fn handler(admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+///     auditor.admin(&admin, "setting.change", &key).change(Some(&before), Some(&after)).record(pool.as_ref()).await?;
+///     ...
+/// }
+/// ```
+pub struct Auditor {
+	ip: Option<String>,
+}
+
+impl Auditor {
+	/// 管理者による操作
+	pub fn admin(&self, admin: &Admin, action: &str, target: &str) -> Entry {
+		self.entry(format!("admin:{}", admin.name), action, target)
+	}
+	/// ユーザー本人による操作　`user`は`identity::user_key`の形式
+	pub fn user(&self, user: &str, action: &str, target: &str) -> Entry {
+		self.entry(format!("user:{user}"), action, target)
+	}
+	fn entry(&self, actor: String, action: &str, target: &str) -> Entry {
+		Entry {
+			actor,
+			action: action.into(),
+			target: target.into(),
+			before: None,
+			after: None,
+			ip: self.ip.clone(),
+		}
+	}
+}
+impl FromRequest for Auditor {
+	type Error = actix_web::Error;
+	type Future = Ready<Result<Self, Self::Error>>;
+
+	fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+		ok(Self {
+			ip: Some(client_ip(req)).filter(|x| !x.is_empty()),
+		})
+	}
+}
+
+/// サーバー自身による操作（予約の実行など）
+pub fn system(action: &str, target: &str) -> Entry {
+	Entry {
+		actor: "system".into(),
+		action: action.into(),
+		target: target.into(),
+		before: None,
+		after: None,
+		ip: None,
+	}
+}
+
+/// 記録する1件
+pub struct Entry {
+	actor: String,
+	action: String,
+	target: String,
+	before: Option<String>,
+	after: Option<String>,
+	ip: Option<String>,
+}
+
+impl Entry {
+	/// 変更前後の値　パスワードなど値を残すべきでないものには使わない
+	pub fn change(mut self, before: Option<&str>, after: Option<&str>) -> Self {
+		self.before = before.map(String::from);
+		self.after = after.map(String::from);
+		self
+	}
+	/// 記録する　トランザクション内の変更と一緒に残す場合はトランザクションを渡す
+	pub async fn record<'c>(self, executor: impl SqliteExecutor<'c>) -> Result<(), sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query("INSERT INTO audit_log(actor,action,target,before,after,ip,created) VALUES(?,?,?,?,?,?,?)")
+			.bind(self.actor)
+			.bind(self.action)
+			.bind(self.target)
+			.bind(self.before)
+			.bind(self.after)
+			.bind(self.ip)
+			.bind(now)
+			.execute(executor)
+			.await?;
+		Ok(())
+	}
+}
+
+/// 監査ログの1件
+#[derive(FromRow, Serialize)]
+pub struct Record {
+	pub id: i64,
+	pub actor: String,
+	pub action: String,
+	pub target: String,
+	pub before: Option<String>,
+	pub after: Option<String>,
+	pub ip: Option<String>,
+	pub created: i64,
+}
+
+/// 監査ログの絞り込み　空欄の項目は条件にしない
+#[derive(Default, Deserialize, Serialize)]
+pub struct Filter {
+	/// 部分一致
+	#[serde(default)]
+	pub actor: String,
+	/// 前方一致（`user.`で対象がユーザーの操作全て）
+	#[serde(default)]
+	pub action: String,
+	/// 部分一致
+	#[serde(default)]
+	pub target: String,
+	/// この日以降（YYYY-MM-DD）
+	#[serde(default)]
+	pub since: String,
+	/// この日まで（YYYY-MM-DD）
+	#[serde(default)]
+	pub until: String,
+}
+
+impl Filter {
+	/// 新しい順に取得する　`limit`がNoneなら全件
+	pub async fn search(&self, pool: &SqlitePool, offset: i64, limit: Option<i64>) -> Result<Vec<Record>, sqlx::Error> {
+		let mut builder = QueryBuilder::<Sqlite>::new("SELECT id,actor,action,target,before,after,ip,created FROM audit_log WHERE 1=1");
+		if !self.actor.is_empty() {
+			builder.push(" AND actor LIKE ").push_bind(format!("%{}%", self.actor));
+		}
+		if !self.action.is_empty() {
+			builder.push(" AND action LIKE ").push_bind(format!("{}%", self.action));
+		}
+		if !self.target.is_empty() {
+			builder.push(" AND target LIKE ").push_bind(format!("%{}%", self.target));
+		}
+		if let Some(since) = date(&self.since, 0) {
+			builder.push(" AND created>=").push_bind(since);
+		}
+		if let Some(until) = date(&self.until, 1) {
+			builder.push(" AND created<").push_bind(until);
+		}
+		builder.push(" ORDER BY id DESC LIMIT ").push_bind(limit.unwrap_or(-1)).push(" OFFSET ").push_bind(offset);
+		builder.build_query_as().fetch_all(pool).await
+	}
+}
+
+/// 日付の`days`日後の0時（ローカル時刻）
+fn date(value: &str, days: u64) -> Option<i64> {
+	let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.checked_add_days(chrono::Days::new(days))?;
+	Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest().map(|x| x.timestamp())
+}
@@ -0,0 +1,240 @@
+use std::{
+	path::{Path, PathBuf},
+	str::FromStr,
+	time::Duration,
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{ConnectOptions as _, SqliteExecutor, SqlitePool, sqlite::SqliteConnectOptions};
+
+use crate::audit;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// DBのバックアップ
+///
+/// `VACUUM INTO`で取るので、取得中も読み書きは止まらない
+/// スナップショット`{app}-{日時}.db`ごとにマニフェスト`{app}-{日時}.json`（SHA-256・スキーマのハッシュ）を置き、古いものから消す
+///
+/// 環境変数（全て省略可）
+/// - `BACKUP_DIR`: 保存先　既定はDBと同じディレクトリの`backup`
+/// - `BACKUP_KEEP`: 残す数　既定は14
+/// - `BACKUP_INTERVAL`: 定期バックアップの間隔(時間)　0なら取らない　既定は24
+///
+/// # Example
+/// ```ignore
+/// let backup = Backup::from_env(env!("CARGO_PKG_NAME"), &db_url);
+/// backup.spawn(app.pool.as_ref().clone());
+/// ```
+#[derive(Clone)]
+pub struct Backup {
+	app: &'static str,
+	dir: PathBuf,
+	keep: usize,
+	interval: u64,
+}
+
+/// スナップショット1件のマニフェスト
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+	/// 保存先ディレクトリ内のファイル名
+	pub file: String,
+	pub size: u64,
+	pub sha256: String,
+	/// 取得時のスキーマのハッシュ（`schema_version`）
+	pub schema: String,
+	pub created: i64,
+}
+
+impl Backup {
+	pub fn from_env(app: &'static str, db_url: &str) -> Self {
+		let dir = std::env::var("BACKUP_DIR").map(PathBuf::from).unwrap_or_else(|_| {
+			let db = database_path(db_url).unwrap_or_default();
+			db.parent().unwrap_or(Path::new(".")).join("backup")
+		});
+		let var = |key: &str, default: u64| std::env::var(key).ok().and_then(|x| x.parse().ok()).unwrap_or(default);
+		Self {
+			app,
+			dir,
+			keep: var("BACKUP_KEEP", 14).max(1) as usize,
+			interval: var("BACKUP_INTERVAL", 24),
+		}
+	}
+
+	/// スナップショットを取り、古いものを消す
+	pub async fn snapshot(&self, pool: &SqlitePool) -> Result<Snapshot, Error> {
+		tokio::fs::create_dir_all(&self.dir).await?;
+		let now = Local::now();
+		let stem = format!("{}-{}", self.app, now.format("%Y%m%d-%H%M%S"));
+		let path = self.dir.join(format!("{stem}.db"));
+		if tokio::fs::try_exists(&path).await? {
+			return Err("同じ時刻のスナップショットが既にあります".into());
+		}
+		let schema = schema_version(pool).await?;
+		sqlx::query("VACUUM INTO ?").bind(path.to_string_lossy().as_ref()).execute(pool).await?;
+		let (size, sha256) = checksum(path.clone()).await?;
+		let snapshot = Snapshot {
+			file: format!("{stem}.db"),
+			size,
+			sha256,
+			schema,
+			created: now.timestamp(),
+		};
+		tokio::fs::write(self.dir.join(format!("{stem}.json")), serde_json::to_vec_pretty(&snapshot)?).await?;
+		self.rotate().await?;
+		Ok(snapshot)
+	}
+
+	/// 保存されているスナップショット（新しい順）
+	pub async fn list(&self) -> Result<Vec<Snapshot>, Error> {
+		let mut list = Vec::new();
+		let mut dir = match tokio::fs::read_dir(&self.dir).await {
+			Ok(x) => x,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(list),
+			Err(err) => return Err(err.into()),
+		};
+		let prefix = format!("{}-", self.app);
+		while let Some(entry) = dir.next_entry().await? {
+			let name = entry.file_name().to_string_lossy().into_owned();
+			if !name.starts_with(&prefix) || !name.ends_with(".json") {
+				continue;
+			}
+			match serde_json::from_slice::<Snapshot>(&tokio::fs::read(entry.path()).await?) {
+				Ok(x) => list.push(x),
+				Err(err) => eprintln!("{name}: {err}"),
+			}
+		}
+		list.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.file.cmp(&a.file)));
+		Ok(list)
+	}
+
+	/// `keep`件を超えた古いものを消す
+	async fn rotate(&self) -> Result<(), Error> {
+		for snapshot in self.list().await?.into_iter().skip(self.keep) {
+			let db = self.dir.join(&snapshot.file);
+			tokio::fs::remove_file(db.with_extension("json")).await?;
+			if let Err(err) = tokio::fs::remove_file(&db).await
+				&& err.kind() != std::io::ErrorKind::NotFound
+			{
+				return Err(err.into());
+			}
+		}
+		Ok(())
+	}
+
+	/// 定期的にスナップショットを取る　結果は監査ログに`system`として残す
+	pub fn spawn(&self, pool: SqlitePool) {
+		if self.interval == 0 {
+			return;
+		}
+		let backup = self.clone();
+		actix_web::rt::spawn(async move {
+			let interval = Duration::from_secs(backup.interval * 3600);
+			loop {
+				// 起動直後は取らない（再起動を繰り返しても増えないように）
+				tokio::time::sleep(interval).await;
+				match backup.snapshot(&pool).await {
+					Ok(snapshot) => {
+						if let Err(err) = audit::system("backup.create", &snapshot.file).change(None, Some(&snapshot.sha256)).record(&pool).await {
+							eprintln!("{err}");
+						}
+					}
+					Err(err) => eprintln!("バックアップに失敗しました: {err}"),
+				}
+			}
+		});
+	}
+}
+
+/// スキーマのハッシュ　テーブル・インデックスの定義が同じなら同じ値になる
+pub async fn schema_version<'c>(executor: impl SqliteExecutor<'c>) -> Result<String, sqlx::Error> {
+	let rows: Vec<(String, String, Option<String>)> =
+		sqlx::query_as("SELECT type,name,sql FROM sqlite_schema WHERE name NOT LIKE 'sqlite_%' ORDER BY type,name").fetch_all(executor).await?;
+	let mut hasher = Sha256::new();
+	for (kind, name, sql) in rows {
+		hasher.update(format!("{kind}\0{name}\0{}\n", sql.unwrap_or_default()));
+	}
+	Ok(hex(&hasher.finalize()))
+}
+
+/// スナップショットから復元する　サーバーを止めてから実行すること
+///
+/// マニフェストのチェックサムと、現在のDBとのスキーマの一致を確認してからファイルを差し替える
+/// 差し替える前のDBは`{DBのパス}.{日時}.bak`として残す
+///
+/// # Example
+/// ```ignore
+/// // portal restore app/portal/backup/portal-20260101-000000.db
+/// if let [command, file] = args.as_slice() && command == "restore" {
+///     return backup::restore(&db_url, file.as_ref()).await.map_err(std::io::Error::other);
+/// }
+/// ```
+pub async fn restore(db_url: &str, file: &Path) -> Result<(), Error> {
+	let db = database_path(db_url)?;
+	let manifest: Snapshot = serde_json::from_slice(&tokio::fs::read(file.with_extension("json")).await.map_err(|err| format!("マニフェストが読めません: {err}"))?)?;
+	let (_, sha256) = checksum(file.to_path_buf()).await?;
+	if sha256 != manifest.sha256 {
+		return Err(format!("チェックサムが一致しません（{sha256} != {}）", manifest.sha256).into());
+	}
+	let mut conn = SqliteConnectOptions::new().filename(file).read_only(true).connect().await?;
+	let integrity: String = sqlx::query_scalar("PRAGMA integrity_check").fetch_one(&mut conn).await?;
+	if integrity != "ok" {
+		return Err(format!("スナップショットが壊れています: {integrity}").into());
+	}
+	let schema = schema_version(&mut conn).await?;
+	drop(conn);
+	if schema != manifest.schema {
+		return Err("スナップショットのスキーマがマニフェストと一致しません".into());
+	}
+	if tokio::fs::try_exists(&db).await? {
+		// WALの内容を本体に書き戻してから比べる・退避する
+		let mut conn = SqliteConnectOptions::new().filename(&db).connect().await?;
+		let current = schema_version(&mut conn).await?;
+		sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(&mut conn).await?;
+		drop(conn);
+		if current != schema {
+			return Err(format!("スキーマが現在のDBと異なります（現在 {current}、スナップショット {schema}）").into());
+		}
+		let mut backup = db.clone().into_os_string();
+		backup.push(format!(".{}.bak", Local::now().format("%Y%m%d-%H%M%S")));
+		let backup = PathBuf::from(backup);
+		tokio::fs::rename(&db, &backup).await?;
+		println!("{} -> {}", db.display(), backup.display());
+	}
+	// 別名で書いてから差し替える
+	let temp = db.with_extension("restore");
+	tokio::fs::copy(file, &temp).await?;
+	for suffix in ["-wal", "-shm"] {
+		let mut path = db.clone().into_os_string();
+		path.push(suffix);
+		if let Err(err) = tokio::fs::remove_file(path).await
+			&& err.kind() != std::io::ErrorKind::NotFound
+		{
+			return Err(err.into());
+		}
+	}
+	tokio::fs::rename(&temp, &db).await?;
+	println!("{} -> {}", file.display(), db.display());
+	Ok(())
+}
+
+/// `DATABASE_URL`からDBファイルのパス
+pub(crate) fn database_path(db_url: &str) -> Result<PathBuf, sqlx::Error> {
+	Ok(SqliteConnectOptions::from_str(db_url)?.get_filename().to_path_buf())
+}
+
+/// ファイルサイズとSHA-256　DBが大きくても他のリクエストを止めないよう別スレッドで読む
+async fn checksum(path: PathBuf) -> Result<(u64, String), Error> {
+	actix_web::rt::task::spawn_blocking(move || -> Result<_, Error> {
+		let mut hasher = Sha256::new();
+		let size = std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+		Ok((size, hex(&hasher.finalize())))
+	})
+	.await?
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
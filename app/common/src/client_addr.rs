@@ -0,0 +1,45 @@
+use std::{net::IpAddr, sync::OnceLock};
+
+use actix_web::HttpRequest;
+
+/// プロキシが上書きする接続元のヘッダー（nginxの`proxy_set_header X-Real-IP $remote_addr`）
+const REAL_IP: &str = "X-Real-IP";
+
+/// リクエスト元のIPアドレス　レート制限・ロックアウト・監査ログに使う　分からなければ空文字
+///
+/// 直接の接続元が信頼するプロキシの場合のみ`X-Real-IP`を使い、それ以外は接続元そのもの
+/// `X-Forwarded-For`・`Forwarded`はクライアントが好きな値を付けられるので見ない
+///
+/// 信頼するプロキシは環境変数`TRUSTED_PROXIES`（カンマ区切りのIPアドレス）　省略時はループバックとプライベートアドレス
+///
+/// # Example
+/// ```ignore
+/// let key = format!("ip:{}", client_addr::client_ip(&req));
+/// ```
+pub fn client_ip(req: &HttpRequest) -> String {
+	let Some(peer) = req.peer_addr().map(|x| x.ip().to_canonical()) else {
+		return String::new();
+	};
+	if is_trusted(peer)
+		&& let Some(ip) = req.headers().get(REAL_IP).and_then(|x| x.to_str().ok()).and_then(|x| x.trim().parse::<IpAddr>().ok())
+	{
+		return ip.to_canonical().to_string();
+	}
+	peer.to_string()
+}
+
+fn is_trusted(peer: IpAddr) -> bool {
+	static TRUSTED: OnceLock<Option<Vec<IpAddr>>> = OnceLock::new();
+	let trusted = TRUSTED.get_or_init(|| {
+		std::env::var("TRUSTED_PROXIES")
+			.ok()
+			.map(|x| x.split(',').filter_map(|x| x.trim().parse::<IpAddr>().ok()).map(|x| x.to_canonical()).collect())
+	});
+	match trusted {
+		Some(list) => list.contains(&peer),
+		None => match peer {
+			IpAddr::V4(x) => x.is_loopback() || x.is_private(),
+			IpAddr::V6(x) => x.is_loopback() || x.is_unique_local(),
+		},
+	}
+}
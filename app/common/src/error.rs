@@ -1,5 +1,6 @@
 /// ## Example
 /// ```
+/// # use common::error;
 /// error!(MyError);
 ///
 /// impl actix_web::ResponseError for MyError {
@@ -1,22 +1,33 @@
-use std::{
-	future::{Ready, ready},
-	ops::Deref,
-};
+use std::{ops::Deref, pin::Pin};
 
 use actix_session::{Session, SessionExt as _};
 use actix_web::{FromRequest, error::*};
 use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
 
 pub const KEY: &str = "login-session";
 
+/// ユーザーをDBに記録する際の表現　文字列ならそのまま、それ以外はJSON表現
+pub fn user_key<T: Serialize + ?Sized>(value: &T) -> Result<String, serde_json::Error> {
+	Ok(match serde_json::to_value(value)? {
+		Value::String(s) => s,
+		v => v.to_string(),
+	})
+}
+/// `user_key`の逆変換
+pub fn from_user_key<T: DeserializeOwned>(key: &str) -> Option<T> {
+	serde_json::from_value(Value::String(key.into())).ok().or_else(|| serde_json::from_str(key).ok())
+}
+
 pub struct Identity<T: DeserializeOwned>(T);
 
 impl<T: DeserializeOwned + Serialize> Identity<T> {
 	pub fn save(session: &Session, value: &T) -> Result<(), actix_session::SessionInsertError> {
 		session.insert(KEY, value)
 	}
+	/// セッションごと破棄する
 	pub fn delete(session: &Session) {
-		session.remove(KEY);
+		session.purge();
 	}
 }
 impl<T: DeserializeOwned> Deref for Identity<T> {
@@ -26,15 +37,23 @@ impl<T: DeserializeOwned> Deref for Identity<T> {
 		&self.0
 	}
 }
-impl<T: DeserializeOwned> FromRequest for Identity<T> {
+/// 利用停止中のユーザーは書き込みリクエストで403になる
+/// 管理者がなりすまし中ならなりすまし先のユーザーになる
+impl<T: DeserializeOwned + 'static> FromRequest for Identity<T> {
 	type Error = actix_web::Error;
-	type Future = Ready<Result<Self, Self::Error>>;
+	type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
 	fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
-		ready(match req.get_session().get(KEY) {
-			Ok(Some(v)) => Ok(Self(v)),
-			Ok(None) => Err(ErrorUnauthorized("ログインしてください")),
-			Err(err) => Err(ErrorBadRequest(err)),
+		let value = crate::impersonation::identity(&req.get_session());
+		let guard = crate::suspension::Guard::new(req);
+		Box::pin(async move {
+			let value = match value {
+				Ok(Some(v)) => v,
+				Ok(None) => return Err(ErrorUnauthorized("ログインしてください")),
+				Err(err) => return Err(ErrorBadRequest(err)),
+			};
+			guard.check(&user_key(&value).map_err(ErrorBadRequest)?).await?;
+			Ok(Self(serde_json::from_value(value).map_err(ErrorBadRequest)?))
 		})
 	}
 }
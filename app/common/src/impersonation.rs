@@ -0,0 +1,162 @@
+use std::{
+	future::{Ready, ready},
+	rc::Rc,
+};
+
+use actix_session::{Session, SessionExt as _, SessionGetError};
+use actix_web::{
+	FromRequest as _,
+	body::{BoxBody, EitherBody},
+	dev::{Service, ServiceRequest, ServiceResponse, Transform},
+	error::*,
+};
+use chrono::Local;
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Admin, identity};
+
+const KEY: &str = "impersonation";
+
+tokio::task_local! {
+	/// 処理中のリクエストのなりすまし　レイアウトから読む
+	static CURRENT: Option<Impersonation>;
+}
+
+/// 管理者によるなりすまし（ユーザーとして閲覧）
+///
+/// 管理セッションと同じセッションに保持し、`identity::KEY`は書き換えない（管理者自身のログインとセッション一覧はそのまま）
+/// なりすまし中は`Identity`・`Authorized`がなりすまし先のユーザーになる
+/// 書き込みの禁止と管理セッションの確認は`Guard`で行う
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Impersonation {
+	/// 開始した管理者
+	pub admin: String,
+	/// なりすまし先（`Identity`に保存する値）
+	pub user: Value,
+	pub started: i64,
+}
+
+impl Impersonation {
+	/// なりすましを開始する　既になりすまし中なら差し替える
+	pub fn start<T: Serialize>(session: &Session, admin: &Admin, user: &T) -> Result<(), actix_web::Error> {
+		let value = Self {
+			admin: admin.name.clone(),
+			user: serde_json::to_value(user).map_err(ErrorBadRequest)?,
+			started: Local::now().timestamp(),
+		};
+		session.insert(KEY, value).map_err(ErrorInternalServerError)
+	}
+	/// なりすましを終了する　なりすまし中だったらその内容を返す
+	pub fn end(session: &Session) -> Option<Self> {
+		session.remove_as::<Self>(KEY).and_then(Result::ok)
+	}
+	/// セッションのなりすまし
+	pub fn get(session: &Session) -> Option<Self> {
+		session.get::<Self>(KEY).ok().flatten()
+	}
+	/// なりすまし先の`identity::user_key`
+	pub fn user_key(&self) -> String {
+		identity::user_key(&self.user).unwrap_or_default()
+	}
+	/// 処理中のリクエストのなりすまし　`Guard`の内側でのみ取得できる
+	pub fn current() -> Option<Self> {
+		CURRENT.try_with(Clone::clone).ok().flatten()
+	}
+	/// なりすまし中なら403にする　`Guard`はGETを通すので、書き込みを伴うGET（認証コードの発行など）の先頭で呼ぶ
+	pub fn forbid() -> Result<(), actix_web::Error> {
+		match Self::current() {
+			Some(_) => Err(ErrorForbidden("なりすまし中は閲覧のみできます")),
+			None => Ok(()),
+		}
+	}
+}
+
+/// ログイン中のユーザーとして扱う値　なりすまし中ならなりすまし先
+pub(crate) fn identity(session: &Session) -> Result<Option<Value>, SessionGetError> {
+	match Impersonation::get(session) {
+		Some(x) => Ok(Some(x.user)),
+		None => session.get::<Value>(identity::KEY),
+	}
+}
+
+/// なりすまし中のリクエストに掛けるガード　セッションミドルウェアの内側でアプリ全体に`wrap`する
+///
+/// 開始した管理者の管理セッションが切れていればなりすましを終了する
+/// `exempt`以下（管理画面）を除き、GET/HEAD以外は403にする　レイアウトのバナーのため`exempt`以下でも`Impersonation::current`は取得できる
+/// 書き込みを伴うGETはハンドラーで`Impersonation::forbid`を呼ぶこと
+///
+/// # Example
+/// ```ignore
+/// App::new().wrap(impersonation::Guard::new("/admin")).wrap(session)
+/// ```
+#[derive(Clone)]
+pub struct Guard {
+	exempt: &'static str,
+}
+impl Guard {
+	pub fn new(exempt: &'static str) -> Self {
+		Self { exempt }
+	}
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Guard
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type InitError = ();
+	type Transform = GuardImpl<S>;
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ready(Ok(GuardImpl {
+			service: Rc::new(service),
+			exempt: self.exempt,
+		}))
+	}
+}
+
+pub struct GuardImpl<S> {
+	service: Rc<S>,
+	exempt: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for GuardImpl<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&self, ctx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+		self.service.poll_ready(ctx)
+	}
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		let service = self.service.clone();
+		let exempt = self.exempt;
+		Box::pin(async move {
+			let session = req.get_session();
+			let Some(current) = Impersonation::get(&session) else {
+				return Ok(service.call(req).await?.map_into_left_body());
+			};
+			// 管理セッションが切れた・別の管理者に替わった場合は終了する
+			if !Admin::extract(req.request()).await.is_ok_and(|x| x.name == current.admin) {
+				Impersonation::end(&session);
+				return Ok(service.call(req).await?.map_into_left_body());
+			}
+			let path = req.path();
+			let exempted = path == exempt || path.strip_prefix(exempt).is_some_and(|x| x.starts_with('/'));
+			if !exempted && !req.method().is_safe() {
+				return Ok(req.error_response(ErrorForbidden("なりすまし中は閲覧のみできます")).map_into_right_body());
+			}
+			Ok(CURRENT.scope(Some(current), service.call(req)).await?.map_into_left_body())
+		})
+	}
+}
@@ -1,15 +1,38 @@
 pub mod admin_guard;
+pub mod admin_page;
+pub mod announcement;
+pub mod api_token;
+pub mod audit;
+pub mod backup;
+pub mod client_addr;
 pub mod device;
 pub mod error;
 pub mod identity;
+pub mod impersonation;
+pub mod lockout;
+pub mod portal;
+pub mod schedule;
 pub mod serialize;
+pub mod session;
 pub mod state;
+pub mod suspension;
 pub mod webhook;
 
 pub use crate::{
-	admin_guard::AdminGuardMiddleware,
+	admin_guard::{Admin, AdminGuardMiddleware, RequireRole, Role},
+	admin_page::AdminPage,
+	announcement::Announcement,
+	api_token::{ApiToken, Authorized},
+	audit::Auditor,
+	backup::Backup,
 	device::Device,
 	identity::Identity,
-	state::{Handle as StateHandle, IsMaintenance},
+	impersonation::{Guard as ImpersonationGuard, Impersonation},
+	lockout::Lockout,
+	portal::Portal,
+	schedule::Schedule,
+	session::SqliteSessionStore,
+	state::{Handle as StateHandle, IsMaintenance, Maintenance, MaintenancePage, Policy as StatePolicy, Rule as StateRule},
+	suspension::{SuspendedPage, Suspension},
 	webhook::Webhook,
 };
@@ -0,0 +1,109 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use actix_web::{HttpResponse, http::header};
+use chrono::Local;
+use serde::Serialize;
+
+/// 認証失敗の記録
+#[derive(Clone, Serialize)]
+pub struct Failure {
+	pub count: u32,
+	/// 最後に失敗した時刻
+	pub last: i64,
+	/// ロック解除時刻
+	pub until: i64,
+}
+
+/// 認証失敗回数に応じて一時的にロックする
+///
+/// キーはアカウント単位なら`user:{name}`（管理者は`admin:{name}`）、IP単位なら`ip:{addr}`とする
+/// 各アプリで1つ作って`app_data`に登録しておく
+#[derive(Default)]
+pub struct Lockout {
+	records: Mutex<HashMap<String, Failure>>,
+}
+
+impl Lockout {
+	/// ロックせずに許容する失敗回数
+	const FREE: u32 = 3;
+	/// 最初のロック時間(秒)　以降失敗するごとに倍になる
+	const BASE: i64 = 30;
+	/// ロック時間の上限(秒)
+	const MAX: i64 = 3600;
+	/// 最後の失敗からこの時間(秒)が経過したら記録を破棄する
+	const FORGET: i64 = 86400;
+
+	/// 試行を始める　いずれかのキーがロック中なら残り秒数を返す
+	///
+	/// 確認とあわせて、検証の前に失敗として数えておく（検証中に並行して送られた試行もロックの判定に含める）
+	/// 失敗した場合はそのまま、成功した場合は`success`を呼ぶ
+	pub fn begin(&self, keys: &[&str]) -> Result<(), i64> {
+		let now = Local::now().timestamp();
+		let Ok(mut records) = self.records.lock() else {
+			return Err(Self::BASE);
+		};
+		records.retain(|_, x| x.last > now - Self::FORGET);
+		let wait = keys.iter().filter_map(|x| records.get(*x)).map(|x| x.until - now).max().unwrap_or(0);
+		if wait > 0 {
+			return Err(wait);
+		}
+		for key in keys {
+			let record = records.entry((*key).into()).or_insert(Failure { count: 0, last: now, until: 0 });
+			record.count += 1;
+			record.last = now;
+			if record.count > Self::FREE {
+				let exp = (record.count - Self::FREE - 1).min(16);
+				record.until = now + (Self::BASE << exp).min(Self::MAX);
+			}
+		}
+		Ok(())
+	}
+	/// 成功した試行　`begin`で数えた分を戻し、`account`の記録は消す
+	///
+	/// IPなど他のキーの失敗は残す（自分のアカウントでの成功で、他のアカウントへの試行の記録が消えないように）
+	pub fn success(&self, keys: &[&str], account: Option<&str>) {
+		let Ok(mut records) = self.records.lock() else {
+			return;
+		};
+		for key in keys {
+			if account == Some(*key) {
+				records.remove(*key);
+			} else if let Some(record) = records.get_mut(*key) {
+				record.count = record.count.saturating_sub(1);
+				if record.count <= Self::FREE {
+					record.until = 0;
+				}
+				if record.count == 0 {
+					records.remove(*key);
+				}
+			}
+		}
+	}
+	/// 記録の一覧
+	pub fn list(&self) -> Vec<(String, Failure)> {
+		let now = Local::now().timestamp();
+		match self.records.lock() {
+			Ok(records) => records.iter().filter(|(_, x)| x.last > now - Self::FORGET).map(|(k, v)| (k.clone(), v.clone())).collect(),
+			Err(_) => Vec::new(),
+		}
+	}
+	/// 記録を消す　キー未指定なら全て
+	pub fn clear(&self, key: Option<&str>) {
+		if let Ok(mut records) = self.records.lock() {
+			match key {
+				Some(key) => {
+					records.remove(key);
+				}
+				None => records.clear(),
+			}
+		}
+	}
+}
+
+/// ロック中のレスポンス
+pub fn too_many_requests(wait: i64) -> HttpResponse {
+	HttpResponse::TooManyRequests()
+		.insert_header((header::RETRY_AFTER, wait.to_string()))
+		.content_type(actix_web::mime::TEXT_PLAIN)
+		.body(format!("試行回数が多すぎます。{wait}秒後に再度お試しください"))
+}
@@ -0,0 +1,423 @@
+use std::time::Duration;
+
+use actix_web::{ResponseError, http::StatusCode};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use rand::{TryRngCore as _, rngs::OsRng};
+use reqwest::{Client, RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::Announcement;
+
+/// 認証コード交換 (POST /auth)
+#[derive(Serialize, Deserialize)]
+pub struct CertRequest {
+	pub code: String,
+	pub redirect_uri: String,
+	pub code_verifier: String,
+}
+#[derive(Serialize, Deserialize)]
+pub struct CertResponse {
+	pub user: String,
+}
+
+/// ユーザー情報 (GET /auth/user/{name})
+#[derive(Serialize, Deserialize)]
+pub struct UserResponse {
+	pub name: String,
+	pub webhook: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+	/// 認証コードまたはクライアント認証が不正
+	Unauthorized(String),
+	/// 対象が存在しない
+	NotFound,
+	/// ポータルがクローズ・メンテナンス中
+	Unavailable(String),
+	/// その他のエラーレスポンス
+	Status(u16, String),
+	/// 通信エラー・タイムアウト
+	Request(reqwest::Error),
+	/// 接続設定が不正
+	Config(String),
+}
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Unauthorized(msg) => write!(f, "ポータル認証に失敗しました: {msg}"),
+			Self::NotFound => write!(f, "ポータルアカウントが存在しません"),
+			Self::Unavailable(msg) => write!(f, "ポータルが利用できません: {msg}"),
+			Self::Status(code, msg) => write!(f, "ポータルがエラーを返しました({code}): {msg}"),
+			Self::Request(err) => write!(f, "ポータルとの通信に失敗しました: {err}"),
+			Self::Config(msg) => write!(f, "ポータル接続設定が不正です: {msg}"),
+		}
+	}
+}
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Request(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+impl ResponseError for Error {
+	fn status_code(&self) -> StatusCode {
+		match self {
+			Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+			Self::Status(..) => StatusCode::BAD_GATEWAY,
+			Self::Request(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+			Self::Request(_) => StatusCode::BAD_GATEWAY,
+			Self::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+}
+impl From<reqwest::Error> for Error {
+	fn from(value: reqwest::Error) -> Self {
+		Self::Request(value)
+	}
+}
+
+/// 認可リクエスト開始時にセッションへ保存しておく値
+#[derive(Serialize, Deserialize)]
+pub struct Pending {
+	pub state: String,
+	pub verifier: String,
+}
+
+/// code_verifierからcode_challenge(S256)を求める
+pub fn challenge(verifier: &str) -> String {
+	BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// ポータル接続設定
+pub struct Config {
+	/// サーバー間通信で使うポータルのベースURL（Dockerなら"http://portal:8000"、テストならモックサーバーのURL）
+	pub url: String,
+	/// ブラウザから見たポータルのベースURL
+	pub public_url: String,
+	pub client_id: String,
+	pub client_secret: String,
+	/// ポータルに登録したredirect_uri
+	pub redirect_uri: String,
+	pub timeout: Duration,
+	/// 失敗時の再試行回数
+	pub retries: u32,
+}
+impl Config {
+	pub fn new(url: &str, client_id: &str, client_secret: &str) -> Self {
+		Self {
+			url: url.into(),
+			public_url: url.into(),
+			client_id: client_id.into(),
+			client_secret: client_secret.into(),
+			redirect_uri: String::new(),
+			timeout: Duration::from_secs(5),
+			retries: 2,
+		}
+	}
+	/// `PORTAL_URL`, `PORTAL_PUBLIC_URL`, `PORTAL_CLIENT_ID`, `PORTAL_CLIENT_SECRET`, `PORTAL_REDIRECT_URI` から読み込む
+	pub fn from_env() -> Result<Self, std::env::VarError> {
+		let mut config = Self::new(
+			&std::env::var("PORTAL_URL")?,
+			&std::env::var("PORTAL_CLIENT_ID")?,
+			&std::env::var("PORTAL_CLIENT_SECRET")?,
+		);
+		config.public_url = std::env::var("PORTAL_PUBLIC_URL")?;
+		config.redirect_uri = std::env::var("PORTAL_REDIRECT_URI")?;
+		Ok(config)
+	}
+}
+
+/// サーバー間通信用のポータルクライアント
+///
+/// 内部のコネクションプールを共有するため、`web::Data`に入れて使い回すこと
+///
+/// # Example
+/// ```ignore
+/// let portal = web::Data::new(common::Portal::new(common::portal::Config::from_env()?)?);
+/// ```
+#[derive(Clone)]
+pub struct Portal {
+	client: Client,
+	url: Url,
+	public_url: Url,
+	client_id: String,
+	client_secret: String,
+	redirect_uri: String,
+	retries: u32,
+}
+impl Portal {
+	pub fn new(config: Config) -> Result<Self, Error> {
+		let parse = |url: &str| {
+			// パス結合のため末尾を/に揃える
+			let mut url = Url::parse(url).map_err(|err| Error::Config(err.to_string()))?;
+			if !url.path().ends_with('/') {
+				url.set_path(&format!("{}/", url.path()));
+			}
+			Ok::<_, Error>(url)
+		};
+		let url = parse(&config.url)?;
+		let public_url = parse(&config.public_url)?;
+		let client = Client::builder()
+			.timeout(config.timeout)
+			.connect_timeout(config.timeout)
+			.pool_idle_timeout(Duration::from_secs(90))
+			.build()?;
+		Ok(Self {
+			client,
+			url,
+			public_url,
+			client_id: config.client_id,
+			client_secret: config.client_secret,
+			redirect_uri: config.redirect_uri,
+			retries: config.retries,
+		})
+	}
+
+	/// ブラウザで開く認可リクエストのURLを作成する
+	///
+	/// 戻り値の`Pending`はセッションに保存しておき、コード交換時に`exchange`へ渡す
+	pub fn authorize(&self) -> Result<(Url, Pending), Error> {
+		let random = || {
+			let mut dst = [0u8; 32];
+			OsRng.try_fill_bytes(&mut dst).map_err(|err| Error::Config(err.to_string()))?;
+			Ok::<_, Error>(BASE64_URL_SAFE_NO_PAD.encode(dst))
+		};
+		let pending = Pending {
+			state: random()?,
+			verifier: random()?,
+		};
+		let mut url = Self::join(&self.public_url, &["auth"]);
+		url.query_pairs_mut()
+			.append_pair("client_id", &self.client_id)
+			.append_pair("redirect_uri", &self.redirect_uri)
+			.append_pair("state", &pending.state)
+			.append_pair("code_challenge", &challenge(&pending.verifier))
+			.append_pair("code_challenge_method", "S256");
+		Ok((url, pending))
+	}
+
+	/// ポータルから受け取ったコードとstateを検証し、ポータルのユーザー名を得る
+	pub async fn exchange(&self, code: &str, state: &str, pending: &Pending) -> Result<String, Error> {
+		if state != pending.state {
+			return Err(Error::Unauthorized("stateが一致しません".into()));
+		}
+		self.cert(&CertRequest {
+			code: code.into(),
+			redirect_uri: self.redirect_uri.clone(),
+			code_verifier: pending.verifier.clone(),
+		})
+		.await
+	}
+
+	/// postMessageの送信元確認に使うポータルのオリジン
+	pub fn origin(&self) -> String {
+		self.public_url.origin().ascii_serialization()
+	}
+
+	/// 認証コードを交換し、ポータルのユーザー名を得る
+	pub async fn cert(&self, req: &CertRequest) -> Result<String, Error> {
+		let url = self.endpoint(&["auth"]);
+		// コードは一度きりなので、リクエストが届いた可能性がある場合は再試行しない
+		let res = self.send(|| self.client.post(url.clone()).json(req), false).await?;
+		Ok(res.json::<CertResponse>().await?.user)
+	}
+
+	/// ユーザー情報を取得する
+	pub async fn user(&self, name: &str) -> Result<UserResponse, Error> {
+		let url = self.endpoint(&["auth", "user", name]);
+		let res = self.send(|| self.client.get(url.clone()), true).await?;
+		Ok(res.json().await?)
+	}
+
+	/// `app`に表示中のお知らせを取得する
+	pub async fn announcements(&self, app: &str) -> Result<Vec<Announcement>, Error> {
+		let mut url = self.endpoint(&["announcement"]);
+		url.query_pairs_mut().append_pair("app", app);
+		let res = self.send(|| self.client.get(url.clone()), true).await?;
+		Ok(res.json().await?)
+	}
+
+	/// アカウントが存在するか
+	pub async fn exists(&self, name: &str) -> Result<bool, Error> {
+		match self.user(name).await {
+			Ok(_) => Ok(true),
+			Err(Error::NotFound) => Ok(false),
+			Err(err) => Err(err),
+		}
+	}
+
+	fn endpoint(&self, segments: &[&str]) -> Url {
+		Self::join(&self.url, segments)
+	}
+
+	fn join(base: &Url, segments: &[&str]) -> Url {
+		let mut url = base.clone();
+		if let Ok(mut path) = url.path_segments_mut() {
+			path.pop_if_empty().extend(segments);
+		}
+		url
+	}
+
+	async fn send(&self, build: impl Fn() -> RequestBuilder, idempotent: bool) -> Result<Response, Error> {
+		// 再試行の初回待機時間　以降倍々に伸ばす
+		const WAIT: Duration = Duration::from_millis(200);
+
+		let mut attempt = 0;
+		let res = loop {
+			let res = build().basic_auth(&self.client_id, Some(&self.client_secret)).send().await;
+			let retry = match &res {
+				Ok(res) => idempotent && res.status().is_server_error(),
+				Err(err) => err.is_connect() || (idempotent && err.is_timeout()),
+			};
+			if !retry || attempt >= self.retries {
+				break res?;
+			}
+			tokio::time::sleep(WAIT * 2u32.pow(attempt)).await;
+			attempt += 1;
+		};
+		let status = res.status();
+		if status.is_success() {
+			return Ok(res);
+		}
+		let msg = res.text().await.unwrap_or_default();
+		Err(match status.as_u16() {
+			401 => Error::Unauthorized(msg),
+			404 => Error::NotFound,
+			403 | 503 => Error::Unavailable(msg),
+			code => Error::Status(code, msg),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use actix_web::{App, HttpResponse, HttpServer, web};
+
+	use super::*;
+
+	const CLIENT_ID: &str = "app";
+	const CLIENT_SECRET: &str = "secret";
+	const REDIRECT_URI: &str = "http://app.example/entry";
+
+	/// モックのポータルが受け取ったリクエスト数
+	#[derive(Default)]
+	struct Hits {
+		cert: AtomicUsize,
+		user: AtomicUsize,
+	}
+
+	/// 認証コード交換　コードで応答を切り替える
+	async fn cert(web::Json(req): web::Json<CertRequest>, hits: web::Data<Hits>) -> HttpResponse {
+		hits.cert.fetch_add(1, Ordering::SeqCst);
+		match req.code.as_str() {
+			"good" if req.redirect_uri == REDIRECT_URI && !req.code_verifier.is_empty() => HttpResponse::Ok().json(CertResponse { user: "alice".into() }),
+			"down" => HttpResponse::InternalServerError().body("内部エラー"),
+			_ => HttpResponse::Unauthorized().body("認証コードが不正です"),
+		}
+	}
+
+	/// ユーザー情報　`busy`は常に503、`slow`はタイムアウトより長く待つ
+	async fn user(name: web::Path<String>, hits: web::Data<Hits>) -> HttpResponse {
+		hits.user.fetch_add(1, Ordering::SeqCst);
+		match name.as_str() {
+			"alice" => HttpResponse::Ok().json(UserResponse { name: "alice".into(), webhook: None }),
+			"busy" => HttpResponse::ServiceUnavailable().body("メンテナンス中"),
+			"slow" => {
+				tokio::time::sleep(Duration::from_secs(2)).await;
+				HttpResponse::Ok().finish()
+			}
+			_ => HttpResponse::NotFound().finish(),
+		}
+	}
+
+	/// 空いているポートでモックのポータルを起動し、そこへ接続するクライアントを返す
+	fn mock() -> (Portal, web::Data<Hits>) {
+		let hits = web::Data::new(Hits::default());
+		let data = hits.clone();
+		let server = HttpServer::new(move || {
+			App::new()
+				.app_data(data.clone())
+				.route("/auth", web::post().to(cert))
+				.route("/auth/user/{name}", web::get().to(user))
+		})
+		.workers(1)
+		.bind(("127.0.0.1", 0))
+		.unwrap();
+		let addr = server.addrs()[0];
+		actix_web::rt::spawn(server.run());
+		let mut config = Config::new(&format!("http://{addr}"), CLIENT_ID, CLIENT_SECRET);
+		config.redirect_uri = REDIRECT_URI.into();
+		config.timeout = Duration::from_millis(500);
+		config.retries = 1;
+		(Portal::new(config).unwrap(), hits)
+	}
+
+	#[actix_web::test]
+	async fn exchange_returns_user() {
+		let (portal, hits) = mock();
+		let (_, pending) = portal.authorize().unwrap();
+		assert_eq!(portal.exchange("good", &pending.state, &pending).await.unwrap(), "alice");
+		assert_eq!(hits.cert.load(Ordering::SeqCst), 1);
+	}
+
+	#[actix_web::test]
+	async fn exchange_rejects_state_without_request() {
+		let (portal, hits) = mock();
+		let (_, pending) = portal.authorize().unwrap();
+		assert!(matches!(portal.exchange("good", "other", &pending).await, Err(Error::Unauthorized(_))));
+		assert_eq!(hits.cert.load(Ordering::SeqCst), 0);
+	}
+
+	#[actix_web::test]
+	async fn unauthorized_is_mapped() {
+		let (portal, _) = mock();
+		let (_, pending) = portal.authorize().unwrap();
+		let err = portal.exchange("bad", &pending.state, &pending).await.unwrap_err();
+		assert!(matches!(&err, Error::Unauthorized(msg) if msg == "認証コードが不正です"), "{err}");
+		assert_eq!(err.status_code(), StatusCode::UNAUTHORIZED);
+	}
+
+	#[actix_web::test]
+	async fn cert_is_not_retried() {
+		let (portal, hits) = mock();
+		let (_, pending) = portal.authorize().unwrap();
+		let err = portal.exchange("down", &pending.state, &pending).await.unwrap_err();
+		assert!(matches!(err, Error::Status(500, _)), "{err}");
+		assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+		assert_eq!(hits.cert.load(Ordering::SeqCst), 1);
+	}
+
+	#[actix_web::test]
+	async fn user_is_retried_and_mapped() {
+		let (portal, hits) = mock();
+		assert_eq!(portal.user("alice").await.unwrap().name, "alice");
+		assert!(!portal.exists("nobody").await.unwrap());
+		let Err(err) = portal.user("busy").await else {
+			panic!("busyが成功しました");
+		};
+		assert!(matches!(err, Error::Unavailable(_)), "{err}");
+		assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+		// alice・nobodyに1回ずつ、busyは再試行を含めて2回
+		assert_eq!(hits.user.load(Ordering::SeqCst), 4);
+	}
+
+	#[actix_web::test]
+	async fn timeout_is_mapped() {
+		let (portal, hits) = mock();
+		let Err(err) = portal.user("slow").await else {
+			panic!("slowが成功しました");
+		};
+		assert!(matches!(&err, Error::Request(x) if x.is_timeout()), "{err}");
+		assert_eq!(err.status_code(), StatusCode::GATEWAY_TIMEOUT);
+		// GETはタイムアウトも再試行する
+		assert_eq!(hits.user.load(Ordering::SeqCst), 2);
+	}
+}
@@ -0,0 +1,172 @@
+use std::{
+	fmt::Debug,
+	str::FromStr,
+	sync::{LazyLock, RwLock},
+	time::Duration,
+};
+
+use actix_web::web;
+use chrono::Local;
+use futures_util::future::select;
+use serde::Serialize;
+use sqlx::{SqlitePool, prelude::FromRow};
+use tokio::sync::Notify;
+
+use crate::{IsMaintenance, audit};
+
+/// 予約された状態変更
+#[derive(Clone, FromRow, Serialize)]
+pub struct Transition {
+	pub id: i64,
+	/// 変更後の状態（各アプリの`State`の文字列表現）
+	pub state: String,
+	/// 実行予定時刻
+	pub at: i64,
+	/// 予約した管理者
+	pub creator: String,
+}
+
+/// 予約一覧のキャッシュ　レイアウトでの告知はリクエストごとにDBを引かずにここから読む
+struct Cache {
+	upcoming: RwLock<Vec<Transition>>,
+	/// 予約が変わったらスケジューラーを起こす
+	notify: Notify,
+}
+static CACHE: LazyLock<Cache> = LazyLock::new(|| Cache {
+	upcoming: RwLock::new(Vec::new()),
+	notify: Notify::new(),
+});
+
+/// サーバー状態の予約変更
+///
+/// 予約はDBに保持し、プロセス内のスケジューラーが時刻になったら適用する
+/// 再起動中に時刻を過ぎた予約は、起動時に古い順に適用される
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table state_schedule
+///     id int pk
+///     state text
+///     at timestamp
+///     creator text
+///     created timestamp
+/// ```
+pub struct Schedule;
+
+impl Schedule {
+	/// これから実行される予約（時刻順）
+	pub fn upcoming() -> Vec<Transition> {
+		CACHE.upcoming.read().map(|x| x.clone()).unwrap_or_default()
+	}
+	/// 予約を追加する　監査ログ`entry`も同じトランザクションで記録する
+	pub async fn add(pool: &SqlitePool, state: &str, at: i64, creator: &str, entry: audit::Entry) -> Result<(), sqlx::Error> {
+		let now = Local::now().timestamp();
+		let mut tx = pool.begin().await?;
+		sqlx::query("INSERT INTO state_schedule(state,at,creator,created) VALUES(?,?,?,?)")
+			.bind(state)
+			.bind(at)
+			.bind(creator)
+			.bind(now)
+			.execute(&mut *tx)
+			.await?;
+		entry.record(&mut *tx).await?;
+		tx.commit().await?;
+		Self::reload(pool).await
+	}
+	/// 予約を取り消す　取り消した場合のみ、監査ログ`entry`も同じトランザクションで記録する
+	pub async fn cancel(pool: &SqlitePool, id: i64, entry: audit::Entry) -> Result<bool, sqlx::Error> {
+		let mut tx = pool.begin().await?;
+		let result = sqlx::query("DELETE FROM state_schedule WHERE id=?").bind(id).execute(&mut *tx).await?;
+		let deleted = result.rows_affected() > 0;
+		if deleted {
+			entry.record(&mut *tx).await?;
+		}
+		tx.commit().await?;
+		Self::reload(pool).await?;
+		Ok(deleted)
+	}
+
+	/// スケジューラーを起動する　`key`はsettingテーブルで状態を、`maintenance`はメンテナンスの内容を保持しているキー
+	///
+	/// # Example
+	/// ```ignore
+	/// Schedule::spawn(app.pool.as_ref().clone(), app.state.clone(), STATE, MAINTENANCE);
+	/// ```
+	pub fn spawn<T>(pool: SqlitePool, state: web::Data<RwLock<T>>, key: &'static str, maintenance: &'static str)
+	where
+		T: Debug + FromStr + ToString + IsMaintenance + 'static,
+	{
+		// 予約が無くても定期的に確認する間隔(秒)
+		const INTERVAL: i64 = 3600;
+
+		actix_web::rt::spawn(async move {
+			if let Err(err) = Self::reload(&pool).await {
+				eprintln!("{err}");
+			}
+			loop {
+				if let Err(err) = Self::run(&pool, &state, key, maintenance).await {
+					eprintln!("{err}");
+				}
+				let now = Local::now().timestamp();
+				let wait = Self::upcoming().first().map_or(INTERVAL, |x| x.at - now).clamp(1, INTERVAL);
+				let sleep = Box::pin(tokio::time::sleep(Duration::from_secs(wait as u64)));
+				let notified = Box::pin(CACHE.notify.notified());
+				select(sleep, notified).await;
+			}
+		});
+	}
+
+	/// 時刻を過ぎた予約を古い順に適用する
+	async fn run<T>(pool: &SqlitePool, state: &RwLock<T>, key: &str, maintenance: &str) -> Result<(), Box<dyn std::error::Error>>
+	where
+		T: Debug + FromStr + ToString + IsMaintenance,
+	{
+		let now = Local::now().timestamp();
+		let due: Vec<Transition> = sqlx::query_as("SELECT id,state,at,creator FROM state_schedule WHERE at<=? ORDER BY at ASC,id ASC")
+			.bind(now)
+			.fetch_all(pool)
+			.await?;
+		if due.is_empty() {
+			return Ok(());
+		}
+		for transition in due {
+			let mut tx = pool.begin().await?;
+			// 他で取り消し済みなら何もしない
+			let deleted = sqlx::query("DELETE FROM state_schedule WHERE id=?").bind(transition.id).execute(&mut *tx).await?;
+			if deleted.rows_affected() == 0 {
+				continue;
+			}
+			let Ok(new) = T::from_str(&transition.state) else {
+				eprintln!("無効な予約を破棄しました: {}", transition.state);
+				tx.commit().await?;
+				continue;
+			};
+			let before = state.read().map_err(|_| "アプリケーション状態読み込みに失敗")?.to_string();
+			sqlx::query("UPDATE setting SET value=? WHERE key=?").bind(&transition.state).bind(key).execute(&mut *tx).await?;
+			// メンテナンスの内容は状態と一緒に差し替える（以前のメンテナンスの文言・許可ユーザーを引き継がない）
+			let detail = new.maintenance().map(serde_json::to_string).transpose()?;
+			match &detail {
+				Some(detail) => sqlx::query("INSERT OR REPLACE INTO setting VALUES(?,?)").bind(maintenance).bind(detail).execute(&mut *tx).await?,
+				None => sqlx::query("DELETE FROM setting WHERE key=?").bind(maintenance).execute(&mut *tx).await?,
+			};
+			let after = detail.map_or(transition.state.clone(), |x| format!("{} {x}", transition.state));
+			audit::system("state.change", key).change(Some(&before), Some(&after)).record(&mut *tx).await?;
+			tx.commit().await?;
+			let mut guard = state.write().map_err(|_| "アプリケーション状態読み込みに失敗")?;
+			println!("{:?} -> {:?} (予約: {})", guard, new, transition.creator);
+			*guard = new;
+		}
+		Self::reload(pool).await?;
+		Ok(())
+	}
+
+	/// キャッシュを読み直し、スケジューラーを起こす
+	async fn reload(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+		let upcoming: Vec<Transition> = sqlx::query_as("SELECT id,state,at,creator FROM state_schedule ORDER BY at ASC,id ASC").fetch_all(pool).await?;
+		if let Ok(mut cache) = CACHE.upcoming.write() {
+			*cache = upcoming;
+		}
+		CACHE.notify.notify_one();
+		Ok(())
+	}
+}
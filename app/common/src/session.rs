@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use actix_session::{
+	Session,
+	storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError, generate_session_key},
+};
+use actix_web::cookie::time::Duration;
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::Local;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use sqlx::{SqliteExecutor, SqlitePool, prelude::FromRow};
+
+/// ストアが読み込み時に付与する、セッションを識別するためのキー（保存はされない）
+const SESSION_ID: &str = "session-id";
+
+type State = HashMap<String, String>;
+
+/// SQLiteにセッションを保持するストア
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table session
+///     id text pk   # セッションキーのハッシュ
+///     state text   # JSON
+///     user text?   # identity::user_keyの形式
+///     created timestamp
+///     last_seen timestamp
+///     expiry timestamp
+/// ```
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+	pool: SqlitePool,
+}
+
+impl SqliteSessionStore {
+	pub fn new(pool: SqlitePool) -> Self {
+		Self { pool }
+	}
+
+	/// リクエスト中のセッションのID
+	pub fn current(session: &Session) -> Option<String> {
+		session.get(SESSION_ID).ok().flatten()
+	}
+	/// ユーザーの有効なセッション一覧
+	pub async fn list(pool: &SqlitePool, user: &str) -> Result<Vec<Record>, sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query_as("SELECT id,created,last_seen FROM session WHERE user=? AND expiry>? ORDER BY last_seen DESC")
+			.bind(user)
+			.bind(now)
+			.fetch_all(pool)
+			.await
+	}
+	/// ユーザーのセッションを1つ破棄する
+	pub async fn revoke(pool: &SqlitePool, user: &str, id: &str) -> Result<bool, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM session WHERE user=? AND id=?").bind(user).bind(id).execute(pool).await?;
+		Ok(result.rows_affected() > 0)
+	}
+	/// ユーザーのセッションを全て破棄する　`except`に指定したセッションは残す
+	pub async fn revoke_all(executor: impl SqliteExecutor<'_>, user: &str, except: Option<&str>) -> Result<(), sqlx::Error> {
+		sqlx::query("DELETE FROM session WHERE user=? AND id IS NOT ?").bind(user).bind(except).execute(executor).await?;
+		Ok(())
+	}
+
+	/// DBにはセッションキーそのものではなくハッシュを保持する
+	fn id(key: &SessionKey) -> String {
+		BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(key.as_ref().as_bytes()))
+	}
+	/// 保存する状態とユーザーを取り出す
+	fn serialize(mut state: State) -> Result<(String, Option<String>), serde_json::Error> {
+		state.remove(SESSION_ID);
+		let user = match state.get(crate::identity::KEY) {
+			Some(x) => Some(crate::identity::user_key(&serde_json::from_str::<serde_json::Value>(x)?)?),
+			None => None,
+		};
+		Ok((serde_json::to_string(&state)?, user))
+	}
+	async fn insert(&self, state: &str, user: Option<&str>, ttl: &Duration) -> Result<SessionKey, sqlx::Error> {
+		let now = Local::now().timestamp();
+		// ついでに期限切れを掃除
+		sqlx::query("DELETE FROM session WHERE expiry<?").bind(now).execute(&self.pool).await?;
+		let key = generate_session_key();
+		sqlx::query("INSERT INTO session(id,state,user,created,last_seen,expiry) VALUES(?,?,?,?,?,?)")
+			.bind(Self::id(&key))
+			.bind(state)
+			.bind(user)
+			.bind(now)
+			.bind(now)
+			.bind(now + ttl.whole_seconds())
+			.execute(&self.pool)
+			.await?;
+		Ok(key)
+	}
+}
+
+/// セッション一覧の1件
+#[derive(FromRow, Serialize)]
+pub struct Record {
+	pub id: String,
+	pub created: i64,
+	pub last_seen: i64,
+}
+
+impl SessionStore for SqliteSessionStore {
+	async fn load(&self, session_key: &SessionKey) -> Result<Option<State>, LoadError> {
+		let now = Local::now().timestamp();
+		let id = Self::id(session_key);
+		let state: Option<String> = sqlx::query_scalar("UPDATE session SET last_seen=?1 WHERE id=?2 AND expiry>?1 RETURNING state")
+			.bind(now)
+			.bind(&id)
+			.fetch_optional(&self.pool)
+			.await
+			.map_err(|err| LoadError::Other(err.into()))?;
+		let Some(state) = state else {
+			return Ok(None);
+		};
+		let mut state: State = serde_json::from_str(&state).map_err(|err| LoadError::Deserialization(err.into()))?;
+		state.insert(SESSION_ID.into(), serde_json::to_string(&id).map_err(|err| LoadError::Deserialization(err.into()))?);
+		Ok(Some(state))
+	}
+
+	async fn save(&self, session_state: State, ttl: &Duration) -> Result<SessionKey, SaveError> {
+		let (state, user) = Self::serialize(session_state).map_err(|err| SaveError::Serialization(err.into()))?;
+		self.insert(&state, user.as_deref(), ttl).await.map_err(|err| SaveError::Other(err.into()))
+	}
+
+	async fn update(&self, session_key: SessionKey, session_state: State, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+		let now = Local::now().timestamp();
+		let (state, user) = Self::serialize(session_state).map_err(|err| UpdateError::Serialization(err.into()))?;
+		let result = sqlx::query("UPDATE session SET state=?,user=?,last_seen=?,expiry=? WHERE id=?")
+			.bind(&state)
+			.bind(&user)
+			.bind(now)
+			.bind(now + ttl.whole_seconds())
+			.bind(Self::id(&session_key))
+			.execute(&self.pool)
+			.await
+			.map_err(|err| UpdateError::Other(err.into()))?;
+		if result.rows_affected() > 0 {
+			Ok(session_key)
+		} else {
+			// 読み込んだ後に破棄・失効したセッションは、中身を引き継がずに空のセッションとして作り直す
+			// （ログイン状態を書き戻すと、破棄したセッションが別のキーで復活してしまう）
+			self.insert("{}", None, ttl).await.map_err(|err| UpdateError::Other(err.into()))
+		}
+	}
+
+	async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query("UPDATE session SET expiry=? WHERE id=?")
+			.bind(now + ttl.whole_seconds())
+			.bind(Self::id(session_key))
+			.execute(&self.pool)
+			.await?;
+		Ok(())
+	}
+
+	async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+		sqlx::query("DELETE FROM session WHERE id=?").bind(Self::id(session_key)).execute(&self.pool).await?;
+		Ok(())
+	}
+}
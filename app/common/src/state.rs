@@ -1,18 +1,76 @@
 use std::{
 	future::{Ready, ready},
+	marker::PhantomData,
 	ops::Deref,
+	rc::Rc,
 	sync::RwLock,
 };
 
-use actix_web::{FromRequest, error::*, web};
+use actix_session::SessionExt as _;
+use actix_web::{
+	App, FromRequest, HttpRequest, HttpResponse,
+	body::{BoxBody, EitherBody},
+	dev::{Service, ServiceRequest, ServiceResponse, Transform},
+	error::*,
+	http::{Method, header},
+	mime, web,
+};
+use chrono::Local;
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Admin, identity};
+
+/// メンテナンスの内容
+///
+/// 各アプリの`State`のメンテナンス状態に持たせる　settingテーブルにはJSONで保存する
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintenance {
+	/// 利用者に表示する文言
+	#[serde(default)]
+	pub message: Option<String>,
+	/// 終了予定時刻
+	#[serde(default)]
+	pub until: Option<i64>,
+	/// メンテナンス中も利用できるユーザー（identity::user_keyの形式）
+	#[serde(default)]
+	pub allow: Vec<String>,
+}
+
+impl Maintenance {
+	/// Retry-Afterの秒数　終了予定が無い、または過ぎていれば既定値
+	pub fn retry_after(&self) -> i64 {
+		// 終了予定が無い場合(秒)
+		const DEFAULT: i64 = 3600;
+		// 終了予定を過ぎている場合(秒)
+		const OVERRUN: i64 = 60;
+
+		let now = Local::now().timestamp();
+		self.until.map_or(DEFAULT, |x| (x - now).max(OVERRUN))
+	}
+}
 
 pub trait IsMaintenance {
-	fn is_maintenance(&self) -> bool;
+	/// メンテナンス中ならその内容
+	fn maintenance(&self) -> Option<&Maintenance>;
+	fn is_maintenance(&self) -> bool {
+		self.maintenance().is_some()
+	}
 }
 
+/// メンテナンス中に表示するページを生成する関数　各アプリのテンプレートで描画するため`app_data`に登録しておく
+///
+/// 未登録、またはHTMLを受け付けないリクエストにはJSONで返す
+pub struct MaintenancePage(pub fn(&Maintenance) -> String);
+
+/// サーバー状態
+///
+/// 状態による許可・拒否は`Policy`で行う　ハンドラーで状態の値を使う場合に取り出す
+///
 /// # Example
-/// ```
-/// let app_data = web::Data::new(common::StateHandle::new(State::Active).pack());
+/// ```ignore
+/// let app_data = web::Data::new(RwLock::new(State::Active));
 /// ```
 pub struct Handle<T: Clone + IsMaintenance>(T);
 
@@ -32,17 +90,342 @@ impl<T: Clone + IsMaintenance + 'static> FromRequest for Handle<T> {
 	type Error = actix_web::Error;
 	type Future = Ready<Result<Self, Self::Error>>;
 
-	fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
-		ready((|| {
-			let state = req.app_data::<web::Data<RwLock<T>>>().ok_or(ErrorInternalServerError("アプリケーション状態が未定義"))?;
-			let guard = state.read().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?;
-			let state = guard.clone();
-			drop(guard);
-			if !state.is_maintenance() {
-				Ok(Handle(state))
-			} else {
-				Err(ErrorForbidden("メンテナンス中"))
+	fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+		ready(read(req).map(Handle))
+	}
+}
+
+fn read<T: Clone + 'static>(req: &HttpRequest) -> Result<T, actix_web::Error> {
+	let state = req.app_data::<web::Data<RwLock<T>>>().ok_or(ErrorInternalServerError("アプリケーション状態が未定義"))?;
+	Ok(state.read().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?.clone())
+}
+
+/// ルートごとに許可するサーバー状態の宣言
+///
+/// 各スコープの`cfg`と並べて定義し、`Policy::scope`でまとめる
+///
+/// # Example
+/// ```ignore
+/// pub const POLICY: &[StateRule] = &[
+///     StateRule::new("", &[Method::GET], &["active", "close"]),
+///     StateRule::new("", &[Method::POST], &["active"]),
+/// ];
+/// ```
+pub struct Rule {
+	path: &'static str,
+	methods: &'static [Method],
+	states: &'static [&'static str],
+}
+
+impl Rule {
+	/// `path`はスコープ内のパス（`web::resource`と同じ表記）　`methods`が空なら全てのメソッド
+	/// `states`は各アプリの`State`の文字列表現
+	pub const fn new(path: &'static str, methods: &'static [Method], states: &'static [&'static str]) -> Self {
+		Self { path, methods, states }
+	}
+}
+
+/// 状態ポリシー
+///
+/// アプリ全体に`wrap`すると、リクエストのルート（`match_pattern`）とメソッドに合う宣言で状態を確認する
+/// 宣言の無いルート・メソッドは状態にかかわらず拒否する（ルートを追加したら宣言も追加すること）
+/// メンテナンス中は管理者としてログインしているか、`Maintenance::allow`に含まれるユーザーを通し、それ以外は503
+///
+/// # Example
+/// ```ignore
+/// let policy = StatePolicy::<State>::new().exempt("/admin").scope("/profile", profile::POLICY);
+/// App::new().wrap(policy.clone()).app_data(web::Data::new(policy))
+/// ```
+pub struct Policy<T> {
+	routes: Vec<(String, &'static Rule)>,
+	exempt: Vec<String>,
+	_state: PhantomData<fn() -> T>,
+}
+
+/// 実効ポリシーの1件（管理画面での確認用）
+#[derive(Serialize)]
+pub struct Effective {
+	pub pattern: String,
+	/// `*`なら全て
+	pub methods: String,
+	/// `*`なら全て
+	pub states: String,
+}
+
+enum Lookup {
+	Exempt,
+	Allow(&'static [&'static str]),
+	Undeclared,
+}
+
+impl<T> Policy<T> {
+	pub fn new() -> Self {
+		Self {
+			routes: Vec::new(),
+			exempt: Vec::new(),
+			_state: PhantomData,
+		}
+	}
+	/// スコープ（`prefix`）内の宣言を登録する
+	pub fn scope(mut self, prefix: &str, rules: &'static [Rule]) -> Self {
+		for rule in rules {
+			self.routes.push((normalize(&format!("{prefix}/{}", rule.path)), rule));
+		}
+		self
+	}
+	/// `prefix`以下は状態にかかわらず通す（独自のガードがある管理画面など）
+	pub fn exempt(mut self, prefix: &str) -> Self {
+		self.exempt.push(normalize(prefix));
+		self
+	}
+	/// 宣言されている実効ポリシー
+	pub fn describe(&self) -> Vec<Effective> {
+		let exempt = self.exempt.iter().map(|x| Effective {
+			pattern: format!("{x}/*"),
+			methods: "*".into(),
+			states: "*".into(),
+		});
+		let routes = self.routes.iter().map(|(pattern, rule)| Effective {
+			pattern: pattern.clone(),
+			methods: match rule.methods {
+				[] => "*".into(),
+				x => x.iter().map(Method::as_str).collect::<Vec<_>>().join(", "),
+			},
+			states: rule.states.join(", "),
+		});
+		exempt.chain(routes).collect()
+	}
+	/// アプリの`cfg`に登録されているルート・メソッドと宣言を突き合わせ、食い違いを返す（テスト用）
+	///
+	/// 登録されているのに宣言の無いもの（実行時は拒否される）と、宣言されているのに登録の無いものを挙げる
+	/// 登録の有無はパラメーターを`1`で埋めたリクエストを各メソッドで送って確かめる（アプリのデータは登録しないので、ハンドラーは抽出の段階で失敗する）
+	/// OPTIONSはCORSのプリフライトとして送るので、CORSのミドルウェアを付けたスコープだけが登録済みになる
+	///
+	/// # Example
+	/// ```ignore
+	/// let mismatches = policy().mismatches(cfg).await;
+	/// assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+	/// ```
+	pub async fn mismatches(&self, cfg: impl FnOnce(&mut web::ServiceConfig)) -> Vec<String> {
+		use actix_web::{http::StatusCode, test};
+
+		const METHODS: [Method; 6] = [Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS];
+		// 登録済みのルートを取り出すためのルート
+		const ROUTES: &str = "/__routes";
+
+		let app = test::init_service(
+			App::new()
+				.configure(cfg)
+				.route(ROUTES, web::get().to(|req: HttpRequest| async move { format!("{:#?}", req.resource_map()) }))
+				// どのルートにも合わないものを区別する
+				.default_service(web::to(HttpResponse::ImATeapot)),
+		)
+		.await;
+		let routes = String::from_utf8_lossy(&test::call_and_read_body(&app, test::TestRequest::get().uri(ROUTES).to_request()).await).into_owned();
+
+		let mut registered = Vec::new();
+		let mut result = Vec::new();
+		for pattern in resource_patterns(&routes).into_iter().filter(|x| x != ROUTES) {
+			let path = normalize(&fill_params(&pattern));
+			let mut found = false;
+			for method in METHODS {
+				let mut req = test::TestRequest::default().method(method.clone()).uri(&path);
+				if method == Method::OPTIONS {
+					// CORSのミドルウェアが応答するプリフライト　許可していないオリジンでも405にはならない
+					req = req.insert_header((header::ORIGIN, "http://mismatches.invalid")).insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"));
+				}
+				match test::call_service(&app, req.to_request()).await.status() {
+					// `route`で登録したものはメソッドがリソースのガードになるので、他のメソッドはどのルートにも合わない
+					StatusCode::METHOD_NOT_ALLOWED | StatusCode::IM_A_TEAPOT => continue,
+					_ => found = true,
+				}
+				if let Lookup::Undeclared = self.lookup(&pattern, &method) {
+					result.push(format!("{method} {}: 宣言がありません", normalize(&pattern)));
+				}
+				registered.push((normalize(&pattern), method));
 			}
-		})())
+			if !found {
+				result.push(format!("{pattern}: パラメーターを埋めたパス({path})がルートに合いません"));
+			}
+		}
+		for (pattern, rule) in &self.routes {
+			let missing: Vec<_> = match rule.methods {
+				[] if !registered.iter().any(|(x, _)| x == pattern) => vec!["*".to_string()],
+				methods => methods.iter().filter(|m| !registered.iter().any(|(x, y)| x == pattern && y == *m)).map(Method::to_string).collect(),
+			};
+			for method in missing {
+				result.push(format!("{method} {pattern}: 登録されていません"));
+			}
+		}
+		result
+	}
+
+	fn lookup(&self, pattern: &str, method: &Method) -> Lookup {
+		let pattern = normalize(pattern);
+		if self.exempt.iter().any(|x| pattern == *x || pattern.strip_prefix(x.as_str()).is_some_and(|x| x.starts_with('/'))) {
+			return Lookup::Exempt;
+		}
+		self.routes
+			.iter()
+			.find(|(x, rule)| *x == pattern && (rule.methods.is_empty() || rule.methods.contains(method)))
+			.map_or(Lookup::Undeclared, |(_, rule)| Lookup::Allow(rule.states))
+	}
+}
+impl<T> Default for Policy<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl<T> Clone for Policy<T> {
+	fn clone(&self) -> Self {
+		Self {
+			routes: self.routes.clone(),
+			exempt: self.exempt.clone(),
+			_state: PhantomData,
+		}
+	}
+}
+
+/// 先頭に`/`を付け、末尾の`/`を取る
+fn normalize(path: &str) -> String {
+	format!("/{}", path.trim_matches('/'))
+}
+
+/// `ResourceMap`のデバッグ表記から、末端（リソース）のパターンを親のスコープと連結して取り出す
+fn resource_patterns(debug: &str) -> Vec<String> {
+	// (インデント, パターン)
+	let mut stack: Vec<(usize, String)> = Vec::new();
+	let mut result = Vec::new();
+	let mut lines = debug.lines();
+	while let Some(line) = lines.next() {
+		let indent = line.len() - line.trim_start().len();
+		match line.trim() {
+			"ResourceMap {" => {
+				stack.retain(|(x, _)| *x < indent);
+				stack.push((indent, String::new()));
+			}
+			"patterns: Single(" => {
+				let pattern = lines.next().map(|x| x.trim().trim_end_matches(',').trim_matches('"').to_string()).unwrap_or_default();
+				if let Some(last) = stack.last_mut() {
+					last.1 = pattern;
+				}
+			}
+			"nodes: None," => result.push(stack.iter().map(|(_, x)| x.as_str()).collect()),
+			_ => (),
+		}
+	}
+	result
+}
+
+/// パターンのパラメーター（`{id}`など）を`1`で埋める
+fn fill_params(pattern: &str) -> String {
+	let mut result = String::new();
+	let mut rest = pattern;
+	while let Some(start) = rest.find('{') {
+		result.push_str(&rest[..start]);
+		result.push('1');
+		rest = rest[start..].find('}').map_or("", |end| &rest[start + end + 1..]);
+	}
+	result.push_str(rest);
+	result
+}
+
+impl<S, B, T> Transform<S, ServiceRequest> for Policy<T>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+	T: Clone + IsMaintenance + ToString + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type InitError = ();
+	type Transform = PolicyImpl<S, T>;
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ready(Ok(PolicyImpl {
+			service: Rc::new(service),
+			policy: Rc::new(self.clone()),
+		}))
 	}
 }
+
+pub struct PolicyImpl<S, T> {
+	service: Rc<S>,
+	policy: Rc<Policy<T>>,
+}
+
+impl<S, B, T> Service<ServiceRequest> for PolicyImpl<S, T>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+	B: 'static,
+	T: Clone + IsMaintenance + ToString + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B, BoxBody>>;
+	type Error = actix_web::Error;
+	type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&self, ctx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+		self.service.poll_ready(ctx)
+	}
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		let service = self.service.clone();
+		let policy = self.policy.clone();
+		Box::pin(async move {
+			// どのルートにも合わなければ404になるのでそのまま通す
+			let Some(pattern) = req.match_pattern() else {
+				return Ok(service.call(req).await?.map_into_left_body());
+			};
+			let states = match policy.lookup(&pattern, req.method()) {
+				Lookup::Exempt => return Ok(service.call(req).await?.map_into_left_body()),
+				Lookup::Allow(states) => states,
+				Lookup::Undeclared => {
+					eprintln!("状態ポリシーが未定義です: {} {pattern}", req.method());
+					return Ok(req.error_response(ErrorForbidden("この操作は許可されていません")).map_into_right_body());
+				}
+			};
+			let state = match read::<T>(req.request()) {
+				Ok(x) => x,
+				Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+			};
+			if states.contains(&state.to_string().as_str()) {
+				return Ok(service.call(req).await?.map_into_left_body());
+			}
+			let err = match state.maintenance() {
+				Some(maintenance) if bypass(req.request(), maintenance).await => {
+					return Ok(service.call(req).await?.map_into_left_body());
+				}
+				Some(maintenance) => unavailable(req.request(), maintenance),
+				None => ErrorForbidden("当サイトはクローズしています"),
+			};
+			Ok(req.error_response(err).map_into_right_body())
+		})
+	}
+}
+
+/// メンテナンス中も通すか　管理者と許可されたユーザー
+async fn bypass(req: &HttpRequest, maintenance: &Maintenance) -> bool {
+	if Admin::extract(req).await.is_ok() {
+		return true;
+	}
+	let user = req.get_session().get::<Value>(identity::KEY).ok().flatten().and_then(|x| identity::user_key(&x).ok());
+	user.is_some_and(|x| maintenance.allow.contains(&x))
+}
+
+/// メンテナンス中の503
+fn unavailable(req: &HttpRequest, maintenance: &Maintenance) -> actix_web::Error {
+	let message = maintenance.message.clone().unwrap_or_else(|| "メンテナンス中".into());
+	let html = req.headers().get(header::ACCEPT).and_then(|x| x.to_str().ok()).is_some_and(|x| x.contains("text/html"));
+	let mut builder = HttpResponse::ServiceUnavailable();
+	builder.insert_header((header::RETRY_AFTER, maintenance.retry_after()));
+	let res = match req.app_data::<web::Data<MaintenancePage>>().filter(|_| html) {
+		Some(page) => builder.content_type(mime::TEXT_HTML).body((page.0)(maintenance)),
+		None => builder.json(serde_json::json!({
+			"error": "maintenance",
+			"message": &message,
+			"until": maintenance.until,
+		})),
+	};
+	InternalError::from_response(message, res).into()
+}
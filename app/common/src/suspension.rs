@@ -0,0 +1,116 @@
+use actix_web::{HttpRequest, HttpResponse, error::*, http::header, mime, web};
+use chrono::Local;
+use serde::Serialize;
+use sqlx::{SqlitePool, prelude::FromRow};
+
+/// 利用停止
+///
+/// 利用停止中のユーザーも閲覧(GET/HEAD)はできるが、書き込みは`Identity`・`Authorized`の時点で403になる
+///
+/// 各アプリのdatabase.schに以下のテーブルを定義しておくこと
+/// ```text
+/// table suspension
+///     user text pk           # identity::user_keyの形式
+///     reason text
+///     issuer text            # 停止した管理者
+///     created timestamp
+///     expiry timestamp?      # NULLなら無期限
+/// ```
+#[derive(FromRow, Serialize)]
+pub struct Suspension {
+	pub user: String,
+	pub reason: String,
+	pub issuer: String,
+	pub created: i64,
+	pub expiry: Option<i64>,
+}
+
+impl Suspension {
+	/// 停止日数の上限　これより長くするなら無期限にする
+	pub const MAX_DAYS: i64 = 3650;
+
+	/// 停止日数から期限を求める　`None`なら無期限、範囲外なら400
+	pub fn expiry(days: Option<i64>) -> Result<Option<i64>, actix_web::Error> {
+		match days {
+			Some(days) if !(1..=Self::MAX_DAYS).contains(&days) => Err(ErrorBadRequest(format!("停止日数は1〜{}日で指定してください", Self::MAX_DAYS))),
+			Some(days) => Ok(Some(Local::now().timestamp() + days * 86400)),
+			None => Ok(None),
+		}
+	}
+	/// 有効な利用停止を取得する
+	pub async fn find(pool: &SqlitePool, user: &str) -> Result<Option<Self>, sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query_as("SELECT user,reason,issuer,created,expiry FROM suspension WHERE user=? AND (expiry IS NULL OR expiry>?)")
+			.bind(user)
+			.bind(now)
+			.fetch_optional(pool)
+			.await
+	}
+	/// 有効な利用停止の一覧
+	pub async fn list(pool: &SqlitePool) -> Result<Vec<Self>, sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query_as("SELECT user,reason,issuer,created,expiry FROM suspension WHERE expiry IS NULL OR expiry>? ORDER BY created DESC")
+			.bind(now)
+			.fetch_all(pool)
+			.await
+	}
+	/// 利用停止する　既に停止中なら内容を上書きする
+	pub async fn issue(pool: &SqlitePool, user: &str, reason: &str, issuer: &str, expiry: Option<i64>) -> Result<(), sqlx::Error> {
+		let now = Local::now().timestamp();
+		sqlx::query("INSERT OR REPLACE INTO suspension(user,reason,issuer,created,expiry) VALUES(?,?,?,?,?)")
+			.bind(user)
+			.bind(reason)
+			.bind(issuer)
+			.bind(now)
+			.bind(expiry)
+			.execute(pool)
+			.await?;
+		Ok(())
+	}
+	/// 利用停止を解除する
+	pub async fn lift(pool: &SqlitePool, user: &str) -> Result<bool, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM suspension WHERE user=?").bind(user).execute(pool).await?;
+		Ok(result.rows_affected() > 0)
+	}
+}
+
+/// 利用停止中に表示するページを生成する関数　各アプリのテンプレートで描画するため`app_data`に登録しておく
+///
+/// 未登録ならテキストで理由を返す
+pub struct SuspendedPage(pub fn(&Suspension) -> String);
+
+/// `Identity`・`Authorized`から使う利用停止の確認
+pub(crate) struct Guard {
+	pool: Option<web::Data<SqlitePool>>,
+	page: Option<web::Data<SuspendedPage>>,
+	write: bool,
+	html: bool,
+}
+
+impl Guard {
+	pub(crate) fn new(req: &HttpRequest) -> Self {
+		Self {
+			pool: req.app_data::<web::Data<SqlitePool>>().cloned(),
+			page: req.app_data::<web::Data<SuspendedPage>>().cloned(),
+			write: !req.method().is_safe(),
+			html: req.headers().get(header::ACCEPT).and_then(|x| x.to_str().ok()).is_some_and(|x| x.contains("text/html")),
+		}
+	}
+
+	/// 書き込みリクエストなら利用停止中でないか確認する
+	pub(crate) async fn check(self, user: &str) -> Result<(), actix_web::Error> {
+		if !self.write {
+			return Ok(());
+		}
+		let pool = self.pool.ok_or(ErrorInternalServerError("DBが未定義"))?;
+		let Some(suspension) = Suspension::find(pool.as_ref(), user).await.map_err(ErrorInternalServerError)? else {
+			return Ok(());
+		};
+		let message = format!("アカウントが利用停止中のため操作できません\n理由: {}", suspension.reason);
+		let res = match self.page.filter(|_| self.html) {
+			Some(page) => HttpResponse::Forbidden().content_type(mime::TEXT_HTML).body((page.0)(&suspension)),
+			None => HttpResponse::Forbidden().content_type(mime::TEXT_PLAIN).body(message.clone()),
+		};
+		Err(InternalError::from_response(message, res).into())
+	}
+}
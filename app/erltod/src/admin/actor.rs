@@ -0,0 +1,180 @@
+use actix_session::Session;
+use actix_web::{Responder, error::*, web};
+use chrono::Local;
+use common::{Admin, ApiToken, Auditor, Impersonation, SqliteSessionStore, Suspension, identity::user_key};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use super::{Search, datetime, redirect, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("{eno}").get(edit).post(save));
+	cfg.service(web::resource("{eno}/delete").post(delete));
+	cfg.service(web::resource("{eno}/suspend").post(suspend));
+	cfg.service(web::resource("{eno}/lift").post(lift));
+	cfg.service(web::resource("{eno}/impersonate").post(impersonate));
+}
+
+// キャラクター一覧
+async fn list(web::Query(info): web::Query<Search>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(FromRow)]
+	struct Row {
+		eno: i64,
+		user: String,
+		name: String,
+		comment: String,
+		reason: Option<String>,
+		expiry: Option<i64>,
+	}
+	#[derive(Serialize)]
+	struct Record {
+		eno: i64,
+		user: String,
+		name: String,
+		comment: String,
+		suspended: Option<String>,
+	}
+	let now = Local::now().timestamp();
+	let search = format!("%{}%", info.q);
+	// Enoでも検索できるように
+	let eno = info.q.parse::<i64>().ok();
+	let mut rows: Vec<Row> = sqlx::query_as(
+		"SELECT a.eno,a.user,a.name,a.comment,s.reason,s.expiry FROM actor a \
+		LEFT JOIN suspension s ON s.user=CAST(a.eno AS TEXT) AND (s.expiry IS NULL OR s.expiry>?1) \
+		WHERE a.name LIKE ?2 OR a.user LIKE ?2 OR a.eno IS ?3 ORDER BY a.eno ASC LIMIT ?4,?5",
+	)
+	.bind(now)
+	.bind(search)
+	.bind(eno)
+	.bind(info.page.offset() as i64)
+	.bind(info.fetch_limit())
+	.fetch_all(pool.as_ref())
+	.await?;
+	let pager = info.pager(&mut rows);
+	let records: Vec<Record> = rows
+		.into_iter()
+		.map(|x| Record {
+			suspended: x.reason.map(|reason| match x.expiry {
+				Some(expiry) => format!("{reason}（{}まで）", datetime(expiry)),
+				None => format!("{reason}（無期限）"),
+			}),
+			eno: x.eno,
+			user: x.user,
+			name: x.name,
+			comment: x.comment,
+		})
+		.collect();
+	render("html/admin/actor/list.html", liquid::object!({ "actors": &records, "pager": &pager }))
+}
+
+// 編集画面
+#[derive(Deserialize)]
+struct Eno {
+	eno: i64,
+}
+async fn edit(path: web::Path<Eno>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	let pool = pool.as_ref();
+	let record = sqlx::query!("SELECT user,name,comment,profile FROM actor WHERE eno=?", eno)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorNotFound("キャラクターが存在しません"))?;
+	let suspension = Suspension::find(pool, &user_key(&eno)?).await?;
+	let expiry = suspension.as_ref().and_then(|x| x.expiry).map(datetime);
+	render(
+		"html/admin/actor/edit.html",
+		liquid::object!({
+			"eno": eno,
+			"user": &record.user,
+			"name": &record.name,
+			"comment": &record.comment,
+			"profile": &record.profile,
+			"suspension": &suspension,
+			"expiry": &expiry,
+		}),
+	)
+}
+
+// 編集
+#[derive(Deserialize)]
+struct Save {
+	name: String,
+	comment: String,
+	profile: String,
+}
+async fn save(path: web::Path<Eno>, web::Form(info): web::Form<Save>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	if info.name.is_empty() {
+		return Err(ErrorBadRequest("キャラクター名を入力してください").into());
+	}
+	let mut tx = pool.begin().await?;
+	let before = sqlx::query_scalar!("SELECT name FROM actor WHERE eno=?", eno)
+		.fetch_optional(&mut *tx)
+		.await?
+		.ok_or(ErrorNotFound("キャラクターが存在しません"))?;
+	sqlx::query!("UPDATE actor SET name=?,comment=?,profile=? WHERE eno=?", info.name, info.comment, info.profile, eno)
+		.execute(&mut *tx)
+		.await?;
+	auditor.admin(&admin, "actor.update", &eno.to_string()).change(Some(&before), Some(&info.name)).record(&mut *tx).await?;
+	tx.commit().await?;
+	Ok(redirect(&format!("/admin/actor/{eno}")))
+}
+
+// 削除
+async fn delete(path: web::Path<Eno>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	let pool = pool.as_ref();
+	let name = sqlx::query_scalar!("DELETE FROM actor WHERE eno=? RETURNING name", eno).fetch_optional(pool).await?;
+	if let Some(name) = name {
+		auditor.admin(&admin, "actor.delete", &eno.to_string()).change(Some(&name), None).record(pool).await?;
+	}
+	let user = user_key(&eno)?;
+	SqliteSessionStore::revoke_all(pool, &user, None).await?;
+	ApiToken::revoke_all(pool, &user).await?;
+	Ok(redirect("/admin/actor"))
+}
+
+// 利用停止
+#[derive(Deserialize)]
+struct Suspend {
+	reason: String,
+	/// 停止日数　空欄なら無期限
+	days: String,
+}
+async fn suspend(path: web::Path<Eno>, web::Form(info): web::Form<Suspend>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	if info.reason.trim().is_empty() {
+		return Err(ErrorBadRequest("理由を入力してください").into());
+	}
+	let days = match info.days.trim() {
+		"" => None,
+		days => Some(days.parse::<i64>().map_err(|_| ErrorBadRequest("停止日数は数値で指定してください"))?),
+	};
+	let expiry = Suspension::expiry(days)?;
+	Suspension::issue(pool.as_ref(), &user_key(&eno)?, &info.reason, &admin.name, expiry).await?;
+	auditor.admin(&admin, "actor.suspend", &eno.to_string()).change(None, Some(&info.reason)).record(pool.as_ref()).await?;
+	Ok(redirect(&format!("/admin/actor/{eno}")))
+}
+
+// 利用停止の解除
+async fn lift(path: web::Path<Eno>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	if Suspension::lift(pool.as_ref(), &user_key(&eno)?).await? {
+		auditor.admin(&admin, "actor.lift", &eno.to_string()).record(pool.as_ref()).await?;
+	}
+	Ok(redirect(&format!("/admin/actor/{eno}")))
+}
+
+// なりすまし（キャラクターとして閲覧）　終了は`/admin/impersonate/end`
+async fn impersonate(path: web::Path<Eno>, admin: Admin, auditor: Auditor, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = path.into_inner().eno;
+	sqlx::query_scalar!("SELECT eno FROM actor WHERE eno=?", eno)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorNotFound("キャラクターが存在しません"))?;
+	Impersonation::start(&session, &admin, &eno)?;
+	auditor.admin(&admin, "actor.impersonate", &eno.to_string()).record(pool.as_ref()).await?;
+	Ok(redirect("/profile"))
+}
@@ -0,0 +1,54 @@
+use actix_web::{HttpResponse, Responder, http::header, web};
+use common::audit::{Filter, Record};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{Search, datetime, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("export").get(export));
+}
+
+#[derive(Deserialize)]
+struct Query {
+	#[serde(flatten)]
+	search: Search,
+	#[serde(flatten)]
+	filter: Filter,
+}
+
+// 監査ログ一覧
+async fn list(web::Query(info): web::Query<Query>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(Serialize)]
+	struct Item {
+		#[serde(flatten)]
+		record: Record,
+		timestamp: String,
+	}
+	let mut records = info.filter.search(pool.as_ref(), info.search.page.offset() as i64, Some(info.search.fetch_limit())).await?;
+	let pager = info.search.pager(&mut records);
+	let records: Vec<_> = records
+		.into_iter()
+		.map(|record| Item {
+			timestamp: datetime(record.created),
+			record,
+		})
+		.collect();
+	render("html/admin/audit.html", liquid::object!({ "logs": &records, "filter": &info.filter, "pager": &pager }))
+}
+
+// JSON Lines形式で書き出す　絞り込みは一覧と同じ、ページ送りはせず全件
+async fn export(web::Query(filter): web::Query<Filter>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let records = filter.search(pool.as_ref(), 0, None).await?;
+	let mut body = String::new();
+	for record in &records {
+		body.push_str(&serde_json::to_string(record)?);
+		body.push('\n');
+	}
+	Ok(HttpResponse::Ok()
+		.content_type("application/x-ndjson")
+		.insert_header((header::CONTENT_DISPOSITION, r#"attachment; filename="audit.jsonl""#))
+		.body(body))
+}
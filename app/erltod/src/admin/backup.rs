@@ -0,0 +1,44 @@
+use actix_web::{Responder, error::*, web};
+use common::{Admin, Auditor, Backup};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use super::{datetime, redirect, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).post(create));
+}
+
+// スナップショット一覧
+async fn list(backup: web::Data<Backup>) -> PageResult<impl Responder> {
+	#[derive(Serialize)]
+	struct Record {
+		file: String,
+		size: u64,
+		sha256: String,
+		schema: String,
+		created: String,
+	}
+	let records: Vec<Record> = backup
+		.list()
+		.await
+		.map_err(ErrorInternalServerError)?
+		.into_iter()
+		.map(|x| Record {
+			file: x.file,
+			size: x.size,
+			sha256: x.sha256,
+			schema: x.schema,
+			created: datetime(x.created),
+		})
+		.collect();
+	render("html/admin/backup.html", liquid::object!({ "app": env!("CARGO_PKG_NAME"), "snapshots": &records }))
+}
+
+// 今すぐスナップショットを取る
+async fn create(admin: Admin, auditor: Auditor, backup: web::Data<Backup>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let snapshot = backup.snapshot(pool.as_ref()).await.map_err(ErrorInternalServerError)?;
+	auditor.admin(&admin, "backup.create", &snapshot.file).change(None, Some(&snapshot.sha256)).record(pool.as_ref()).await?;
+	Ok(redirect("/admin/backup"))
+}
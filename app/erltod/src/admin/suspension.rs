@@ -0,0 +1,52 @@
+use actix_web::{HttpResponse, Responder, error::*, mime, web};
+use common::{Admin, Auditor, Suspension, identity::user_key};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use validation::Validation;
+
+use crate::utils::MessageResult;
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).put(issue).delete(lift));
+}
+
+// 利用停止中のキャラクター一覧
+async fn list(pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let result = Suspension::list(pool.as_ref()).await?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&result)?))
+}
+
+// 利用停止　既に停止中なら上書き
+#[derive(Deserialize, Validation)]
+struct Issue {
+	eno: i64,
+	#[validation(name = "理由", min = 1, max = 1000)]
+	reason: String,
+	/// 停止日数　無ければ無期限
+	days: Option<i64>,
+}
+async fn issue(web::Json(info): web::Json<Issue>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let expiry = Suspension::expiry(info.days)?;
+	let pool = pool.as_ref();
+	sqlx::query_scalar!("SELECT eno FROM actor WHERE eno=?", info.eno)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorNotFound("キャラクターが存在しません"))?;
+	Suspension::issue(pool, &user_key(&info.eno)?, &info.reason, &admin.name, expiry).await?;
+	auditor.admin(&admin, "actor.suspend", &info.eno.to_string()).change(None, Some(&info.reason)).record(pool).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 利用停止の解除
+#[derive(Deserialize)]
+struct Lift {
+	eno: i64,
+}
+async fn lift(web::Query(info): web::Query<Lift>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	if !Suspension::lift(pool.as_ref(), &user_key(&info.eno)?).await? {
+		return Err(ErrorNotFound("利用停止されていません").into());
+	}
+	auditor.admin(&admin, "actor.lift", &info.eno.to_string()).record(pool.as_ref()).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
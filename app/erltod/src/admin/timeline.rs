@@ -0,0 +1,91 @@
+use actix_web::{Responder, web};
+use common::{Admin, Auditor};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use super::{Search, datetime, redirect, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("{id}/hide").post(hide));
+	cfg.service(web::resource("{id}/show").post(show));
+	cfg.service(web::resource("{id}/delete").post(delete));
+}
+
+// 発言一覧（非表示のものも含む）
+async fn list(web::Query(info): web::Query<Search>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(FromRow)]
+	struct Row {
+		id: i64,
+		timestamp: i64,
+		place: String,
+		actor: Option<i64>,
+		name: String,
+		body: String,
+		visible: bool,
+	}
+	#[derive(Serialize)]
+	struct Record {
+		id: i64,
+		timestamp: String,
+		place: String,
+		actor: Option<i64>,
+		name: String,
+		body: String,
+		visible: bool,
+	}
+	let search = format!("%{}%", info.q);
+	let mut rows: Vec<Row> =
+		sqlx::query_as("SELECT t.id,t.timestamp,p.name AS place,t.actor,t.name,t.body,t.visible FROM timeline t JOIN place p ON p.id=t.place WHERE t.body LIKE ?1 OR p.name LIKE ?1 OR t.name LIKE ?1 ORDER BY t.id DESC LIMIT ?2,?3")
+			.bind(search)
+			.bind(info.page.offset() as i64)
+			.bind(info.fetch_limit())
+			.fetch_all(pool.as_ref())
+			.await?;
+	let pager = info.pager(&mut rows);
+	let records: Vec<Record> = rows
+		.into_iter()
+		.map(|x| Record {
+			id: x.id,
+			timestamp: datetime(x.timestamp),
+			place: x.place,
+			actor: x.actor,
+			name: x.name,
+			body: x.body,
+			visible: x.visible,
+		})
+		.collect();
+	render("html/admin/timeline.html", liquid::object!({ "timeline": &records, "pager": &pager }))
+}
+
+#[derive(Deserialize)]
+struct Id {
+	id: i64,
+}
+
+// 非表示
+async fn hide(path: web::Path<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	sqlx::query!("UPDATE timeline SET visible=FALSE WHERE id=?", id).execute(pool.as_ref()).await?;
+	auditor.admin(&admin, "timeline.hide", &id.to_string()).record(pool.as_ref()).await?;
+	Ok(redirect("/admin/timeline"))
+}
+
+// 再表示
+async fn show(path: web::Path<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	sqlx::query!("UPDATE timeline SET visible=TRUE WHERE id=?", id).execute(pool.as_ref()).await?;
+	auditor.admin(&admin, "timeline.show", &id.to_string()).record(pool.as_ref()).await?;
+	Ok(redirect("/admin/timeline"))
+}
+
+// 削除
+async fn delete(path: web::Path<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let body = sqlx::query_scalar!("DELETE FROM timeline WHERE id=? RETURNING body", id).fetch_optional(pool.as_ref()).await?;
+	if let Some(body) = body {
+		auditor.admin(&admin, "timeline.delete", &id.to_string()).change(Some(&body), None).record(pool.as_ref()).await?;
+	}
+	Ok(redirect("/admin/timeline"))
+}
@@ -0,0 +1,15 @@
+use actix_web::{HttpResponse, Responder, http::Method, mime, web};
+use common::StateRule;
+
+use crate::utils::{MessageResult, state::ALL, template::Notice};
+
+pub const POLICY: &[StateRule] = &[StateRule::new("", &[Method::GET], ALL)];
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+}
+
+// 表示中のお知らせ（ポータルから取得したもの）
+async fn list() -> MessageResult<impl Responder> {
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&Notice::active())?))
+}
@@ -1,3 +1,105 @@
-use actix_web::web;
+use actix_session::Session;
+use actix_web::{
+	HttpResponse, Responder,
+	error::*,
+	http::{Method, header},
+	mime, web,
+};
+use common::{Impersonation, Portal, StateRule, portal::Pending};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use validation::Validation;
 
-pub fn cfg(cfg: &mut web::ServiceConfig) {}
+use crate::utils::{
+	Eno, MessageResult, PageResult, Template,
+	state::{ACTIVE, ALL},
+};
+
+/// 認可リクエスト中の値を保持するセッションキー
+const PENDING: &str = "portal-auth";
+
+pub const POLICY: &[StateRule] = &[
+	// メンテナンス中も許可されたユーザーがログインできるようにする
+	StateRule::new("", &[], ALL),
+	StateRule::new("authorize", &[Method::GET], ALL),
+	StateRule::new("register", &[Method::POST], ACTIVE),
+];
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(index).post(login).delete(logout));
+	cfg.service(web::resource("authorize").get(authorize));
+	cfg.service(web::resource("register").post(register));
+}
+
+// エントランス画面
+async fn index(portal: web::Data<Portal>) -> PageResult<impl Responder> {
+	let html = Template::Base {
+		nobots: false,
+		summary: None,
+		user: None,
+	}
+	.render("html/entry.html", liquid::object!({ "portal": portal.origin() }))?;
+	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
+}
+
+// ポータルの認可画面へ（ポップアップで開かれる）
+async fn authorize(session: Session, portal: web::Data<Portal>) -> MessageResult<impl Responder> {
+	// ログインの手続きはセッションに書き込むので、なりすまし中は始めさせない
+	Impersonation::forbid()?;
+	let (url, pending) = portal.authorize()?;
+	session.insert(PENDING, pending)?;
+	Ok(HttpResponse::Found().insert_header((header::LOCATION, url.to_string())).finish())
+}
+
+/// ポータルから受け取ったコードを交換し、ポータルのユーザー名を得る
+async fn exchange(session: &Session, portal: &Portal, code: &str, state: &str) -> MessageResult<String> {
+	// 認可リクエストは一度きり
+	let pending = session.remove_as::<Pending>(PENDING).and_then(Result::ok).ok_or(ErrorBadRequest("認可リクエストが見つかりません"))?;
+	Ok(portal.exchange(code, state, &pending).await.map_err(actix_web::Error::from)?)
+}
+
+// ログイン
+#[derive(Deserialize)]
+struct Login {
+	code: String,
+	state: String,
+}
+async fn login(web::Form(info): web::Form<Login>, session: Session, portal: web::Data<Portal>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let user = exchange(&session, &portal, &info.code, &info.state).await?;
+	let eno = sqlx::query_scalar!("SELECT eno FROM actor WHERE user=?", user)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorNotFound("キャラクターが登録されていません"))?;
+	Eno::save(&session, &eno)?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// ログアウト
+async fn logout(session: Session) -> MessageResult<impl Responder> {
+	Eno::delete(&session);
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 新規登録
+#[derive(Deserialize, Validation)]
+struct Register {
+	code: String,
+	state: String,
+	#[validation(name = "キャラクター名", max = 30, min = 1)]
+	name: String,
+}
+async fn register(web::Form(info): web::Form<Register>, session: Session, portal: web::Data<Portal>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let user = exchange(&session, &portal, &info.code, &info.state).await?;
+	// 1ユーザーにつき1キャラクター　確認と登録を1文で行う
+	let eno = sqlx::query_scalar!(
+		"INSERT INTO actor(user,name,portraits,icons) SELECT ?1,?2,'','' WHERE NOT EXISTS(SELECT 1 FROM actor WHERE user=?1) RETURNING eno",
+		user,
+		info.name
+	)
+	.fetch_optional(pool.as_ref())
+	.await?
+	.ok_or(ErrorConflict("既にキャラクターが登録されています"))?;
+	Eno::save(&session, &eno)?;
+	Ok(HttpResponse::NoContent().finish())
+}
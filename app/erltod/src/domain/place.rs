@@ -0,0 +1,328 @@
+use actix_web::{HttpResponse, Responder, error::*, http::Method, web};
+use chrono::Local;
+use common::{Authorized, StateRule, api_token};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqliteExecutor, SqlitePool, prelude::FromRow};
+use validation::Validation;
+
+use crate::utils::{
+	MessageResult,
+	state::{ACTIVE, OPEN},
+	unread,
+};
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET], OPEN),
+	StateRule::new("", &[Method::POST], ACTIVE),
+	StateRule::new("{id}", &[Method::GET], OPEN),
+	StateRule::new("{id}", &[Method::PATCH], ACTIVE),
+	StateRule::new("{id}/invite", &[Method::POST], ACTIVE),
+	StateRule::new("{id}/leave", &[Method::POST], ACTIVE),
+	StateRule::new("{id}/read", &[Method::POST], OPEN),
+];
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).post(create));
+	cfg.service(web::resource("{id}").get(detail).patch(rename));
+	cfg.service(web::resource("{id}/invite").post(invite));
+	cfg.service(web::resource("{id}/leave").post(leave));
+	cfg.service(web::resource("{id}/read").post(read));
+}
+
+/// 誰でも読み書きできる
+pub const PUBLIC: &str = "public";
+/// 参加者のみ　作成者が招待する
+pub const GROUP: &str = "group";
+/// 2人の参加者のみ
+pub const DM: &str = "dm";
+/// 名前を指定しなかったDMの名前の接頭辞　他の場所には使わせない（DMの名前を先に取られないように）
+const DM_PREFIX: &str = "DM:";
+
+/// DMの自動の名前に使う接頭辞で始まるか（大文字小文字は区別しない）
+pub fn is_reserved(name: &str) -> bool {
+	name.trim_start().get(..DM_PREFIX.len()).is_some_and(|x| x.eq_ignore_ascii_case(DM_PREFIX))
+}
+
+/// 利用者が指定する名前の確認
+fn check_name(name: &str) -> Result<(), actix_web::Error> {
+	if is_reserved(name) {
+		return Err(ErrorBadRequest(format!("「{DM_PREFIX}」で始まる名前は使えません")));
+	}
+	Ok(())
+}
+
+/// 発言場所
+#[derive(FromRow, Serialize)]
+pub struct Place {
+	pub id: i64,
+	pub name: String,
+	pub visibility: String,
+	pub owner: Option<i64>,
+	pub created: i64,
+}
+
+impl Place {
+	pub async fn get(executor: impl SqliteExecutor<'_>, id: i64) -> Result<Option<Self>, sqlx::Error> {
+		sqlx::query_as("SELECT id,name,visibility,owner,created FROM place WHERE id=?").bind(id).fetch_optional(executor).await
+	}
+	/// `eno`が読める場所を名前で探す　未ログインならpublicのみ
+	///
+	/// group・dmの名前は参加していない場所とは重複しうる　参加している場所同士で重複したら古い方
+	pub async fn find(executor: impl SqliteExecutor<'_>, name: &str, eno: Option<i64>) -> Result<Option<Self>, sqlx::Error> {
+		let mut builder = QueryBuilder::<Sqlite>::new("SELECT p.id,p.name,p.visibility,p.owner,p.created FROM place p WHERE p.name=");
+		builder.push_bind(name).push(" AND ");
+		push_readable(&mut builder, eno);
+		builder.push(" ORDER BY p.id LIMIT 1");
+		builder.build_query_as().fetch_optional(executor).await
+	}
+	pub fn is_public(&self) -> bool {
+		self.visibility == PUBLIC
+	}
+	/// `eno`が読み書きできるか　publicなら誰でも
+	pub async fn is_member(&self, executor: impl SqliteExecutor<'_>, eno: i64) -> Result<bool, sqlx::Error> {
+		if self.is_public() {
+			return Ok(true);
+		}
+		is_member(executor, self.id, eno).await
+	}
+}
+
+/// 参加者か（公開範囲は見ない）
+pub async fn is_member(executor: impl SqliteExecutor<'_>, place: i64, eno: i64) -> Result<bool, sqlx::Error> {
+	Ok(sqlx::query_scalar!("SELECT actor FROM place_member WHERE place=? AND actor=?", place, eno).fetch_optional(executor).await?.is_some())
+}
+
+/// `eno`が読める場所の条件（placeの別名は`p`）　未ログインならpublicのみ
+pub fn push_readable(builder: &mut QueryBuilder<'_, Sqlite>, eno: Option<i64>) {
+	builder.push("(p.visibility='public'");
+	if let Some(eno) = eno {
+		builder.push(" OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=").push_bind(eno).push(")");
+	}
+	builder.push(")");
+}
+
+/// 名前が使われているか（読めない場所も含む）
+async fn exists(executor: impl SqliteExecutor<'_>, name: &str) -> Result<bool, sqlx::Error> {
+	Ok(sqlx::query_scalar!("SELECT id FROM place WHERE name=?", name).fetch_optional(executor).await?.is_some())
+}
+
+/// 読める場所でなければ404（参加していない場所は存在も明かさない）
+async fn readable(executor: impl SqliteExecutor<'_> + Copy, id: i64, eno: i64) -> Result<Place, actix_web::Error> {
+	let place = Place::get(executor, id).await.map_err(ErrorInternalServerError)?;
+	match place {
+		Some(place) if place.is_member(executor, eno).await.map_err(ErrorInternalServerError)? => Ok(place),
+		_ => Err(ErrorNotFound("場所が存在しません")),
+	}
+}
+
+// 場所の一覧　publicと自分が参加している場所
+async fn list(eno: Option<Authorized<i64>>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = eno.as_ref().map(|x| x.require(api_token::READ).copied()).transpose()?;
+	let mut builder = QueryBuilder::<Sqlite>::new("SELECT p.id,p.name,p.visibility,p.owner,p.created FROM place p WHERE ");
+	push_readable(&mut builder, eno);
+	builder.push(" ORDER BY p.id");
+	let places: Vec<Place> = builder.build_query_as().fetch_all(pool.as_ref()).await?;
+	Ok(web::Json(places))
+}
+
+/// 場所と参加者（publicなら空）
+#[derive(Serialize)]
+struct Detail {
+	#[serde(flatten)]
+	place: Place,
+	members: Vec<i64>,
+}
+impl Detail {
+	async fn new(executor: impl SqliteExecutor<'_>, place: Place) -> Result<Self, sqlx::Error> {
+		let members = sqlx::query_scalar!("SELECT actor FROM place_member WHERE place=? ORDER BY created,actor", place.id).fetch_all(executor).await?;
+		Ok(Self { place, members })
+	}
+}
+
+// 作成　DMは相手を1人だけ指定し、同じ相手とのDMが既にあればそれを返す
+#[derive(Deserialize, Validation)]
+struct Create {
+	/// 空欄ならDMは"DM:{Eno}-{Eno}"
+	#[validation(name = "名前", max = 30)]
+	#[serde(default)]
+	name: String,
+	visibility: String,
+	/// 自分以外の参加者
+	#[serde(default)]
+	members: Vec<i64>,
+}
+async fn create(web::Json(info): web::Json<Create>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	info.validate().map_err(ErrorBadRequest)?;
+	check_name(&info.name)?;
+	let mut members = info.members;
+	members.retain(|x| *x != eno);
+	members.sort_unstable();
+	members.dedup();
+	let now = Local::now().timestamp();
+	let mut tx = pool.begin().await?;
+	for member in &members {
+		if sqlx::query_scalar!("SELECT eno FROM actor WHERE eno=?", member).fetch_optional(&mut *tx).await?.is_none() {
+			return Err(ErrorBadRequest(format!("Eno.{member}のキャラクターが存在しません")).into());
+		}
+	}
+	let name = match info.visibility.as_str() {
+		GROUP if info.name.is_empty() => return Err(ErrorBadRequest("名前を入力してください").into()),
+		GROUP => info.name,
+		DM => {
+			let [other] = members[..] else {
+				return Err(ErrorBadRequest("DMの相手を1人指定してください").into());
+			};
+			let existing: Option<i64> = sqlx::query_scalar(
+				"SELECT p.id FROM place p JOIN place_member a ON a.place=p.id AND a.actor=? JOIN place_member b ON b.place=p.id AND b.actor=? WHERE p.visibility='dm'",
+			)
+			.bind(eno)
+			.bind(other)
+			.fetch_optional(&mut *tx)
+			.await?;
+			if let Some(id) = existing {
+				let place = Place::get(&mut *tx, id).await?.ok_or(ErrorInternalServerError("場所が存在しません"))?;
+				return Ok(HttpResponse::Ok().json(Detail::new(&mut *tx, place).await?));
+			}
+			if info.name.is_empty() {
+				// 抜けた後に作り直した場合は番号を付ける
+				let base = format!("DM:{}-{}", eno.min(other), eno.max(other));
+				let mut name = base.clone();
+				let mut n = 1;
+				while exists(&mut *tx, &name).await? {
+					n += 1;
+					name = format!("{base}({n})");
+				}
+				name
+			} else {
+				info.name
+			}
+		}
+		_ => return Err(ErrorBadRequest("公開範囲はgroupかdmを指定してください").into()),
+	};
+	// 重複を確かめるのは自分が読める場所とだけ（参加していない場所の名前を明かさない）
+	let id = sqlx::query_scalar!(
+		"INSERT INTO place(name,visibility,owner,created) SELECT ?1,?2,?3,?4 WHERE NOT EXISTS(SELECT 1 FROM place p WHERE p.name=?1 AND (p.visibility='public' OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=?3))) RETURNING id",
+		name,
+		info.visibility,
+		eno,
+		now
+	)
+	.fetch_optional(&mut *tx)
+	.await?
+	.ok_or(ErrorConflict("同じ名前の場所があります"))?;
+	for member in std::iter::once(&eno).chain(&members) {
+		sqlx::query!("INSERT INTO place_member(place,actor,created) VALUES(?,?,?)", id, member, now).execute(&mut *tx).await?;
+	}
+	let place = Place::get(&mut *tx, id).await?.ok_or(ErrorInternalServerError("場所が存在しません"))?;
+	let detail = Detail::new(&mut *tx, place).await?;
+	tx.commit().await?;
+	Ok(HttpResponse::Created().json(detail))
+}
+
+// 詳細
+#[derive(Deserialize)]
+struct Id {
+	id: i64,
+}
+async fn detail(path: web::Path<Id>, eno: Option<Authorized<i64>>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = eno.as_ref().map(|x| x.require(api_token::READ).copied()).transpose()?;
+	let place = match eno {
+		Some(eno) => readable(pool.as_ref(), path.id, eno).await?,
+		None => Place::get(pool.as_ref(), path.id).await?.filter(Place::is_public).ok_or(ErrorNotFound("場所が存在しません"))?,
+	};
+	Ok(web::Json(Detail::new(pool.as_ref(), place).await?))
+}
+
+// 名前の変更　groupは作成者、DMは参加者ならできる（idは変わらないので発言はそのまま）
+#[derive(Deserialize, Validation)]
+struct Rename {
+	#[validation(name = "名前", min = 1, max = 30)]
+	name: String,
+}
+async fn rename(path: web::Path<Id>, web::Json(info): web::Json<Rename>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	info.validate().map_err(ErrorBadRequest)?;
+	check_name(&info.name)?;
+	let place = readable(pool.as_ref(), path.id, eno).await?;
+	match place.visibility.as_str() {
+		PUBLIC => return Err(ErrorForbidden("公開の場所の名前は変更できません").into()),
+		GROUP if place.owner != Some(eno) => return Err(ErrorForbidden("名前を変更できるのは作成者だけです").into()),
+		_ => {}
+	}
+	// 作成と同じく、重複を確かめるのは自分が読める場所とだけ
+	sqlx::query_scalar!(
+		"UPDATE place SET name=?1 WHERE id=?2 AND NOT EXISTS(SELECT 1 FROM place p WHERE p.name=?1 AND p.id<>?2 AND (p.visibility='public' OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=?3))) RETURNING id",
+		info.name,
+		place.id,
+		eno
+	)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorConflict("同じ名前の場所があります"))?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 招待　groupの作成者のみ
+#[derive(Deserialize)]
+struct Invite {
+	eno: i64,
+}
+async fn invite(path: web::Path<Id>, web::Json(info): web::Json<Invite>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	let place = readable(pool.as_ref(), path.id, eno).await?;
+	if place.visibility != GROUP {
+		return Err(ErrorBadRequest("招待できるのはグループだけです").into());
+	}
+	if place.owner != Some(eno) {
+		return Err(ErrorForbidden("招待できるのは作成者だけです").into());
+	}
+	if sqlx::query_scalar!("SELECT eno FROM actor WHERE eno=?", info.eno).fetch_optional(pool.as_ref()).await?.is_none() {
+		return Err(ErrorBadRequest(format!("Eno.{}のキャラクターが存在しません", info.eno)).into());
+	}
+	let now = Local::now().timestamp();
+	sqlx::query!("INSERT INTO place_member(place,actor,created) VALUES(?,?,?) ON CONFLICT DO NOTHING", place.id, info.eno, now)
+		.execute(pool.as_ref())
+		.await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 退出　作成者が抜けたら最も古い参加者に引き継ぐ
+async fn leave(path: web::Path<Id>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	let mut tx = pool.begin().await?;
+	let place = Place::get(&mut *tx, path.id).await?.ok_or(ErrorNotFound("場所が存在しません"))?;
+	if place.is_public() {
+		return Err(ErrorBadRequest("公開の場所からは抜けられません").into());
+	}
+	if sqlx::query!("DELETE FROM place_member WHERE place=? AND actor=?", place.id, eno).execute(&mut *tx).await?.rows_affected() == 0 {
+		return Err(ErrorNotFound("場所が存在しません").into());
+	}
+	sqlx::query!("DELETE FROM place_read WHERE place=? AND actor=?", place.id, eno).execute(&mut *tx).await?;
+	if place.owner == Some(eno) {
+		sqlx::query!(
+			"UPDATE place SET owner=(SELECT actor FROM place_member WHERE place=?1 ORDER BY created,actor LIMIT 1) WHERE id=?1",
+			place.id
+		)
+		.execute(&mut *tx)
+		.await?;
+	}
+	tx.commit().await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 既読にする　`last_id`まで（省略したら全て）　publicの場所はこれ以降未読数を数える
+#[derive(Deserialize)]
+struct Read {
+	last_id: Option<i64>,
+}
+async fn read(path: web::Path<Id>, web::Json(info): web::Json<Read>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	let place = readable(pool.as_ref(), path.id, eno).await?;
+	let last = match info.last_id {
+		Some(x) => x,
+		None => sqlx::query_scalar!("SELECT MAX(id) FROM timeline WHERE place=?", place.id).fetch_one(pool.as_ref()).await?.unwrap_or(0),
+	};
+	unread::mark_place(pool.as_ref(), eno, place.id, last).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
@@ -1,21 +1,35 @@
-use actix_web::{HttpResponse, Responder, error::*, mime, web};
+use actix_session::Session;
+use actix_web::{HttpResponse, Responder, error::*, http::Method, mime, web};
+use common::{ApiToken, Auditor, Authorized, SqliteSessionStore, StateRule, api_token, identity::user_key};
 use serde::Deserialize;
 use sqlx::SqlitePool;
 use validation::Validation;
 
-use crate::utils::{Eno, MessageResult, PageResult, State, StateHandle, Template};
+use crate::utils::{
+	Eno, MessageResult, PageResult, Template,
+	state::{ACTIVE, OPEN},
+	template::User,
+};
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET, Method::DELETE], OPEN),
+	StateRule::new("", &[Method::PATCH], ACTIVE),
+	StateRule::new("token", &[Method::GET, Method::POST, Method::DELETE], OPEN),
+	StateRule::new("battle", &[Method::GET, Method::POST, Method::DELETE], OPEN),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(index).patch(patch).delete(delete));
+	cfg.service(api_token::resource::<i64>("token"));
 	cfg.service(web::resource("battle").get(battle::index).post(battle::post).delete(battle::delete));
 }
 
 // 編集・設定画面
-async fn index() -> PageResult<impl Responder> {
+async fn index(eno: Option<Eno>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
 	let html = Template::Base {
 		nobots: true,
 		summary: None,
-		user: None,
+		user: User::load(pool.as_ref(), eno.map(|x| *x)).await?,
 	}
 	.render("html/profile/index.html", liquid::object!({}))?;
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
@@ -35,7 +49,7 @@ struct Patch {
 	#[validation(name = "アイコン画像", max = 2000)]
 	icons: Option<String>,
 }
-async fn patch(web::Json(info): web::Json<Patch>, eno: Eno, state: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+async fn patch(web::Json(info): web::Json<Patch>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
 	fn format_urls(v: String) -> String {
 		let mut out = String::new();
 		for line in v.lines() {
@@ -49,9 +63,7 @@ async fn patch(web::Json(info): web::Json<Patch>, eno: Eno, state: StateHandle,
 		}
 		out
 	}
-	if *state != State::Active {
-		return Err(ErrorForbidden("当サイトはクローズしています").into());
-	}
+	let eno = eno.require(api_token::WRITE)?;
 	// SQL構築
 	let mut builder = sqlx::QueryBuilder::new("UPDATE actor SET ");
 	let mut sep = builder.separated(',');
@@ -78,7 +90,7 @@ async fn patch(web::Json(info): web::Json<Patch>, eno: Eno, state: StateHandle,
 	if let Some(v) = info.icons {
 		sep.push("icons=").push_bind_unseparated(format_urls(v));
 	}
-	builder.push(" WHERE name=").push_bind(&*eno);
+	builder.push(" WHERE eno=").push_bind(*eno);
 	builder.build().execute(pool).await?;
 	Ok(HttpResponse::NoContent().finish())
 }
@@ -89,7 +101,7 @@ struct Delete {
 	eno: i64,
 	user: String,
 }
-async fn delete(web::Form(info): web::Form<Delete>, eno: Eno, _: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+async fn delete(web::Form(info): web::Form<Delete>, eno: Eno, session: Session, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
 	if info.eno != *eno {
 		return Err(ErrorForbidden("Enoが正しくありません").into());
 	}
@@ -99,6 +111,11 @@ async fn delete(web::Form(info): web::Form<Delete>, eno: Eno, _: StateHandle, po
 		return Err(ErrorForbidden("ユーザー名が正しくありません").into());
 	}
 	sqlx::query!("DELETE FROM actor WHERE eno=?", *eno).execute(pool).await?;
+	let user = user_key(&*eno)?;
+	auditor.user(&user, "actor.delete", &user).record(pool).await?;
+	SqliteSessionStore::revoke_all(pool, &user, None).await?;
+	ApiToken::revoke_all(pool, &user).await?;
+	Eno::delete(&session);
 	Ok(HttpResponse::NoContent().finish())
 }
 
@@ -0,0 +1,667 @@
+use actix_web::{HttpResponse, Responder, error::*, http::Method, web};
+use chrono::Local;
+use common::{Authorized, StateRule, api_token};
+use html_codec::HTMLEncode as _;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+use validation::Validation;
+
+use super::place::{self, Place};
+use crate::utils::{
+	CommonTag, CursorParams, Hub, MessageResult, Unread,
+	hub::{Event, Post},
+	state::{ACTIVE, OPEN},
+	unread,
+};
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET], OPEN),
+	StateRule::new("", &[Method::POST], ACTIVE),
+	StateRule::new("mentions", &[Method::GET], OPEN),
+	StateRule::new("mentions/read", &[Method::POST], OPEN),
+	StateRule::new("unread", &[Method::GET], OPEN),
+	StateRule::new("stream", &[Method::GET], OPEN),
+];
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).post(post));
+	cfg.service(web::resource("mentions").get(mentions));
+	cfg.service(web::resource("mentions/read").post(read_mentions));
+	cfg.service(web::resource("unread").get(unread));
+	cfg.service(web::resource("stream").get(stream::connect));
+}
+
+/// 一覧の1ページ　`posts`は常に新しい順
+#[derive(Serialize)]
+struct Page {
+	posts: Vec<Post>,
+	/// このページで最も古いID（より古いものは`before_id`に指定する）
+	before_id: Option<i64>,
+	/// このページで最も新しいID（より新しいものは`after_id`に指定する）
+	after_id: Option<i64>,
+	/// 取得した方向にまだ続きがあるか
+	more: bool,
+}
+
+/// 絞り込み　空欄の項目は条件にしない
+enum Filter {
+	/// 場所の現在の名前
+	Place(String),
+	Actor(i64),
+	/// 宛先（timeline_actor）
+	Mention(i64),
+}
+
+/// `eno`が読める場所（publicと参加している場所）の発言のみ
+async fn fetch(pool: &SqlitePool, eno: Option<i64>, filters: &[Filter], cursor: &CursorParams) -> Result<Page, sqlx::Error> {
+	let limit = cursor.limit();
+	let ascending = cursor.ascending();
+	let mut builder = QueryBuilder::<Sqlite>::new(
+		"SELECT t.id,t.timestamp,p.name AS place,t.place AS place_id,t.actor,t.name,t.body,a.icon FROM timeline t JOIN place p ON p.id=t.place LEFT JOIN actor a ON a.eno=t.actor",
+	);
+	for filter in filters {
+		if let Filter::Mention(eno) = filter {
+			builder.push(" JOIN timeline_actor m ON m.timeline=t.id AND m.actor=").push_bind(*eno);
+		}
+	}
+	builder.push(" WHERE t.visible AND ");
+	place::push_readable(&mut builder, eno);
+	for filter in filters {
+		match filter {
+			Filter::Place(place) => builder.push(" AND p.name=").push_bind(place.clone()),
+			Filter::Actor(eno) => builder.push(" AND t.actor=").push_bind(*eno),
+			Filter::Mention(_) => &mut builder,
+		};
+	}
+	if let Some(id) = cursor.before_id {
+		builder.push(" AND t.id<").push_bind(id);
+	}
+	if let Some(id) = cursor.after_id {
+		builder.push(" AND t.id>").push_bind(id);
+	}
+	// 続きがあるか判定するため、1件多く取得する
+	builder.push(if ascending { " ORDER BY t.id ASC" } else { " ORDER BY t.id DESC" }).push(" LIMIT ").push_bind(limit as i64 + 1);
+	let mut posts: Vec<Post> = builder.build_query_as().fetch_all(pool).await?;
+	let more = posts.len() > limit;
+	posts.truncate(limit);
+	if ascending {
+		posts.reverse();
+	}
+	Ok(Page {
+		before_id: posts.last().map(|x| x.id),
+		after_id: posts.first().map(|x| x.id),
+		posts,
+		more,
+	})
+}
+
+// 発言一覧　場所・発言者で絞り込む　カーソルは`CursorParams`
+// ログインしていれば参加している場所の発言も含む
+#[derive(Deserialize)]
+struct List {
+	#[serde(default)]
+	place: String,
+	actor: Option<i64>,
+}
+async fn list(
+	web::Query(info): web::Query<List>,
+	web::Query(cursor): web::Query<CursorParams>,
+	eno: Option<Authorized<i64>>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
+	let eno = eno.as_ref().map(|x| x.require(api_token::READ).copied()).transpose()?;
+	let mut filters = Vec::new();
+	if !info.place.is_empty() {
+		filters.push(Filter::Place(info.place));
+	}
+	if let Some(eno) = info.actor {
+		filters.push(Filter::Actor(eno));
+	}
+	Ok(web::Json(fetch(pool.as_ref(), eno, &filters, &cursor).await?))
+}
+
+// 自分宛の発言一覧（アンカー・メンション）　場所で絞り込める
+async fn mentions(web::Query(info): web::Query<List>, web::Query(cursor): web::Query<CursorParams>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::READ)?;
+	let mut filters = vec![Filter::Mention(eno)];
+	if !info.place.is_empty() {
+		filters.push(Filter::Place(info.place));
+	}
+	Ok(web::Json(fetch(pool.as_ref(), Some(eno), &filters, &cursor).await?))
+}
+
+// 自分宛を既読にする　`last_id`まで（省略したら全て）
+#[derive(Deserialize)]
+struct Read {
+	last_id: Option<i64>,
+}
+async fn read_mentions(web::Json(info): web::Json<Read>, eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::WRITE)?;
+	let last = match info.last_id {
+		Some(x) => x,
+		None => sqlx::query_scalar!("SELECT MAX(id) FROM timeline").fetch_one(pool.as_ref()).await?.unwrap_or(0),
+	};
+	unread::mark_mentions(pool.as_ref(), eno, last).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 未読数　場所ごとと自分宛
+async fn unread(eno: Authorized<i64>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let eno = *eno.require(api_token::READ)?;
+	Ok(web::Json(Unread::load(pool.as_ref(), eno).await?))
+}
+
+// 発言　無い場所ならpublicとして作る（group・dmは参加者のみ、「DM:」で始まる名前は作らない）
+// 発言した場所はそこまで既読にする
+#[derive(Deserialize, Validation)]
+struct Form {
+	#[validation(name = "場所", min = 1, max = 30)]
+	place: String,
+	#[validation(name = "表示名", min = 1, max = 30)]
+	name: String,
+	#[validation(name = "本文", min = 1, max = 2000)]
+	body: String,
+}
+async fn post(web::Json(info): web::Json<Form>, eno: Authorized<i64>, hub: web::Data<Hub>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	#[derive(Serialize)]
+	struct Posted {
+		id: i64,
+		/// タグ処理済みの本文
+		body: String,
+		/// 存在しない・非表示の発言へのアンカー（発言自体は行う）
+		invalid_anchors: Vec<i64>,
+	}
+	let eno = *eno.require(api_token::WRITE)?;
+	info.validate().map_err(ErrorBadRequest)?;
+	let (anchors, mentions) = references(&info.body);
+	// DBに保持するのはタグ処理済みのもの（表示時にもう一度処理しない）
+	let body = info.body.escape(false).tag(CommonTag).br().into_owned();
+	let now = Local::now().timestamp();
+	let mut tx = pool.begin().await?;
+	// DMの名前はpublicとして作らない　publicの名前は読めない場所も含めて重複させない（参加者から見て同じ名前の場所が2つにならないように）
+	if !place::is_reserved(&info.place) {
+		sqlx::query!("INSERT INTO place(name,visibility,created) SELECT ?1,'public',?2 WHERE NOT EXISTS(SELECT 1 FROM place WHERE name=?1)", info.place, now)
+			.execute(&mut *tx)
+			.await?;
+	}
+	// 参加していない場所は存在しない場所と同じ扱い
+	let place = Place::find(&mut *tx, &info.place, Some(eno)).await?.ok_or(ErrorNotFound("場所が存在しません"))?;
+	let id: i64 = sqlx::query_scalar("INSERT INTO timeline(timestamp,place,actor,name,body) VALUES(?,?,?,?,?) RETURNING id")
+		.bind(now)
+		.bind(place.id)
+		.bind(eno)
+		.bind(&info.name)
+		.bind(&body)
+		.fetch_one(&mut *tx)
+		.await?;
+	let (actors, invalid_anchors) = recipients(&mut tx, &place, eno, anchors, mentions).await?;
+	for actor in &actors {
+		sqlx::query!("INSERT INTO timeline_actor(timeline,actor) VALUES(?,?)", id, actor).execute(&mut *tx).await?;
+	}
+	unread::mark_place(&mut *tx, eno, place.id, id).await?;
+	let icon = sqlx::query_scalar!("SELECT icon FROM actor WHERE eno=?", eno).fetch_one(&mut *tx).await?;
+	tx.commit().await?;
+	// 接続中のクライアントに配信
+	let post = Post {
+		id,
+		timestamp: now,
+		place: place.name.clone(),
+		place_id: place.id,
+		actor: Some(eno),
+		name: info.name,
+		body: body.clone(),
+		icon,
+	};
+	hub.publish(Event {
+		post,
+		public: place.is_public(),
+		actors,
+	});
+	Ok(HttpResponse::Created().json(Posted { id, body, invalid_anchors }))
+}
+
+/// 宛先　アンカー先の発言者とメンションされたキャラクター（重複を除いて昇順）と、無効なアンカー
+///
+/// 存在しない・非表示・`eno`が読めない場所の発言へのアンカーは無効　存在しないキャラクターへのメンションは無視する
+async fn recipients(conn: &mut SqliteConnection, place: &Place, eno: i64, anchors: Vec<i64>, mentions: Vec<i64>) -> Result<(Vec<i64>, Vec<i64>), sqlx::Error> {
+	let mut actors = Vec::new();
+	let mut invalid_anchors = Vec::new();
+	for anchor in anchors {
+		// 読めない場所の発言へのアンカーは存在しないものと同じ扱い
+		let actor = sqlx::query_scalar!(
+			"SELECT t.actor FROM timeline t JOIN place p ON p.id=t.place WHERE t.id=? AND t.visible AND (p.visibility='public' OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=?))",
+			anchor,
+			eno
+		)
+		.fetch_optional(&mut *conn)
+		.await?;
+		match actor {
+			// システムメッセージ・削除されたキャラクターの発言には宛先が無い
+			Some(actor) => actors.extend(actor),
+			None => invalid_anchors.push(anchor),
+		}
+	}
+	for mention in mentions {
+		// 存在しないキャラクターへのメンションは無視する
+		if sqlx::query_scalar!("SELECT eno FROM actor WHERE eno=?", mention).fetch_optional(&mut *conn).await?.is_some() {
+			actors.push(mention);
+		}
+	}
+	actors.sort_unstable();
+	actors.dedup();
+	// group・dmでは参加者以外を宛先にしない
+	if !place.is_public() {
+		let mut members = Vec::new();
+		for actor in actors {
+			if place::is_member(&mut *conn, place.id, actor).await? {
+				members.push(actor);
+			}
+		}
+		actors = members;
+	}
+	Ok((actors, invalid_anchors))
+}
+
+/// 本文中のアンカー(`>>{id}`)とメンション(`@{eno}`)　どちらも重複を除いて出現順
+fn references(body: &str) -> (Vec<i64>, Vec<i64>) {
+	// `prefix`の直後に続く数字
+	fn numbers(body: &str, prefix: &str) -> Vec<i64> {
+		let mut list: Vec<i64> = Vec::new();
+		for (idx, _) in body.match_indices(prefix) {
+			let rest = &body[idx + prefix.len()..];
+			let len = rest.bytes().take_while(u8::is_ascii_digit).count();
+			if let Ok(x) = rest[..len].parse()
+				&& !list.contains(&x)
+			{
+				list.push(x);
+			}
+		}
+		list
+	}
+	(numbers(body, ">>"), numbers(body, "@"))
+}
+
+/// 新しい発言の配信（Server-Sent Events）
+mod stream {
+	use std::{collections::VecDeque, time::Duration};
+
+	use actix_web::{HttpRequest, HttpResponse, Responder, error::*, http::header, web};
+	use common::{Authorized, api_token};
+	use serde::Serialize;
+	use sqlx::{QueryBuilder, Sqlite, SqlitePool, prelude::FromRow};
+	use tokio::sync::broadcast::error::RecvError;
+
+	use super::place::{self, Place};
+	use crate::utils::{
+		Hub, MessageResult,
+		hub::{Post, Subscription},
+	};
+
+	/// 接続を保つためのコメントを送る間隔
+	const HEARTBEAT: Duration = Duration::from_secs(15);
+	/// 再接続までの待ち時間(ミリ秒)　EventSourceに指示する
+	const RETRY: u64 = 3000;
+	/// DBから取り直す時に1度に読む件数
+	const BACKLOG: i64 = 100;
+
+	/// 送る1件　`mention`は自分宛か
+	#[derive(FromRow, Serialize)]
+	struct Message {
+		#[sqlx(flatten)]
+		#[serde(flatten)]
+		post: Post,
+		mention: bool,
+	}
+
+	/// 送る位置　どの発言を送るか・いつDBから読むかの判断だけを持つ
+	///
+	/// IDは発言のトランザクションを確定した順に振られるが、配信の順は前後しうる
+	/// 配信のIDが飛んだらその前のものはDBに確定済みなので、配信を待たずにDBからID順に読む
+	#[derive(Debug, Default, Clone, Copy, PartialEq)]
+	struct Cursor {
+		/// 送った最後のID　送るのはID順で、これ以下のものは送り済みか送らないもの
+		last: i64,
+		/// 配信で受け取った最後のID（読めないものも含む）
+		seen: i64,
+		/// DBからの読み込みの途中か　1度に`BACKLOG`件ずつ、送り終えてから次を読む
+		catching_up: bool,
+	}
+
+	/// 配信で受け取った発言の扱い
+	#[derive(Debug, PartialEq)]
+	enum Received {
+		/// 送り済みか、DBから読む分に含まれる
+		Skip,
+		/// 間が飛んだので、この発言も含めてDBから読む
+		CatchUp,
+		/// 購読しているものなら送る
+		Deliver,
+	}
+
+	impl Cursor {
+		/// 配信で発言を受け取った
+		fn receive(&mut self, id: i64) -> Received {
+			if id > self.seen {
+				let skipped = id > self.seen + 1;
+				self.seen = id;
+				if skipped {
+					self.catching_up = true;
+					return Received::CatchUp;
+				}
+			}
+			if self.catching_up || id <= self.last { Received::Skip } else { Received::Deliver }
+		}
+		/// 配信に追いつけなかった
+		fn lagged(&mut self) {
+			self.catching_up = true;
+		}
+		/// DBから1回分（`len`件）読んだ　`BACKLOG`件ちょうどなら続きがあるかもしれない
+		fn fetched(&mut self, len: usize) {
+			self.catching_up = len as i64 == BACKLOG;
+		}
+		/// 発言を送った
+		fn sent(&mut self, id: i64) {
+			self.last = self.last.max(id);
+		}
+	}
+
+	/// 接続ごとの状態
+	struct Connection {
+		subscription: Subscription,
+		/// 購読する場所のID
+		places: Vec<i64>,
+		/// 接続時にまだ無かった場所の名前　作られたら`places`に移す
+		names: Vec<String>,
+		eno: i64,
+		cursor: Cursor,
+		pending: VecDeque<web::Bytes>,
+		pool: web::Data<SqlitePool>,
+	}
+
+	impl Connection {
+		fn push(&mut self, message: &Message) -> Result<(), serde_json::Error> {
+			let data = serde_json::to_string(message)?;
+			self.pending.push_back(web::Bytes::from(format!("id: {}\nevent: post\ndata: {data}\n\n", message.post.id)));
+			self.cursor.sent(message.post.id);
+			Ok(())
+		}
+		/// 送った最後のIDより後のものをDBから`BACKLOG`件まで読む（再接続時と、配信に追いつけなかった・間が飛んだ時）
+		///
+		/// 残りがあれば`catching_up`のままにし、送り終えてから`next`で続きを読む
+		async fn catch_up(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+			let mut builder = QueryBuilder::<Sqlite>::new(
+				"SELECT t.id,t.timestamp,p.name AS place,t.place AS place_id,t.actor,t.name,t.body,a.icon,EXISTS(SELECT 1 FROM timeline_actor m WHERE m.timeline=t.id AND m.actor=",
+			);
+			builder.push_bind(self.eno);
+			builder.push(") AS mention FROM timeline t JOIN place p ON p.id=t.place LEFT JOIN actor a ON a.eno=t.actor WHERE t.visible AND t.id>");
+			builder.push_bind(self.cursor.last).push(" AND ");
+			place::push_readable(&mut builder, Some(self.eno));
+			builder.push(" AND (mention");
+			if !self.places.is_empty() {
+				builder.push(" OR t.place IN (");
+				let mut sep = builder.separated(',');
+				for place in &self.places {
+					sep.push_bind(*place);
+				}
+				builder.push(")");
+			}
+			if !self.names.is_empty() {
+				builder.push(" OR p.name IN (");
+				let mut sep = builder.separated(',');
+				for name in &self.names {
+					sep.push_bind(name.clone());
+				}
+				builder.push(")");
+			}
+			builder.push(") ORDER BY t.id ASC LIMIT ").push_bind(BACKLOG);
+			let messages: Vec<Message> = builder.build_query_as().fetch_all(self.pool.as_ref()).await?;
+			for message in &messages {
+				self.resolve(&message.post);
+				self.push(message)?;
+			}
+			self.cursor.fetched(messages.len());
+			Ok(())
+		}
+		/// 名前で購読していた場所が作られていたらIDで購読する（以後は名前を変えても届く）
+		fn resolve(&mut self, post: &Post) {
+			if let Some(idx) = self.names.iter().position(|x| *x == post.place) {
+				self.names.swap_remove(idx);
+				self.places.push(post.place_id);
+			}
+		}
+		/// 次に送るもの　Noneなら切断する
+		async fn next(&mut self) -> Option<web::Bytes> {
+			loop {
+				if let Some(bytes) = self.pending.pop_front() {
+					return Some(bytes);
+				}
+				if self.cursor.catching_up {
+					if let Err(err) = self.catch_up().await {
+						eprintln!("{err}");
+						return None;
+					}
+					continue;
+				}
+				let event = match tokio::time::timeout(HEARTBEAT, self.subscription.receiver.recv()).await {
+					Err(_) => return Some(web::Bytes::from_static(b": heartbeat\n\n")),
+					Ok(Ok(event)) => event,
+					Ok(Err(RecvError::Lagged(_))) => {
+						self.cursor.lagged();
+						continue;
+					}
+					Ok(Err(RecvError::Closed)) => return None,
+				};
+				if self.cursor.receive(event.post.id) != Received::Deliver {
+					continue;
+				}
+				let mention = event.actors.contains(&self.eno);
+				if !(mention || self.places.contains(&event.post.place_id) || self.names.contains(&event.post.place)) {
+					continue;
+				}
+				// 参加は接続中にも変わるので、その都度確認する
+				if !event.public {
+					match place::is_member(self.pool.as_ref(), event.post.place_id, self.eno).await {
+						Ok(true) => {}
+						Ok(false) => continue,
+						Err(err) => {
+							eprintln!("{err}");
+							return None;
+						}
+					}
+				}
+				self.resolve(&event.post);
+				let message = Message {
+					post: event.post.clone(),
+					mention,
+				};
+				if let Err(err) = self.push(&message) {
+					eprintln!("{err}");
+					return None;
+				}
+			}
+		}
+	}
+
+	// 購読する場所は`place`(名前)を繰り返し指定する　自分宛の発言は場所にかかわらず送る　どちらも読める場所のもののみ
+	// 再接続時は`Last-Event-ID`（またはクエリの`last_event_id`）より後のものから送る
+	pub(super) async fn connect(
+		req: HttpRequest,
+		web::Query(query): web::Query<Vec<(String, String)>>,
+		eno: Authorized<i64>,
+		hub: web::Data<Hub>,
+		pool: web::Data<SqlitePool>,
+	) -> MessageResult<impl Responder> {
+		let eno = *eno.require(api_token::READ)?;
+		let mut places = Vec::new();
+		let mut names = Vec::new();
+		for (_, name) in query.iter().filter(|(key, value)| key == "place" && !value.is_empty()) {
+			// 参加していない場所も購読はできるが、配信時に除く（存在を明かさない）
+			match Place::find(pool.as_ref(), name, Some(eno)).await? {
+				Some(place) => places.push(place.id),
+				None => names.push(name.clone()),
+			}
+		}
+		let last = req
+			.headers()
+			.get("Last-Event-ID")
+			.and_then(|x| x.to_str().ok())
+			.or_else(|| query.iter().find(|(key, _)| key == "last_event_id").map(|(_, value)| value.as_str()))
+			.map(|x| x.trim().parse::<i64>().map_err(|_| ErrorBadRequest("Last-Event-IDが不正です")))
+			.transpose()?;
+		// 先に購読してから過去分を読む（間に投稿されたものは`last`で重複を除く）
+		let subscription = Hub::subscribe(&hub, eno).ok_or(ErrorTooManyRequests(format!("同時に接続できるのは{}つまでです", Hub::MAX_CONNECTIONS)))?;
+		let newest = sqlx::query_scalar!("SELECT MAX(id) FROM timeline").fetch_one(pool.as_ref()).await?.unwrap_or(0);
+		let mut connection = Connection {
+			subscription,
+			places,
+			names,
+			eno,
+			// 初回は今より後のものだけ
+			cursor: Cursor {
+				last: last.unwrap_or(newest),
+				seen: newest,
+				catching_up: false,
+			},
+			pending: VecDeque::from([web::Bytes::from(format!("retry: {RETRY}\n\n"))]),
+			pool,
+		};
+		// 再接続時は最初の`BACKLOG`件だけ読んでおき、続きは送りながら読む
+		if last.is_some() {
+			connection.catch_up().await.map_err(|err| ErrorInternalServerError(err.to_string()))?;
+		}
+		let stream = futures_util::stream::unfold(connection, |mut connection| async move {
+			connection.next().await.map(|bytes| (Ok::<_, actix_web::Error>(bytes), connection))
+		});
+		Ok(HttpResponse::Ok()
+			.content_type("text/event-stream")
+			.insert_header((header::CACHE_CONTROL, "no-cache"))
+			// nginxでバッファリングさせない
+			.insert_header(("X-Accel-Buffering", "no"))
+			.streaming(stream))
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+
+		#[test]
+		fn out_of_order_id_catches_up_from_db() {
+			let mut cursor = Cursor { last: 5, seen: 5, catching_up: false };
+			// 6より先に7が届いたら、6の確定を待たずにDBから読む
+			assert_eq!(cursor.receive(7), Received::CatchUp);
+			assert_eq!(cursor, Cursor { last: 5, seen: 7, catching_up: true });
+			cursor.sent(6);
+			cursor.sent(7);
+			cursor.fetched(2);
+			assert!(!cursor.catching_up);
+			// 遅れて届いた6はDBから送り済み
+			assert_eq!(cursor.receive(6), Received::Skip);
+			assert_eq!(cursor.receive(8), Received::Deliver);
+		}
+
+		#[test]
+		fn full_batch_keeps_catching_up() {
+			let mut cursor = Cursor::default();
+			cursor.fetched(BACKLOG as usize);
+			assert!(cursor.catching_up);
+			// 読み込みの途中に届いたものは次の読み込みに含まれる
+			assert_eq!(cursor.receive(1), Received::Skip);
+			cursor.fetched(BACKLOG as usize - 1);
+			assert!(!cursor.catching_up);
+			cursor.fetched(BACKLOG as usize);
+			cursor.fetched(0);
+			assert!(!cursor.catching_up);
+		}
+
+		#[test]
+		fn sent_ids_are_not_resent() {
+			let mut cursor = Cursor { last: 10, seen: 12, catching_up: false };
+			assert_eq!(cursor.receive(9), Received::Skip);
+			assert_eq!(cursor.receive(10), Received::Skip);
+			assert_eq!(cursor.receive(13), Received::Deliver);
+			cursor.sent(13);
+			// 古いIDを送っても位置は戻らない
+			cursor.sent(9);
+			assert_eq!(cursor.last, 13);
+			assert_eq!(cursor.receive(13), Received::Skip);
+		}
+
+		#[test]
+		fn lagged_catches_up() {
+			let mut cursor = Cursor { last: 3, seen: 3, catching_up: false };
+			cursor.lagged();
+			assert!(cursor.catching_up);
+			assert_eq!(cursor.receive(4), Received::Skip);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sqlx::Connection as _;
+
+	#[test]
+	fn references_in_order_without_duplicates() {
+		assert_eq!(references(">>12 >>3 @5 >>12 @5 @2"), (vec![12, 3], vec![5, 2]));
+		// 記号が続いても直後の数字だけを読む
+		assert_eq!(references(">>>>7 @@8 >> 9 @x"), (vec![7], vec![8]));
+		// 桁あふれは無視する
+		assert_eq!(references(">>99999999999999999999 >>1"), (vec![1], vec![]));
+	}
+
+	#[test]
+	fn references_are_read_from_the_raw_body() {
+		// タグの中でも使える
+		assert_eq!(references("[b]>>4[/b] [i]@6[/i]"), (vec![4], vec![6]));
+		// エスケープ済みの形で書かれたものはアンカーではない（エスケープ前の本文から読む）
+		assert_eq!(references("&gt;&gt;4 &#64;6"), (vec![], vec![]));
+	}
+
+	/// 場所1はpublic、場所2は1・2が参加するgroup、場所3は3だけが参加するgroup
+	async fn connection() -> SqliteConnection {
+		let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+		sqlx::raw_sql(
+			"CREATE TABLE actor(eno INTEGER PRIMARY KEY);
+			CREATE TABLE place(id INTEGER PRIMARY KEY, visibility TEXT NOT NULL);
+			CREATE TABLE place_member(place INTEGER NOT NULL, actor INTEGER NOT NULL);
+			CREATE TABLE timeline(id INTEGER PRIMARY KEY, place INTEGER NOT NULL, actor INTEGER, visible BOOLEAN NOT NULL DEFAULT TRUE);
+			INSERT INTO actor VALUES(1),(2),(3),(4);
+			INSERT INTO place VALUES(1,'public'),(2,'group'),(3,'group');
+			INSERT INTO place_member VALUES(2,1),(2,2),(3,3);
+			INSERT INTO timeline VALUES(10,1,2,TRUE),(11,1,3,FALSE),(12,3,3,TRUE),(13,1,NULL,TRUE),(14,2,2,TRUE);",
+		)
+		.execute(&mut conn)
+		.await
+		.unwrap();
+		conn
+	}
+
+	fn place(id: i64, visibility: &str) -> Place {
+		Place {
+			id,
+			name: String::new(),
+			visibility: visibility.into(),
+			owner: None,
+			created: 0,
+		}
+	}
+
+	#[actix_web::test]
+	async fn recipients_in_public_place() {
+		let mut conn = connection().await;
+		let (actors, invalid) = recipients(&mut conn, &place(1, place::PUBLIC), 1, vec![10, 11, 12, 13, 14, 99], vec![3, 2, 50]).await.unwrap();
+		// 10・14の発言者2とメンションの3　存在しないキャラクター50は無視する
+		assert_eq!(actors, vec![2, 3]);
+		// 非表示(11)・読めない場所(12)・存在しない(99)は無効　システムメッセージ(13)は有効だが宛先は無い
+		assert_eq!(invalid, vec![11, 12, 99]);
+	}
+
+	#[actix_web::test]
+	async fn recipients_in_group_are_members_only() {
+		let mut conn = connection().await;
+		let (actors, invalid) = recipients(&mut conn, &place(2, place::GROUP), 1, vec![10], vec![3, 4]).await.unwrap();
+		assert_eq!(actors, vec![2]);
+		assert!(invalid.is_empty());
+	}
+}
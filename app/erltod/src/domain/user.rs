@@ -1,3 +1,6 @@
 use actix_web::web;
+use common::StateRule;
+
+pub const POLICY: &[StateRule] = &[];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {}
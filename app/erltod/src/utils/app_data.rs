@@ -2,50 +2,77 @@ use std::{str::FromStr as _, sync::RwLock};
 
 use actix_web::{cookie, web};
 use base64::{Engine, prelude::BASE64_STANDARD};
+use common::{Admin, AdminPage, Backup, Lockout, Portal, MaintenancePage, SuspendedPage, portal::Config};
 use sqlx::SqlitePool;
 
-use super::{KEY, STATE, State};
+use super::{Hub, KEY, MAINTENANCE, STATE, State};
 
 #[derive(Clone)]
 pub struct AppData {
 	pub pool: web::Data<SqlitePool>,
 	pub state: web::Data<RwLock<State>>,
+	pub suspended: web::Data<SuspendedPage>,
+	pub maintenance: web::Data<MaintenancePage>,
+	pub admin_page: web::Data<AdminPage>,
+	pub backup: web::Data<Backup>,
+	pub portal: web::Data<Portal>,
+	pub hub: web::Data<Hub>,
+	pub lockout: web::Data<Lockout>,
 	pub session_key: cookie::Key,
-	pub admin_key: String,
 }
 impl AppData {
 	pub async fn new(url: &str) -> Self {
 		// DB接続
 		let pool = SqlitePool::connect(url).await.unwrap();
 		// State読み込み
-		let state = match sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", STATE).fetch_one(&pool).await {
+		let mut state = match sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", STATE).fetch_one(&pool).await {
 			Ok(r) => State::from_str(&r).unwrap(),
 			Err(sqlx::Error::RowNotFound) => {
-				let state = State::Maintenance;
+				let state = State::Maintenance(Default::default());
 				let str = state.to_string();
 				sqlx::query!("INSERT INTO setting VALUES(?,?)", STATE, str).execute(&pool).await.unwrap();
 				state
 			}
 			Err(err) => panic!("{}", err),
 		};
+		if let State::Maintenance(maintenance) = &mut state
+			&& let Some(value) = sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", MAINTENANCE).fetch_optional(&pool).await.unwrap()
+		{
+			*maintenance = serde_json::from_str(&value).unwrap_or_default();
+		}
 		// Key読み込み
-		let (session_key, admin_key) = match sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", KEY).fetch_one(&pool).await {
-			Ok(r) => (cookie::Key::from(&BASE64_STANDARD.decode(&r).unwrap()), r),
+		// セッションの署名鍵　管理者の資格情報とは独立しているので、管理者の変更でユーザーのセッションは切れない
+		let session_key = match sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", KEY).fetch_one(&pool).await {
+			Ok(r) => cookie::Key::from(&BASE64_STANDARD.decode(&r).unwrap()),
 			Err(sqlx::Error::RowNotFound) => {
 				let session_key = cookie::Key::generate();
-				let admin_key = BASE64_STANDARD.encode(&session_key.master());
-				sqlx::query_scalar!("INSERT INTO setting VALUES(?,?)", KEY, admin_key).execute(&pool).await.unwrap();
-				(session_key, admin_key)
+				let value = BASE64_STANDARD.encode(session_key.master());
+				sqlx::query_scalar!("INSERT INTO setting VALUES(?,?)", KEY, value).execute(&pool).await.unwrap();
+				session_key
 			}
 			Err(err) => panic!("{}", err),
 		};
-		println!("admin: {admin_key}");
+		// 管理者が居なければ初期のオーナーを作成
+		if let Some(path) = Admin::bootstrap(&pool, url).await.unwrap() {
+			println!("admin: name=admin password written to {}", path.display());
+		}
+		// ポータル接続設定読み込み
+		let portal = Portal::new(Config::from_env().expect("portal config is undefined")).unwrap();
 		// 作成
 		Self {
 			pool: web::Data::new(pool),
 			state: web::Data::new(RwLock::new(state)),
+			suspended: web::Data::new(SuspendedPage(super::template::suspended)),
+			maintenance: web::Data::new(MaintenancePage(super::template::maintenance)),
+			admin_page: web::Data::new(AdminPage {
+				plain: super::template::admin_plain,
+				layout: super::template::admin_layout,
+			}),
+			backup: web::Data::new(Backup::from_env(env!("CARGO_PKG_NAME"), url)),
+			portal: web::Data::new(portal),
+			hub: web::Data::new(Hub::new()),
+			lockout: web::Data::new(Lockout::default()),
 			session_key,
-			admin_key,
 		}
 	}
 }
@@ -0,0 +1,27 @@
+use serde::Deserialize;
+
+const CURSOR_LIMIT: usize = 100;
+
+/// IDによるカーソル　新しいものが追加され続ける一覧のページ送りに使う
+///
+/// `before_id`より古いもの・`after_id`より新しいものを取得する（両方なら間のもの）
+#[derive(Deserialize)]
+pub struct CursorParams {
+	pub before_id: Option<i64>,
+	pub after_id: Option<i64>,
+	#[serde(default = "cursor_limit_default")]
+	pub limit: usize,
+}
+impl CursorParams {
+	pub fn limit(&self) -> usize {
+		self.limit.clamp(1, CURSOR_LIMIT)
+	}
+	/// `after_id`のみなら古い方から辿る（カーソルの直後から取りこぼさないように）
+	pub fn ascending(&self) -> bool {
+		self.after_id.is_some() && self.before_id.is_none()
+	}
+}
+
+fn cursor_limit_default() -> usize {
+	20
+}
@@ -0,0 +1,99 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use actix_web::web;
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+use tokio::sync::broadcast;
+
+/// 発言1件（表示用）
+#[derive(Clone, FromRow, Serialize)]
+pub struct Post {
+	pub id: i64,
+	pub timestamp: i64,
+	/// 場所の現在の名前
+	pub place: String,
+	pub place_id: i64,
+	pub actor: Option<i64>,
+	pub name: String,
+	/// タグ処理済み
+	pub body: String,
+	/// 発言者の現在のアイコン
+	pub icon: Option<String>,
+}
+
+/// 配信する1件
+pub struct Event {
+	pub post: Post,
+	/// publicの場所か（それ以外は受け取る側で参加を確認する）
+	pub public: bool,
+	/// 宛先（timeline_actorに登録したキャラクター）
+	pub actors: Vec<i64>,
+}
+
+/// 新しい発言のプロセス内配信
+///
+/// 発言したハンドラーが`publish`し、SSEの接続ごとに`subscribe`して受け取る　受け取る側で場所・宛先を絞り込む
+/// 同じキャラクターの同時接続数を制限する
+pub struct Hub {
+	sender: broadcast::Sender<Arc<Event>>,
+	connections: Mutex<HashMap<i64, usize>>,
+}
+
+impl Hub {
+	/// 受け取る側が処理しきれずに溜められる件数　超えたらDBから取り直す
+	const CAPACITY: usize = 256;
+	/// キャラクターごとの同時接続数
+	pub const MAX_CONNECTIONS: usize = 3;
+
+	pub fn new() -> Self {
+		Self {
+			sender: broadcast::channel(Self::CAPACITY).0,
+			connections: Mutex::new(HashMap::new()),
+		}
+	}
+	/// 配信する　接続が無ければ何もしない
+	pub fn publish(&self, event: Event) {
+		let _ = self.sender.send(Arc::new(event));
+	}
+	/// 購読を始める　同時接続数を超えていればNone
+	pub fn subscribe(hub: &web::Data<Self>, eno: i64) -> Option<Subscription> {
+		let mut connections = hub.connections.lock().ok()?;
+		let count = connections.entry(eno).or_default();
+		if *count >= Self::MAX_CONNECTIONS {
+			return None;
+		}
+		*count += 1;
+		Some(Subscription {
+			receiver: hub.sender.subscribe(),
+			hub: hub.clone(),
+			eno,
+		})
+	}
+}
+impl Default for Hub {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// 購読中の接続　破棄すると接続数から外れる
+pub struct Subscription {
+	pub receiver: broadcast::Receiver<Arc<Event>>,
+	hub: web::Data<Hub>,
+	eno: i64,
+}
+impl Drop for Subscription {
+	fn drop(&mut self) {
+		if let Ok(mut connections) = self.hub.connections.lock()
+			&& let Some(count) = connections.get_mut(&self.eno)
+		{
+			*count -= 1;
+			if *count == 0 {
+				connections.remove(&self.eno);
+			}
+		}
+	}
+}
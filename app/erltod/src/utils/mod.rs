@@ -1,20 +1,25 @@
 pub mod app_data;
+pub mod cursor_params;
 pub mod error;
+pub mod hub;
 pub mod page_params;
 pub mod state;
 pub mod tag_format;
 pub mod template;
+pub mod unread;
 
 use serde::{Deserialize as _, Deserializer};
 
-pub use self::{app_data::AppData, error::*, page_params::PageParams, state::State, tag_format::CommonTag, template::Template};
+pub use self::{app_data::AppData, cursor_params::CursorParams, error::*, hub::Hub, page_params::PageParams, state::State, tag_format::CommonTag, template::Template, unread::Unread};
 
-pub type StateHandle = common::StateHandle<State>;
+pub type StatePolicy = common::StatePolicy<State>;
 pub type Eno = common::Identity<i64>;
 
 // 変数定義
 pub const STATE: &str = "STATE";
-const KEY: &str = "KEY";
+/// メンテナンスの内容（`common::Maintenance`のJSON）
+pub const MAINTENANCE: &str = "MAINTENANCE";
+pub const KEY: &str = "KEY";
 
 /// リソースへのパスを生成する
 pub fn resource(path: &str) -> String {
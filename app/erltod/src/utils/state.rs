@@ -1,18 +1,28 @@
 use std::str::FromStr;
 
+use common::Maintenance;
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum State {
 	Active,
 	Close,
-	Maintenance,
+	Maintenance(Maintenance),
 }
 
+/// 状態ポリシー(`common::StateRule`)で許可する状態
+pub const ALL: &[&str] = &["active", "close", "maintenance"];
+/// メンテナンス中以外
+pub const OPEN: &[&str] = &["active", "close"];
+pub const ACTIVE: &[&str] = &["active"];
+
 impl common::IsMaintenance for State {
-	fn is_maintenance(&self) -> bool {
-		*self == State::Maintenance
+	fn maintenance(&self) -> Option<&Maintenance> {
+		match self {
+			Self::Maintenance(x) => Some(x),
+			_ => None,
+		}
 	}
 }
 impl ToString for State {
@@ -20,7 +30,7 @@ impl ToString for State {
 		match self {
 			Self::Active => "active",
 			Self::Close => "close",
-			Self::Maintenance => "maintenance",
+			Self::Maintenance(_) => "maintenance",
 		}
 		.into()
 	}
@@ -32,7 +42,8 @@ impl FromStr for State {
 		Ok(match s {
 			"active" => Self::Active,
 			"close" => Self::Close,
-			"maintenance" => Self::Maintenance,
+			// 内容はsettingテーブルの`MAINTENANCE`から別に読み込む
+			"maintenance" => Self::Maintenance(Maintenance::default()),
 			_ => return Err(()),
 		})
 	}
@@ -1,6 +1,10 @@
+use chrono::{DateTime, Local};
+use common::{Announcement, Impersonation, Maintenance, Schedule, Suspension};
+use html_codec::HTMLEncode as _;
 use serde::Serialize;
+use sqlx::SqlitePool;
 
-use super::resource;
+use super::{CommonTag, Unread, resource};
 
 /// テンプレート種別
 pub enum Template {
@@ -16,9 +20,33 @@ pub struct Summary {
 	pub image: String,
 	pub card: String,
 }
+/// ログイン中のキャラクター　ヘッダーに名前と未読数を出す
 #[derive(Serialize)]
 pub struct User {
+	pub eno: i64,
 	pub name: String,
+	/// 場所の未読数の合計
+	pub unread: i64,
+	/// 自分宛の未読数
+	pub mentions: i64,
+}
+impl User {
+	/// ログインしていない・キャラクターが存在しなければNone
+	pub async fn load(pool: &SqlitePool, eno: Option<i64>) -> Result<Option<Self>, sqlx::Error> {
+		let Some(eno) = eno else {
+			return Ok(None);
+		};
+		let Some(name) = sqlx::query_scalar!("SELECT name FROM actor WHERE eno=?", eno).fetch_optional(pool).await? else {
+			return Ok(None);
+		};
+		let unread = Unread::load(pool, eno).await?;
+		Ok(Some(Self {
+			eno,
+			name,
+			unread: unread.total(),
+			mentions: unread.mentions,
+		}))
+	}
 }
 impl Template {
 	/// テンプレートからHTMLを生成
@@ -31,6 +59,9 @@ impl Template {
 				"nobots": &nobots,
 				"summary": &summary,
 				"user": &user,
+				"schedule": &schedule(),
+				"announcements": &Notice::active(),
+				"impersonation": &impersonation(),
 			})),
 			Template::None => Ok(main),
 		}
@@ -43,8 +74,97 @@ impl Template {
 				"nobots": &nobots,
 				"summary": &summary,
 				"user": &user,
+				"schedule": &schedule(),
+				"announcements": &Notice::active(),
+				"impersonation": &impersonation(),
 			})),
 			Template::None => Ok(text.into()),
 		}
 	}
 }
+
+/// メンテナンス中に表示するページ　`common::MaintenancePage`として登録する
+pub fn maintenance(maintenance: &Maintenance) -> String {
+	let until = maintenance
+		.until
+		.and_then(|x| DateTime::from_timestamp(x, 0))
+		.map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+	let tpl = Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	};
+	tpl.render("html/maintenance.html", liquid::object!({ "message": &maintenance.message, "until": &until }))
+		.unwrap_or_else(|err| format!("メンテナンス中です\n\n\n(liquid error)\n{err}"))
+}
+
+/// 表示用のお知らせ　本文はCommonTagを変換したHTMLにする
+#[derive(Serialize)]
+pub struct Notice {
+	#[serde(flatten)]
+	pub announcement: Announcement,
+	pub html: String,
+}
+impl Notice {
+	/// このアプリに表示中のお知らせ
+	pub fn active() -> Vec<Self> {
+		Self::active_for(env!("CARGO_PKG_NAME"))
+	}
+	pub fn active_for(app: &str) -> Vec<Self> {
+		Announcement::active(app)
+			.into_iter()
+			.map(|x| Self {
+				html: x.body.escape(false).tag(CommonTag).br().into_owned(),
+				announcement: x,
+			})
+			.collect()
+	}
+}
+
+/// 次に予約されている状態変更　レイアウトで告知する
+fn schedule() -> Option<liquid::Object> {
+	Schedule::upcoming().into_iter().next().map(|x| {
+		let at = DateTime::from_timestamp(x.at, 0).map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+		liquid::object!({ "state": &x.state, "at": &at })
+	})
+}
+
+/// 管理者がなりすまし中ならその内容　レイアウトにバナーを出す
+fn impersonation() -> Option<liquid::Object> {
+	Impersonation::current().map(|x| liquid::object!({ "admin": &x.admin, "user": &x.user_key() }))
+}
+
+/// 管理画面のログイン画面など、ナビゲーション無しのページ　`common::AdminPage`として登録する
+pub fn admin_plain(main: &str) -> Result<String, liquid::Error> {
+	Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	}
+	.render_raw(main)
+}
+
+/// 管理画面の共通レイアウト（ナビゲーション付き）　`common::AdminPage`として登録する
+pub fn admin_layout(main: &str) -> Result<String, liquid::Error> {
+	Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	}
+	.render("html/admin/layout.html", liquid::object!({ "main": main }))
+}
+
+/// 利用停止中のユーザーに表示するページ　`common::SuspendedPage`として登録する
+pub fn suspended(suspension: &Suspension) -> String {
+	let expiry = suspension
+		.expiry
+		.and_then(|x| DateTime::from_timestamp(x, 0))
+		.map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+	let tpl = Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	};
+	tpl.render("html/suspended.html", liquid::object!({ "reason": &suspension.reason, "expiry": &expiry }))
+		.unwrap_or_else(|err| format!("アカウントが利用停止中です\n理由: {}\n\n\n(liquid error)\n{err}", suspension.reason))
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+use sqlx::{SqliteExecutor, SqlitePool, prelude::FromRow};
+
+/// 場所1件の未読
+#[derive(FromRow, Serialize)]
+pub struct PlaceUnread {
+	pub id: i64,
+	pub name: String,
+	pub visibility: String,
+	/// 最後に読んだ発言のID　未読なら0
+	pub last_read: i64,
+	pub unread: i64,
+}
+
+/// 未読数　自分の発言は数えない
+///
+/// 場所は参加しているgroup・dmと、既読位置(place_read)のあるpublicの場所
+/// 自分宛は`actor.mention_read`より後のtimeline_actor（読めない場所のものは除く）
+#[derive(Serialize)]
+pub struct Unread {
+	pub places: Vec<PlaceUnread>,
+	pub mentions: i64,
+}
+
+impl Unread {
+	pub async fn load(pool: &SqlitePool, eno: i64) -> Result<Self, sqlx::Error> {
+		let places = sqlx::query_as(
+			"SELECT p.id,p.name,p.visibility,COALESCE(r.last,0) AS last_read,(SELECT COUNT(*) FROM timeline t WHERE t.place=p.id AND t.visible AND t.id>COALESCE(r.last,0) AND t.actor IS NOT ?1) AS unread FROM place p LEFT JOIN place_read r ON r.place=p.id AND r.actor=?1 WHERE (p.visibility='public' AND r.actor IS NOT NULL) OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=?1) ORDER BY p.id",
+		)
+		.bind(eno)
+		.fetch_all(pool)
+		.await?;
+		let mentions = sqlx::query_scalar(
+			"SELECT COUNT(*) FROM timeline_actor m JOIN timeline t ON t.id=m.timeline JOIN place p ON p.id=t.place WHERE m.actor=?1 AND t.visible AND t.actor IS NOT ?1 AND t.id>(SELECT mention_read FROM actor WHERE eno=?1) AND (p.visibility='public' OR EXISTS(SELECT 1 FROM place_member pm WHERE pm.place=p.id AND pm.actor=?1))",
+		)
+		.bind(eno)
+		.fetch_one(pool)
+		.await?;
+		Ok(Self { places, mentions })
+	}
+	/// 全ての場所の未読数の合計
+	pub fn total(&self) -> i64 {
+		self.places.iter().map(|x| x.unread).sum()
+	}
+}
+
+/// 場所の既読位置を進める（戻しはしない）
+pub async fn mark_place(executor: impl SqliteExecutor<'_>, eno: i64, place: i64, last: i64) -> Result<(), sqlx::Error> {
+	sqlx::query!(
+		"INSERT INTO place_read(actor,place,last) VALUES(?,?,?) ON CONFLICT(actor,place) DO UPDATE SET last=MAX(last,excluded.last)",
+		eno,
+		place,
+		last
+	)
+	.execute(executor)
+	.await?;
+	Ok(())
+}
+
+/// 自分宛の既読位置を進める（戻しはしない）
+pub async fn mark_mentions(executor: impl SqliteExecutor<'_>, eno: i64, last: i64) -> Result<(), sqlx::Error> {
+	sqlx::query!("UPDATE actor SET mention_read=MAX(mention_read,?) WHERE eno=?", last, eno).execute(executor).await?;
+	Ok(())
+}
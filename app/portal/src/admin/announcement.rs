@@ -0,0 +1,190 @@
+use actix_web::{Responder, error::*, web};
+use chrono::Local;
+use common::{Admin, Announcement, Auditor};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+use validation::Validation;
+
+use super::{Search, datetime, datetime_input, datetime_local, redirect, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).post(create));
+	cfg.service(web::resource("{id}").get(edit).post(update));
+	cfg.service(web::resource("{id}/delete").post(delete));
+}
+
+// お知らせ一覧
+async fn list(web::Query(info): web::Query<Search>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(Serialize)]
+	struct Record {
+		id: i64,
+		title: String,
+		priority: i64,
+		since: String,
+		until: Option<String>,
+		apps: String,
+		active: bool,
+	}
+	let now = Local::now().timestamp();
+	let search = format!("%{}%", info.q);
+	let mut rows: Vec<Announcement> = sqlx::query_as(
+		"SELECT id,title,body,priority,since,until,apps FROM announcement WHERE title LIKE ?1 OR body LIKE ?1 ORDER BY id DESC LIMIT ?2,?3",
+	)
+	.bind(search)
+	.bind(info.page.offset() as i64)
+	.bind(info.fetch_limit())
+	.fetch_all(pool.as_ref())
+	.await?;
+	let pager = info.pager(&mut rows);
+	let records: Vec<Record> = rows
+		.into_iter()
+		.map(|x| Record {
+			active: x.is_active(now),
+			id: x.id,
+			title: x.title,
+			priority: x.priority,
+			since: datetime(x.since),
+			until: x.until.map(datetime),
+			apps: x.apps,
+		})
+		.collect();
+	render("html/admin/announcement/list.html", liquid::object!({ "announcements": &records, "pager": &pager }))
+}
+
+/// 作成・編集フォーム
+#[derive(Deserialize, Validation)]
+struct Form {
+	#[validation(name = "タイトル", min = 1, max = 100)]
+	title: String,
+	#[validation(name = "本文", min = 1, max = 4000)]
+	body: String,
+	#[serde(default)]
+	priority: i64,
+	/// `<input type="datetime-local">`の値　空欄なら今から
+	#[serde(default)]
+	since: String,
+	/// `<input type="datetime-local">`の値　空欄なら無期限
+	#[serde(default)]
+	until: String,
+	/// 空白区切り　空欄なら全てのアプリ
+	#[serde(default)]
+	apps: String,
+}
+/// 検証済みの値
+struct Values {
+	since: i64,
+	until: Option<i64>,
+	apps: String,
+}
+impl Form {
+	fn values(&self) -> Result<Values, actix_web::Error> {
+		self.validate().map_err(ErrorBadRequest)?;
+		let since = match self.since.as_str() {
+			"" => Local::now().timestamp(),
+			x => datetime_local(x)?,
+		};
+		let until = Some(self.until.as_str()).filter(|x| !x.is_empty()).map(datetime_local).transpose()?;
+		if until.is_some_and(|x| x <= since) {
+			return Err(ErrorBadRequest("表示終了は表示開始より後にしてください"));
+		}
+		Ok(Values {
+			since,
+			until,
+			apps: self.apps.split_whitespace().collect::<Vec<_>>().join(" "),
+		})
+	}
+}
+
+// 作成
+async fn create(web::Form(info): web::Form<Form>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let values = info.values()?;
+	let now = Local::now().timestamp();
+	let mut tx = pool.begin().await?;
+	let id = sqlx::query_scalar!(
+		"INSERT INTO announcement(title,body,priority,since,until,apps,created) VALUES(?,?,?,?,?,?,?) RETURNING id",
+		info.title,
+		info.body,
+		info.priority,
+		values.since,
+		values.until,
+		values.apps,
+		now
+	)
+	.fetch_one(&mut *tx)
+	.await?;
+	auditor.admin(&admin, "announcement.create", &id.to_string()).change(None, Some(&info.title)).record(&mut *tx).await?;
+	tx.commit().await?;
+	Announcement::reload(pool.as_ref()).await?;
+	Ok(redirect("/admin/announcement"))
+}
+
+// 編集画面
+#[derive(Deserialize)]
+struct Id {
+	id: i64,
+}
+async fn edit(path: web::Path<Id>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let record: Announcement = sqlx::query_as("SELECT id,title,body,priority,since,until,apps FROM announcement WHERE id=?")
+		.bind(id)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorNotFound("お知らせが存在しません"))?;
+	render(
+		"html/admin/announcement/edit.html",
+		liquid::object!({
+			"announcement": &record,
+			"since": &datetime_input(record.since),
+			"until": &record.until.map(datetime_input),
+		}),
+	)
+}
+
+// 編集
+async fn update(path: web::Path<Id>, web::Form(info): web::Form<Form>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	#[derive(FromRow)]
+	struct Before {
+		title: String,
+		body: String,
+	}
+	let id = path.into_inner().id;
+	let values = info.values()?;
+	let now = Local::now().timestamp();
+	let mut tx = pool.begin().await?;
+	let before: Before = sqlx::query_as("SELECT title,body FROM announcement WHERE id=?")
+		.bind(id)
+		.fetch_optional(&mut *tx)
+		.await?
+		.ok_or(ErrorNotFound("お知らせが存在しません"))?;
+	sqlx::query!(
+		"UPDATE announcement SET title=?,body=?,priority=?,since=?,until=?,apps=?,updated=? WHERE id=?",
+		info.title,
+		info.body,
+		info.priority,
+		values.since,
+		values.until,
+		values.apps,
+		now,
+		id
+	)
+	.execute(&mut *tx)
+	.await?;
+	let before = format!("{}\n{}", before.title, before.body);
+	let after = format!("{}\n{}", info.title, info.body);
+	auditor.admin(&admin, "announcement.update", &id.to_string()).change(Some(&before), Some(&after)).record(&mut *tx).await?;
+	tx.commit().await?;
+	Announcement::reload(pool.as_ref()).await?;
+	Ok(redirect(&format!("/admin/announcement/{id}")))
+}
+
+// 削除
+async fn delete(path: web::Path<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let title = sqlx::query_scalar!("DELETE FROM announcement WHERE id=? RETURNING title", id).fetch_optional(pool.as_ref()).await?;
+	if let Some(title) = title {
+		auditor.admin(&admin, "announcement.delete", &id.to_string()).change(Some(&title), None).record(pool.as_ref()).await?;
+		Announcement::reload(pool.as_ref()).await?;
+	}
+	Ok(redirect("/admin/announcement"))
+}
@@ -0,0 +1,113 @@
+use actix_web::{HttpResponse, Responder, error::*, mime, web};
+use base64::{Engine, prelude::*};
+use rand::{TryRngCore as _, rngs::OsRng};
+use common::{Admin, Auditor};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+use url::Url;
+
+use crate::utils::{MessageResult, PageParams, app_client::Origins};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").put(put).delete(delete));
+	cfg.service(web::resource("secret").post(secret));
+	cfg.service(web::resource("log").get(log));
+}
+
+// クライアント登録・更新
+#[derive(Deserialize)]
+struct Put {
+	id: String,
+	name: String,
+	origins: Vec<String>,
+	redirects: Vec<String>,
+}
+async fn put(web::Json(info): web::Json<Put>, admin: Admin, auditor: Auditor, cache: web::Data<Origins>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	// オリジンはシリアライズ済みの形式に揃えておく
+	let origins = info
+		.origins
+		.iter()
+		.map(|x| Url::parse(x).map(|url| url.origin().ascii_serialization()))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(ErrorBadRequest)?;
+	for redirect in &info.redirects {
+		let origin = Url::parse(redirect).map_err(ErrorBadRequest)?.origin().ascii_serialization();
+		if !origins.contains(&origin) {
+			return Err(ErrorBadRequest(format!("{redirect} のオリジンが許可されていません")).into());
+		}
+	}
+	let origins = origins.join("\n");
+	let redirects = info.redirects.join("\n");
+	sqlx::query!(
+		"INSERT INTO app_client(id,name,origins,redirects) VALUES(?1,?2,?3,?4) ON CONFLICT(id) DO UPDATE SET name=?2,origins=?3,redirects=?4",
+		info.id,
+		info.name,
+		origins,
+		redirects
+	)
+	.execute(pool.as_ref())
+	.await?;
+	auditor
+		.admin(&admin, "client.put", &info.id)
+		.change(None, Some(&format!("{}\n{origins}\n{redirects}", info.name)))
+		.record(pool.as_ref())
+		.await?;
+	cache.reload(pool.as_ref()).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// クライアント削除
+#[derive(Deserialize)]
+struct Id {
+	id: String,
+}
+async fn delete(web::Query(info): web::Query<Id>, admin: Admin, auditor: Auditor, cache: web::Data<Origins>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let result = sqlx::query!("DELETE FROM app_client WHERE id=?", info.id).execute(pool.as_ref()).await?;
+	if result.rows_affected() > 0 {
+		auditor.admin(&admin, "client.delete", &info.id).record(pool.as_ref()).await?;
+	}
+	cache.reload(pool.as_ref()).await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// シークレット(再)発行　平文はこのレスポンスでしか確認できない
+async fn secret(web::Query(info): web::Query<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let mut dst = [0u8; 32];
+	OsRng.try_fill_bytes(&mut dst)?;
+	let secret = BASE64_URL_SAFE_NO_PAD.encode(dst);
+	let hashed = crate::utils::password::hash(&secret).map_err(ErrorInternalServerError)?;
+	let result = sqlx::query!("UPDATE app_client SET secret=? WHERE id=?", hashed, info.id).execute(pool.as_ref()).await?;
+	if result.rows_affected() == 0 {
+		return Err(ErrorNotFound("未登録のクライアントです").into());
+	}
+	auditor.admin(&admin, "client.secret", &info.id).record(pool.as_ref()).await?;
+	Ok(HttpResponse::Ok().body(secret))
+}
+
+// コード交換の記録
+#[derive(Deserialize)]
+struct Log {
+	#[serde(flatten)]
+	page: PageParams,
+	id: Option<String>,
+}
+async fn log(web::Query(info): web::Query<Log>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	#[derive(FromRow, Serialize)]
+	struct Record {
+		timestamp: i64,
+		client: String,
+		user: Option<String>,
+		result: String,
+	}
+	let mut builder = sqlx::QueryBuilder::new("SELECT timestamp,client,user,result FROM app_client_log");
+	if let Some(id) = info.id {
+		builder.push(" WHERE client=").push_bind(id);
+	}
+	builder
+		.push(" ORDER BY id DESC LIMIT ")
+		.push_bind(info.page.offset() as i64)
+		.push(",")
+		.push_bind(info.page.limit() as i64);
+	let result: Vec<Record> = builder.build_query_as().fetch_all(pool.as_ref()).await?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&result)?))
+}
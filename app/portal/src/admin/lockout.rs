@@ -0,0 +1,31 @@
+use actix_web::{HttpResponse, Responder, mime, web};
+use common::{Lockout, lockout::Failure};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::MessageResult;
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list).delete(clear));
+}
+
+// ロック状況一覧
+async fn list(lockout: web::Data<Lockout>) -> MessageResult<impl Responder> {
+	#[derive(Serialize)]
+	struct Record {
+		key: String,
+		#[serde(flatten)]
+		failure: Failure,
+	}
+	let result: Vec<Record> = lockout.list().into_iter().map(|(key, failure)| Record { key, failure }).collect();
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&result)?))
+}
+
+// ロック解除　キー未指定なら全て
+#[derive(Deserialize)]
+struct Clear {
+	key: Option<String>,
+}
+async fn clear(web::Query(info): web::Query<Clear>, lockout: web::Data<Lockout>) -> MessageResult<impl Responder> {
+	lockout.clear(info.key.as_deref());
+	Ok(HttpResponse::NoContent().finish())
+}
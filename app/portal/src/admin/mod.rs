@@ -1,24 +1,242 @@
+mod announcement;
+mod audit;
+mod backup;
+mod client;
+mod lockout;
+mod report;
+mod setting;
+mod suspension;
+mod user;
+
 use std::{str::FromStr, sync::RwLock};
 
-use actix_web::{HttpRequest, HttpResponse, Responder, error::*, web};
+use actix_session::Session;
+use actix_web::{HttpResponse, Responder, error::*, http::header, mime, web};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone as _};
+use common::{Admin, Auditor, Impersonation, IsMaintenance as _, Maintenance, RequireRole, Role, Schedule, admin_page};
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 
-use crate::utils::{MessageResult, STATE, State};
+use crate::utils::{MAINTENANCE, MessageResult, PageParams, PageResult, STATE, State, StatePolicy, Template, template};
 
+/// 閲覧はviewer、操作はmoderator以上（`AdminGuardMiddleware`の既定）　それ以上が必要なものは`RequireRole`で宣言する
 pub fn cfg(cfg: &mut web::ServiceConfig) {
-	cfg.route("", web::to(async || "Hello Admin"));
-	// GETメソッドでサーバー状態を変更するの行儀が悪いけど、適当に<a>並べるの楽だったので
-	// Adminはどうせ自分しか見ないので多少行儀の悪い書き方しててもいいんじゃないだろうか
-	cfg.route("state", web::to(state));
+	cfg.route("", web::get().to(index));
+	// 変更はPOSTのみ　GETは確認画面
+	cfg.service(web::resource("state").get(state_confirm).route(web::post().to(state).wrap(RequireRole(Role::Owner))));
+	cfg.route("policy", web::get().to(policy));
+	// なりすましの開始は`user/{name}/impersonate`
+	cfg.route("impersonate/end", web::post().to(impersonate_end));
+	cfg.service(
+		web::scope("schedule")
+			.wrap(RequireRole(Role::Owner))
+			.service(web::resource("").post(schedule))
+			.service(web::resource("{id}/cancel").post(schedule_cancel)),
+	);
+	cfg.service(web::scope("account").wrap(RequireRole(Role::Owner)).configure(admin_page::account));
+	cfg.service(web::scope("user").configure(user::cfg));
+	cfg.service(web::scope("report").configure(report::cfg));
+	cfg.service(web::scope("announcement").configure(announcement::cfg));
+	cfg.service(web::scope("audit").configure(audit::cfg));
+	cfg.service(web::scope("backup").wrap(RequireRole(Role::Owner)).configure(backup::cfg));
+	cfg.service(web::scope("setting").configure(setting::cfg));
+	cfg.service(web::scope("client").wrap(RequireRole(Role::Owner)).configure(client::cfg));
+	cfg.service(web::scope("lockout").configure(lockout::cfg));
+	cfg.service(web::scope("suspension").configure(suspension::cfg));
+}
+
+/// 管理画面の共通レイアウト（ナビゲーション付き）で描画する
+fn render(file: &str, globals: liquid::Object) -> PageResult<HttpResponse> {
+	let main = Template::None.render(file, globals)?;
+	let html = template::admin_layout(&main)?;
+	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
+}
+
+/// 操作後に一覧などへ戻す
+fn redirect(location: &str) -> HttpResponse {
+	HttpResponse::SeeOther().insert_header((header::LOCATION, location)).finish()
+}
+
+/// 表示用の日時
+fn datetime(timestamp: i64) -> String {
+	DateTime::from_timestamp(timestamp, 0)
+		.map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string())
+		.unwrap_or_default()
+}
+
+/// `<input type="datetime-local">`の初期値
+fn datetime_input(timestamp: i64) -> String {
+	DateTime::from_timestamp(timestamp, 0)
+		.map(|x| x.with_timezone(&Local).format("%Y-%m-%dT%H:%M").to_string())
+		.unwrap_or_default()
+}
+
+/// `<input type="datetime-local">`の値を読む
+fn datetime_local(value: &str) -> Result<i64, actix_web::Error> {
+	NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M")
+		.ok()
+		.and_then(|x| Local.from_local_datetime(&x).earliest())
+		.map(|x| x.timestamp())
+		.ok_or(ErrorBadRequest("日時が不正です"))
+}
+
+/// 一覧画面の検索・ページ送り
+#[derive(Deserialize)]
+struct Search {
+	#[serde(flatten)]
+	page: PageParams,
+	#[serde(default)]
+	q: String,
+}
+impl Search {
+	/// 次のページがあるか判定するため、1件多く取得する
+	fn fetch_limit(&self) -> i64 {
+		self.page.limit() as i64 + 1
+	}
+	/// テンプレートに渡すページ情報　`records`は余分に取得した1件を取り除く
+	fn pager<T>(&self, records: &mut Vec<T>) -> Pager {
+		let next = records.len() > self.page.limit();
+		records.truncate(self.page.limit());
+		Pager {
+			q: self.q.clone(),
+			page: self.page.page,
+			limit: self.page.limit(),
+			prev: self.page.page.checked_sub(1),
+			next: next.then_some(self.page.page + 1),
+		}
+	}
+}
+#[derive(Serialize)]
+struct Pager {
+	q: String,
+	page: usize,
+	limit: usize,
+	prev: Option<usize>,
+	next: Option<usize>,
+}
+
+// トップ
+async fn index(state: web::Data<RwLock<State>>) -> PageResult<impl Responder> {
+	let state = state.read().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?.clone();
+	let maintenance = state.maintenance().map(|x| {
+		liquid::object!({
+			"message": &x.message,
+			"until": &x.until.map(datetime),
+			"allow": &x.allow,
+		})
+	});
+	let state = state.to_string();
+	let schedules: Vec<_> = Schedule::upcoming()
+		.into_iter()
+		.map(|x| liquid::object!({ "id": x.id, "state": &x.state, "at": &datetime(x.at), "creator": &x.creator }))
+		.collect();
+	render("html/admin/index.html", liquid::object!({ "state": &state, "maintenance": &maintenance, "schedules": &schedules }))
+}
+
+// サーバー状態変更の確認画面
+#[derive(Deserialize)]
+struct StateForm {
+	state: String,
+	/// 以下はメンテナンスの場合のみ使う
+	#[serde(default)]
+	message: String,
+	/// `<input type="datetime-local">`の値　空欄なら未定
+	#[serde(default)]
+	until: String,
+	/// メンテナンス中も利用できるユーザー（1行に1人）
+	#[serde(default)]
+	allow: String,
+}
+async fn state_confirm(web::Query(info): web::Query<StateForm>, state: web::Data<RwLock<State>>) -> PageResult<impl Responder> {
+	let new = State::from_str(&info.state).map_err(|_| ErrorBadRequest("無効なステートが指定されました"))?.to_string();
+	let now = state.read().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?.clone();
+	// メンテナンス中なら現在の内容を初期値にする
+	let maintenance = now.maintenance().cloned().unwrap_or_default();
+	let until = maintenance.until.map(datetime_input);
+	render(
+		"html/admin/state.html",
+		liquid::object!({
+			"now": &now.to_string(),
+			"new": &new,
+			"message": &maintenance.message,
+			"until": &until,
+			"allow": &maintenance.allow.join("\n"),
+		}),
+	)
 }
 
-async fn state(req: HttpRequest, state: web::Data<RwLock<State>>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
-	let new = State::from_str(req.query_string()).map_err(|_| ErrorBadRequest("無効なステートが指定されました"))?;
+// サーバー状態変更
+async fn state(web::Form(info): web::Form<StateForm>, admin: Admin, auditor: Auditor, state: web::Data<RwLock<State>>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let mut new = State::from_str(&info.state).map_err(|_| ErrorBadRequest("無効なステートが指定されました"))?;
+	if let State::Maintenance(maintenance) = &mut new {
+		*maintenance = Maintenance {
+			message: Some(info.message.trim()).filter(|x| !x.is_empty()).map(String::from),
+			until: Some(info.until.as_str()).filter(|x| !x.is_empty()).map(datetime_local).transpose()?,
+			allow: info.allow.lines().map(str::trim).filter(|x| !x.is_empty()).map(String::from).collect(),
+		};
+	}
+	let str = new.to_string();
+	let detail = new.maintenance().map(serde_json::to_string).transpose()?;
+	let old = state.read().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?.to_string();
+	let mut tx = pool.begin().await?;
+	sqlx::query!("UPDATE setting SET value=?2 WHERE key=?1", STATE, str).execute(&mut *tx).await?;
+	// メンテナンスの内容は状態と一緒に差し替える
+	match &detail {
+		Some(detail) => sqlx::query!("INSERT OR REPLACE INTO setting VALUES(?,?)", MAINTENANCE, detail).execute(&mut *tx).await?,
+		None => sqlx::query!("DELETE FROM setting WHERE key=?", MAINTENANCE).execute(&mut *tx).await?,
+	};
+	let after = detail.map_or(str.clone(), |x| format!("{str} {x}"));
+	auditor.admin(&admin, "state.change", STATE).change(Some(&old), Some(&after)).record(&mut *tx).await?;
+	tx.commit().await?;
 	let mut guard = state.write().map_err(|_| ErrorInternalServerError("アプリケーション状態読み込みに失敗"))?;
 	println!("{:?} -> {:?}", guard, new);
 	*guard = new;
-	drop(guard);
-	let str = new.to_string();
-	sqlx::query!("UPDATE setting SET value=?2 WHERE key=?1", STATE, str).execute(pool.as_ref()).await?;
-	Ok(HttpResponse::NoContent().finish())
+	Ok(redirect("/admin"))
+}
+
+// 状態ポリシーの一覧
+async fn policy(policy: web::Data<StatePolicy>) -> PageResult<impl Responder> {
+	render("html/admin/policy.html", liquid::object!({ "routes": &policy.describe() }))
+}
+
+// サーバー状態変更の予約
+#[derive(Deserialize)]
+struct ScheduleForm {
+	state: String,
+	/// `<input type="datetime-local">`の値
+	at: String,
+}
+async fn schedule(web::Form(info): web::Form<ScheduleForm>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let state = State::from_str(&info.state).map_err(|_| ErrorBadRequest("無効なステートが指定されました"))?.to_string();
+	let at = datetime_local(&info.at)?;
+	if at <= Local::now().timestamp() {
+		return Err(ErrorBadRequest("過去の日時は指定できません").into());
+	}
+	let entry = auditor.admin(&admin, "state.schedule", STATE).change(None, Some(&format!("{state} {}", datetime(at))));
+	Schedule::add(pool.as_ref(), &state, at, &admin.name, entry).await?;
+	Ok(redirect("/admin"))
+}
+
+// 予約の取り消し
+#[derive(Deserialize)]
+struct ScheduleId {
+	id: i64,
+}
+async fn schedule_cancel(path: web::Path<ScheduleId>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let entry = auditor.admin(&admin, "state.unschedule", &id.to_string());
+	if !Schedule::cancel(pool.as_ref(), id, entry).await? {
+		return Err(ErrorNotFound("予約が存在しません").into());
+	}
+	Ok(redirect("/admin"))
+}
+
+// なりすましの終了　管理者のセッションに戻す
+async fn impersonate_end(admin: Admin, auditor: Auditor, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let Some(impersonation) = Impersonation::end(&session) else {
+		return Ok(redirect("/admin"));
+	};
+	let name = impersonation.user_key();
+	auditor.admin(&admin, "user.unimpersonate", &name).record(pool.as_ref()).await?;
+	Ok(redirect(&user::page(&name)))
 }
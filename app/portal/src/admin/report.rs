@@ -0,0 +1,230 @@
+use std::str::FromStr;
+
+use actix_web::{Responder, error::*, web};
+use chrono::Local;
+use common::{Admin, Auditor, Webhook};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use super::{Search, datetime, redirect, render};
+use crate::utils::{
+	MessageResult, PageResult,
+	report::{Status, Target},
+};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("{id}").get(detail).post(update));
+	cfg.service(web::resource("{id}/note").post(note));
+	cfg.service(web::resource("{id}/delete").post(delete));
+}
+
+#[derive(FromRow)]
+struct Row {
+	id: i64,
+	timestamp: i64,
+	user: Option<String>,
+	target_type: Option<String>,
+	target: Option<String>,
+	body: String,
+	status: String,
+	assignee: Option<String>,
+	notify: bool,
+	updated: Option<i64>,
+}
+/// 画面表示用
+#[derive(Serialize)]
+struct Record {
+	id: i64,
+	timestamp: String,
+	user: Option<String>,
+	target_type: Option<String>,
+	target_label: Option<&'static str>,
+	target: Option<String>,
+	body: String,
+	status: String,
+	status_label: &'static str,
+	assignee: Option<String>,
+	notify: bool,
+	updated: Option<String>,
+}
+impl From<Row> for Record {
+	fn from(x: Row) -> Self {
+		Self {
+			id: x.id,
+			timestamp: datetime(x.timestamp),
+			user: x.user,
+			target_label: x.target_type.as_deref().and_then(|x| Target::from_str(x).ok()).map(|x| x.label()),
+			target_type: x.target_type,
+			target: x.target,
+			body: x.body,
+			status_label: Status::from_str(&x.status).map(|x| x.label()).unwrap_or_default(),
+			status: x.status,
+			assignee: x.assignee,
+			notify: x.notify,
+			updated: x.updated.map(datetime),
+		}
+	}
+}
+/// 選択肢
+#[derive(Serialize)]
+struct StatusOption {
+	value: String,
+	label: &'static str,
+}
+fn status_options() -> Vec<StatusOption> {
+	Status::ALL
+		.iter()
+		.map(|x| StatusOption {
+			value: x.to_string(),
+			label: x.label(),
+		})
+		.collect()
+}
+
+// 通報一覧
+#[derive(Deserialize)]
+struct Query {
+	#[serde(flatten)]
+	search: Search,
+	/// 空欄なら全て
+	#[serde(default)]
+	status: String,
+}
+async fn list(web::Query(info): web::Query<Query>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	let search = format!("%{}%", info.search.q);
+	let mut rows: Vec<Row> = sqlx::query_as(
+		"SELECT id,timestamp,user,target_type,target,body,status,assignee,notify,updated FROM report \
+		WHERE (body LIKE ?1 OR user LIKE ?1 OR target LIKE ?1) AND (?2='' OR status=?2) ORDER BY id DESC LIMIT ?3,?4",
+	)
+	.bind(search)
+	.bind(&info.status)
+	.bind(info.search.page.offset() as i64)
+	.bind(info.search.fetch_limit())
+	.fetch_all(pool.as_ref())
+	.await?;
+	let pager = info.search.pager(&mut rows);
+	let records: Vec<Record> = rows.into_iter().map(Record::from).collect();
+	render(
+		"html/admin/report/list.html",
+		liquid::object!({ "reports": &records, "pager": &pager, "status": &info.status, "statuses": &status_options() }),
+	)
+}
+
+// 詳細
+#[derive(Deserialize)]
+struct Id {
+	id: i64,
+}
+async fn detail(path: web::Path<Id>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(FromRow, Serialize)]
+	struct Note {
+		admin: String,
+		body: String,
+		timestamp: i64,
+	}
+	let id = path.into_inner().id;
+	let pool = pool.as_ref();
+	let row: Row = sqlx::query_as("SELECT id,timestamp,user,target_type,target,body,status,assignee,notify,updated FROM report WHERE id=?")
+		.bind(id)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorNotFound("通報が存在しません"))?;
+	let notes: Vec<Note> = sqlx::query_as("SELECT admin,body,timestamp FROM report_note WHERE report=? ORDER BY id ASC").bind(id).fetch_all(pool).await?;
+	let notes: Vec<_> = notes
+		.into_iter()
+		.map(|x| liquid::object!({ "admin": &x.admin, "body": &x.body, "timestamp": &datetime(x.timestamp) }))
+		.collect();
+	let admins: Vec<String> = Admin::list(pool).await?.into_iter().map(|x| x.name).collect();
+	render(
+		"html/admin/report/detail.html",
+		liquid::object!({
+			"report": &Record::from(row),
+			"notes": &notes,
+			"admins": &admins,
+			"statuses": &status_options(),
+		}),
+	)
+}
+
+// 状態・担当の変更
+#[derive(Deserialize)]
+struct Update {
+	status: String,
+	/// 空欄なら担当なし
+	assignee: String,
+}
+async fn update(path: web::Path<Id>, web::Form(info): web::Form<Update>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let status = Status::from_str(&info.status).map_err(|_| ErrorBadRequest("無効な状態が指定されました"))?;
+	let assignee = Some(info.assignee).filter(|x| !x.is_empty());
+	let mut tx = pool.begin().await?;
+	let before = sqlx::query!("SELECT user,status,assignee,notify FROM report WHERE id=?", id)
+		.fetch_optional(&mut *tx)
+		.await?
+		.ok_or(ErrorNotFound("通報が存在しません"))?;
+	let str = status.to_string();
+	let now = Local::now().timestamp();
+	sqlx::query!("UPDATE report SET status=?,assignee=?,updated=? WHERE id=?", str, assignee, now, id)
+		.execute(&mut *tx)
+		.await
+		.map_err(|err| match err {
+			sqlx::Error::Database(err) if err.is_foreign_key_violation() => ErrorBadRequest("担当の管理者が存在しません"),
+			err => ErrorInternalServerError(err),
+		})?;
+	let target = id.to_string();
+	if before.status != str {
+		auditor.admin(&admin, "report.status", &target).change(Some(&before.status), Some(&str)).record(&mut *tx).await?;
+	}
+	if before.assignee != assignee {
+		auditor.admin(&admin, "report.assign", &target).change(before.assignee.as_deref(), assignee.as_deref()).record(&mut *tx).await?;
+	}
+	// 解決したら通報者に知らせる
+	let webhook = match before.user {
+		Some(user) if before.notify && before.status != str && status == Status::Resolved => {
+			sqlx::query_scalar!("SELECT webhook FROM user WHERE name=?", user).fetch_optional(&mut *tx).await?.flatten()
+		}
+		_ => None,
+	};
+	tx.commit().await?;
+	if let Some(webhook) = webhook {
+		actix_web::rt::spawn(async move {
+			let content = format!("通報・お問い合わせ #{id} への対応が完了しました。\nご連絡ありがとうございました。");
+			if let Err(err) = Webhook::new(&content, "untroche", None).send(&webhook).await {
+				eprintln!("{err}");
+			}
+		});
+	}
+	Ok(redirect(&format!("/admin/report/{id}")))
+}
+
+// 内部メモの追加
+#[derive(Deserialize)]
+struct Note {
+	body: String,
+}
+async fn note(path: web::Path<Id>, web::Form(info): web::Form<Note>, admin: Admin, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	if info.body.trim().is_empty() {
+		return Err(ErrorBadRequest("メモを入力してください").into());
+	}
+	let now = Local::now().timestamp();
+	sqlx::query!("INSERT INTO report_note(report,admin,body,timestamp) VALUES(?,?,?,?)", id, admin.name, info.body, now)
+		.execute(pool.as_ref())
+		.await
+		.map_err(|err| match err {
+			sqlx::Error::Database(err) if err.is_foreign_key_violation() => ErrorNotFound("通報が存在しません"),
+			err => ErrorInternalServerError(err),
+		})?;
+	Ok(redirect(&format!("/admin/report/{id}")))
+}
+
+// 削除
+async fn delete(path: web::Path<Id>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let id = path.into_inner().id;
+	let body = sqlx::query_scalar!("DELETE FROM report WHERE id=? RETURNING body", id).fetch_optional(pool.as_ref()).await?;
+	if let Some(body) = body {
+		auditor.admin(&admin, "report.delete", &id.to_string()).change(Some(&body), None).record(pool.as_ref()).await?;
+	}
+	Ok(redirect("/admin/report"))
+}
@@ -0,0 +1,66 @@
+use actix_web::{Responder, error::*, web};
+use common::{Admin, Auditor, RequireRole, Role};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+
+use super::{Search, redirect, render};
+use crate::utils::{KEY, MAINTENANCE, MessageResult, PageResult, STATE};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("{key}").route(web::post().to(save).wrap(RequireRole(Role::Owner))));
+}
+
+/// 画面から編集させない設定　状態はstateから、鍵は表示もしない
+const PROTECTED: &[&str] = &[KEY, STATE, MAINTENANCE];
+
+// 設定一覧
+async fn list(web::Query(info): web::Query<Search>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(FromRow, Serialize)]
+	struct Record {
+		key: String,
+		value: String,
+		#[sqlx(skip)]
+		protected: bool,
+	}
+	let search = format!("%{}%", info.q);
+	let mut records: Vec<Record> = sqlx::query_as("SELECT key,value FROM setting WHERE key LIKE ? ORDER BY key ASC LIMIT ?,?")
+		.bind(search)
+		.bind(info.page.offset() as i64)
+		.bind(info.fetch_limit())
+		.fetch_all(pool.as_ref())
+		.await?;
+	let pager = info.pager(&mut records);
+	for record in &mut records {
+		record.protected = PROTECTED.contains(&record.key.as_str());
+		if record.key == KEY {
+			record.value = "********".into();
+		}
+	}
+	render("html/admin/setting.html", liquid::object!({ "settings": &records, "pager": &pager }))
+}
+
+// 編集
+#[derive(Deserialize)]
+struct Key {
+	key: String,
+}
+#[derive(Deserialize)]
+struct Save {
+	value: String,
+}
+async fn save(path: web::Path<Key>, web::Form(info): web::Form<Save>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let key = path.into_inner().key;
+	if PROTECTED.contains(&key.as_str()) {
+		return Err(ErrorForbidden("この設定は変更できません").into());
+	}
+	let mut tx = pool.begin().await?;
+	let before = sqlx::query_scalar!("SELECT value FROM setting WHERE key=?", key)
+		.fetch_optional(&mut *tx)
+		.await?
+		.ok_or(ErrorNotFound("設定が存在しません"))?;
+	sqlx::query!("UPDATE setting SET value=? WHERE key=?", info.value, key).execute(&mut *tx).await?;
+	auditor.admin(&admin, "setting.change", &key).change(Some(&before), Some(&info.value)).record(&mut *tx).await?;
+	tx.commit().await?;
+	Ok(redirect("/admin/setting"))
+}
@@ -0,0 +1,179 @@
+use actix_session::Session;
+use actix_web::{Responder, error::*, web};
+use chrono::Local;
+use common::{Admin, ApiToken, Auditor, Impersonation, SqliteSessionStore, Suspension};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, prelude::FromRow};
+use url::Url;
+
+use super::{Search, datetime, redirect, render};
+use crate::utils::{MessageResult, PageResult};
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+	cfg.service(web::resource("{name}").get(edit).post(save));
+	cfg.service(web::resource("{name}/delete").post(delete));
+	cfg.service(web::resource("{name}/suspend").post(suspend));
+	cfg.service(web::resource("{name}/lift").post(lift));
+	cfg.service(web::resource("{name}/impersonate").post(impersonate));
+}
+
+/// ユーザーの編集画面のパス　名前は長さしか制限していないので、パスの1要素としてエンコードする
+pub(super) fn page(name: &str) -> String {
+	let mut url = Url::parse("http://localhost/admin/user").expect("固定のURL");
+	url.path_segments_mut().expect("固定のURL").push(name);
+	url.path().to_string()
+}
+
+// ユーザー一覧
+async fn list(web::Query(info): web::Query<Search>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	#[derive(FromRow)]
+	struct Row {
+		name: String,
+		webhook: Option<String>,
+		totp: bool,
+		reason: Option<String>,
+		expiry: Option<i64>,
+	}
+	#[derive(Serialize)]
+	struct Record {
+		name: String,
+		webhook: bool,
+		totp: bool,
+		suspended: Option<String>,
+	}
+	let now = Local::now().timestamp();
+	let search = format!("%{}%", info.q);
+	let mut rows: Vec<Row> = sqlx::query_as(
+		"SELECT u.name,u.webhook,u.totp IS NOT NULL AS totp,s.reason,s.expiry FROM user u \
+		LEFT JOIN suspension s ON s.user=u.name AND (s.expiry IS NULL OR s.expiry>?) \
+		WHERE u.name LIKE ? ORDER BY u.name ASC LIMIT ?,?",
+	)
+	.bind(now)
+	.bind(search)
+	.bind(info.page.offset() as i64)
+	.bind(info.fetch_limit())
+	.fetch_all(pool.as_ref())
+	.await?;
+	let pager = info.pager(&mut rows);
+	let records: Vec<Record> = rows
+		.into_iter()
+		.map(|x| Record {
+			suspended: x.reason.map(|reason| match x.expiry {
+				Some(expiry) => format!("{reason}（{}まで）", datetime(expiry)),
+				None => format!("{reason}（無期限）"),
+			}),
+			name: x.name,
+			webhook: x.webhook.is_some(),
+			totp: x.totp,
+		})
+		.collect();
+	render("html/admin/user/list.html", liquid::object!({ "users": &records, "pager": &pager }))
+}
+
+// 編集画面
+#[derive(Deserialize)]
+struct Name {
+	name: String,
+}
+async fn edit(path: web::Path<Name>, pool: web::Data<SqlitePool>) -> PageResult<impl Responder> {
+	let name = path.into_inner().name;
+	let pool = pool.as_ref();
+	let record = sqlx::query!("SELECT profile,webhook FROM user WHERE name=?", name)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorNotFound("ユーザーが存在しません"))?;
+	let suspension = Suspension::find(pool, &name).await?;
+	let expiry = suspension.as_ref().and_then(|x| x.expiry).map(datetime);
+	render(
+		"html/admin/user/edit.html",
+		liquid::object!({
+			"name": &name,
+			"profile": &record.profile,
+			"webhook": &record.webhook,
+			"suspension": &suspension,
+			"expiry": &expiry,
+		}),
+	)
+}
+
+// 編集
+#[derive(Deserialize)]
+struct Save {
+	profile: String,
+	webhook: String,
+}
+async fn save(path: web::Path<Name>, web::Form(info): web::Form<Save>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	let webhook = Some(info.webhook).filter(|x| !x.is_empty());
+	let mut tx = pool.begin().await?;
+	let before = sqlx::query!("SELECT profile,webhook FROM user WHERE name=?", name)
+		.fetch_optional(&mut *tx)
+		.await?
+		.ok_or(ErrorNotFound("ユーザーが存在しません"))?;
+	sqlx::query!("UPDATE user SET profile=?,webhook=? WHERE name=?", info.profile, webhook, name).execute(&mut *tx).await?;
+	if before.profile != info.profile {
+		auditor.admin(&admin, "user.profile", &name).record(&mut *tx).await?;
+	}
+	if before.webhook != webhook {
+		auditor.admin(&admin, "user.webhook", &name).change(before.webhook.as_deref(), webhook.as_deref()).record(&mut *tx).await?;
+	}
+	tx.commit().await?;
+	Ok(redirect(&page(&name)))
+}
+
+// 削除
+async fn delete(path: web::Path<Name>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	let pool = pool.as_ref();
+	let result = sqlx::query!("DELETE FROM user WHERE name=?", name).execute(pool).await?;
+	if result.rows_affected() > 0 {
+		auditor.admin(&admin, "user.delete", &name).record(pool).await?;
+	}
+	SqliteSessionStore::revoke_all(pool, &name, None).await?;
+	ApiToken::revoke_all(pool, &name).await?;
+	Ok(redirect("/admin/user"))
+}
+
+// 利用停止
+#[derive(Deserialize)]
+struct Suspend {
+	reason: String,
+	/// 停止日数　空欄なら無期限
+	days: String,
+}
+async fn suspend(path: web::Path<Name>, web::Form(info): web::Form<Suspend>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	if info.reason.trim().is_empty() {
+		return Err(ErrorBadRequest("理由を入力してください").into());
+	}
+	let days = match info.days.trim() {
+		"" => None,
+		days => Some(days.parse::<i64>().map_err(|_| ErrorBadRequest("停止日数は数値で指定してください"))?),
+	};
+	let expiry = Suspension::expiry(days)?;
+	Suspension::issue(pool.as_ref(), &name, &info.reason, &admin.name, expiry).await?;
+	auditor.admin(&admin, "user.suspend", &name).change(None, Some(&info.reason)).record(pool.as_ref()).await?;
+	Ok(redirect(&page(&name)))
+}
+
+// 利用停止の解除
+async fn lift(path: web::Path<Name>, admin: Admin, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	if Suspension::lift(pool.as_ref(), &name).await? {
+		auditor.admin(&admin, "user.lift", &name).record(pool.as_ref()).await?;
+	}
+	Ok(redirect(&page(&name)))
+}
+
+// なりすまし（ユーザーとして閲覧）　終了は`/admin/impersonate/end`
+async fn impersonate(path: web::Path<Name>, admin: Admin, auditor: Auditor, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	sqlx::query_scalar!("SELECT name FROM user WHERE name=?", name)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorNotFound("ユーザーが存在しません"))?;
+	Impersonation::start(&session, &admin, &name)?;
+	auditor.admin(&admin, "user.impersonate", &name).record(pool.as_ref()).await?;
+	Ok(redirect("/profile"))
+}
@@ -0,0 +1,26 @@
+use actix_web::{HttpResponse, Responder, http::Method, mime, web};
+use common::StateRule;
+use serde::Deserialize;
+
+use crate::utils::{MessageResult, state::ALL, template::Notice};
+
+pub const POLICY: &[StateRule] = &[StateRule::new("", &[Method::GET], ALL)];
+
+pub fn cfg(cfg: &mut web::ServiceConfig) {
+	cfg.service(web::resource("").get(list));
+}
+
+// 表示中のお知らせ　各アプリはここから取得する
+#[derive(Deserialize)]
+struct Query {
+	/// 対象のアプリ　空欄ならポータル
+	#[serde(default)]
+	app: String,
+}
+async fn list(web::Query(info): web::Query<Query>) -> MessageResult<impl Responder> {
+	let list = match info.app.as_str() {
+		"" => Notice::active(),
+		app => Notice::active_for(app),
+	};
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&list)?))
+}
@@ -1,60 +1,167 @@
-use actix_web::{HttpResponse, Responder, error::*, mime, web};
+use actix_cors::Cors;
+use actix_web::{
+	HttpResponse, Responder,
+	error::*,
+	http::{Method, header},
+	mime, web,
+};
 use base64::{Engine, prelude::*};
 use chrono::Local;
 use rand::{TryRngCore as _, rngs::OsRng};
 use serde::Deserialize;
 use sqlx::SqlitePool;
+use url::Url;
 
-use crate::utils::{MessageResult, Name, State, StateHandle, Template};
+use common::{
+	Impersonation, StateRule, Suspension,
+	portal::{CertRequest, CertResponse, UserResponse, challenge},
+};
+
+use crate::utils::{
+	MessageResult, Name, Template,
+	app_client::{AppClient, Origins, log},
+	state::{ACTIVE, ALL, OPEN},
+};
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET, Method::POST], ACTIVE),
+	StateRule::new("user/{name}", &[Method::GET], OPEN),
+	// CORSのプリフライト
+	StateRule::new("", &[Method::OPTIONS], ALL),
+	StateRule::new("user/{name}", &[Method::OPTIONS], ALL),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(issue).post(cert));
+	cfg.service(web::resource("user/{name}").get(user));
+}
+
+/// 登録済みクライアントのオリジンのみ許可する
+pub fn cors(origins: web::Data<Origins>) -> Cors {
+	Cors::default()
+		.allowed_origin_fn(move |origin, _| origin.to_str().is_ok_and(|x| origins.contains(x)))
+		.allowed_methods(["GET", "POST"])
+		.allowed_header(header::CONTENT_TYPE)
 }
 
-async fn issue(user: Option<Name>, state: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+// 認可リクエスト
+#[derive(Deserialize)]
+struct Issue {
+	client_id: String,
+	redirect_uri: String,
+	state: String,
+	code_challenge: String,
+	code_challenge_method: String,
+}
+async fn issue(web::Query(info): web::Query<Issue>, user: Option<Name>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
 	// 認証コード有効期限(秒)
 	const EXPIRY: i64 = 120;
 
-	if *state != State::Active {
-		return Err(ErrorForbidden("当サイトはクローズしています").into());
+	// 認証コードを発行すると、なりすまし先としてアプリへログインできてしまう
+	Impersonation::forbid()?;
+	if info.state.is_empty() || info.state.len() > 256 {
+		return Err(ErrorBadRequest("stateが不正です").into());
 	}
-	let code = if let Some(user) = user {
+	// PKCEはS256のみ受け付ける
+	if info.code_challenge_method != "S256" || !is_pkce_value(&info.code_challenge) {
+		return Err(ErrorBadRequest("code_challengeが不正です").into());
+	}
+	let pool = pool.as_ref();
+	// クライアント確認　不正なリクエストはリダイレクトせずにその場でエラーを返す
+	let client = sqlx::query!("SELECT origins,redirects FROM app_client WHERE id=?", info.client_id)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorBadRequest("未登録のクライアントです"))?;
+	if !client.redirects.lines().any(|x| x == info.redirect_uri) {
+		return Err(ErrorBadRequest("redirect_uriが登録されていません").into());
+	}
+	let redirect = Url::parse(&info.redirect_uri).map_err(ErrorBadRequest)?;
+	let origin = redirect.origin().ascii_serialization();
+	if !client.origins.lines().any(|x| x == origin) {
+		return Err(ErrorBadRequest("redirect_uriのオリジンが許可されていません").into());
+	}
+	let (code, callback) = if let Some(user) = user {
+		// 利用停止中のユーザーはアプリへログインさせない
+		if Suspension::find(pool, &user).await?.is_some() {
+			return Err(ErrorForbidden("アカウントが利用停止中です").into());
+		}
 		// コード生成
 		let mut dst = [0xffu8; 20];
 		OsRng.try_fill_bytes(&mut dst)?;
 		let code = BASE64_URL_SAFE_NO_PAD.encode(dst);
 		let timestamp = Local::now().timestamp() + EXPIRY;
-		sqlx::query!("INSERT INTO auth(code,timestamp,user) VALUES(?,?,?)", code, timestamp, *user)
-			.execute(pool.as_ref())
-			.await?;
-		Some(code)
+		sqlx::query!(
+			"INSERT INTO auth(code,timestamp,user,client,redirect,challenge) VALUES(?,?,?,?,?,?)",
+			code,
+			timestamp,
+			*user,
+			info.client_id,
+			info.redirect_uri,
+			info.code_challenge
+		)
+		.execute(pool)
+		.await?;
+		// ポップアップでない場合のリダイレクト先
+		let mut callback = redirect;
+		callback.query_pairs_mut().append_pair("code", &code).append_pair("state", &info.state);
+		(Some(code), Some(callback.to_string()))
 	} else {
-		None
+		(None, None)
 	};
 	// コードを埋め込んだhtmlを返す
 	let html = Template::None.render(
 		"auth.html",
 		liquid::object!({
 			"code": code.as_ref(),
+			"state": &info.state,
+			"origin": &origin,
+			"callback": callback.as_ref(),
 		}),
 	)?;
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
 }
 
-#[derive(Deserialize)]
-struct Cert {
-	code: String,
-}
-async fn cert(web::Json(info): web::Json<Cert>, state: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
-	if *state != State::Active {
-		return Err(ErrorForbidden("当サイトはクローズしています").into());
-	}
+// 認証コードの交換
+async fn cert(web::Json(info): web::Json<CertRequest>, client: AppClient, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
 	let pool = pool.as_ref();
 	let timestamp = Local::now().timestamp();
 	sqlx::query!("DELETE FROM auth WHERE timestamp<?", timestamp).execute(pool).await?;
-	match sqlx::query_scalar::<_, String>("SELECT user FROM auth WHERE code=?").bind(info.code).fetch_one(pool).await {
-		Ok(id) => Ok(HttpResponse::Ok().body(id)),
-		Err(sqlx::Error::RowNotFound) => Err(ErrorUnauthorized("認証コードが不正です").into()),
-		Err(err) => Err(err.into()),
+	// コードは照合の成否に関わらず一度きりで破棄する
+	let record = sqlx::query!("DELETE FROM auth WHERE code=? RETURNING user,client,redirect,challenge", info.code)
+		.fetch_optional(pool)
+		.await?;
+	match record {
+		Some(record) if record.client == *client && record.redirect == info.redirect_uri && verify_pkce(&info.code_verifier, &record.challenge) => {
+			log(pool, &client, Some(&record.user), "ok").await?;
+			Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&CertResponse { user: record.user })?))
+		}
+		_ => {
+			log(pool, &client, None, "code").await?;
+			Err(ErrorUnauthorized("認証コードが不正です").into())
+		}
 	}
 }
+
+// ユーザー情報
+#[derive(Deserialize)]
+struct User {
+	name: String,
+}
+async fn user(path: web::Path<User>, _: AppClient, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = path.into_inner().name;
+	let webhook = sqlx::query_scalar!("SELECT webhook FROM user WHERE name=?", name)
+		.fetch_optional(pool.as_ref())
+		.await?
+		.ok_or(ErrorNotFound("ユーザーが存在しません"))?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&UserResponse { name, webhook })?))
+}
+
+/// code_verifier/code_challengeとして使用可能な文字列か(RFC 7636)
+fn is_pkce_value(value: &str) -> bool {
+	(43..=128).contains(&value.len()) && value.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+}
+
+/// code_verifierのSHA-256がcode_challengeと一致するか
+fn verify_pkce(verifier: &str, code_challenge: &str) -> bool {
+	is_pkce_value(verifier) && challenge(verifier) == code_challenge
+}
@@ -1,14 +1,43 @@
 use actix_session::Session;
-use actix_web::{HttpResponse, Responder, error::*, mime, web};
-use serde::Deserialize;
+use actix_web::{HttpRequest, HttpResponse, Responder, error::*, http::Method, mime, web};
+use base64::{Engine, prelude::*};
+use chrono::Local;
+use common::{
+	ApiToken, Auditor, Lockout, SqliteSessionStore, StateRule, Webhook,
+	client_addr::client_ip,
+	lockout::too_many_requests,
+};
+use rand::{TryRngCore as _, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use sqlx::SqlitePool;
 use validation::Validation;
 
-use crate::utils::{MessageResult, Name, PageResult, State, StateHandle, Template, deser_flag};
+use crate::utils::{
+	MessageResult, Name, PageResult, PublicUrl, Template, deser_flag,
+	rate_limit::ResetLimit,
+	state::{ACTIVE, ALL, OPEN},
+	totp::{Totp, normalize_recovery_code},
+};
+
+/// パスワード確認済みで2段階認証待ちのユーザーを保持するセッションキー
+const TOTP_PENDING: &str = "totp-pending";
+
+pub const POLICY: &[StateRule] = &[
+	// メンテナンス中も許可されたユーザーがログインできるようにする
+	StateRule::new("", &[], ALL),
+	StateRule::new("totp", &[Method::POST], ALL),
+	StateRule::new("register", &[Method::POST], ACTIVE),
+	StateRule::new("forgot", &[Method::POST], OPEN),
+	StateRule::new("reset", &[Method::GET, Method::POST], OPEN),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(index).post(login).delete(logout));
+	cfg.service(web::resource("totp").post(totp));
 	cfg.service(web::resource("register").post(register));
+	cfg.service(web::resource("forgot").post(forgot));
+	cfg.service(web::resource("reset").get(reset_form).post(reset));
 }
 
 // ログイン
@@ -40,16 +69,112 @@ async fn index(web::Query(info): web::Query<Index>) -> PageResult<impl Responder
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
 }
 
-async fn login(web::Form(info): web::Form<Authorize>, session: Session, _: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
-	let hashed = sqlx::query_scalar!("SELECT password FROM user WHERE name=?", info.name).fetch_one(pool.as_ref()).await?;
-	if crate::utils::password::verify(&info.password, &hashed).map_err(|err| ErrorInternalServerError(err))? {
+async fn login(
+	req: HttpRequest,
+	web::Form(info): web::Form<Authorize>,
+	session: Session,
+	lockout: web::Data<Lockout>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
+	let ip = format!("ip:{}", client_ip(&req));
+	let user = format!("user:{}", info.name);
+	let keys = [ip.as_str(), user.as_str()];
+	if let Err(wait) = lockout.begin(&keys) {
+		return Ok(too_many_requests(wait));
+	}
+	// 存在しないユーザーでもパスワード違いと同じ扱いにする
+	let record = sqlx::query!(r#"SELECT password,totp IS NOT NULL AS "totp!: bool" FROM user WHERE name=?"#, info.name)
+		.fetch_optional(pool.as_ref())
+		.await?;
+	let verified = match &record {
+		Some(record) => crate::utils::password::verify(&info.password, &record.password).map_err(ErrorInternalServerError)?,
+		None => false,
+	};
+	if verified {
+		lockout.success(&keys, Some(&user));
+		if record.is_some_and(|x| x.totp) {
+			// 2段階認証が有効ならコード入力を待つ
+			session.insert(
+				TOTP_PENDING,
+				TotpPending {
+					name: info.name,
+					expiry: Local::now().timestamp() + TotpPending::EXPIRY,
+				},
+			)?;
+			return Ok(HttpResponse::Accepted().content_type(mime::APPLICATION_JSON).body(r#"{"totp":true}"#));
+		}
 		Name::save(&session, &info.name)?;
 		Ok(HttpResponse::NoContent().finish())
 	} else {
+		// 失敗は`begin`で数え済み
 		Err(ErrorUnauthorized("ユーザー名またはパスワードが異なります").into())
 	}
 }
 
+// 2段階認証
+#[derive(Serialize, Deserialize)]
+struct TotpPending {
+	name: String,
+	expiry: i64,
+}
+impl TotpPending {
+	/// パスワード確認後、コード入力を待つ時間(秒)
+	const EXPIRY: i64 = 300;
+}
+#[derive(Deserialize)]
+struct TotpCode {
+	/// TOTPのコードまたはリカバリーコード
+	code: String,
+}
+async fn totp(req: HttpRequest, web::Form(info): web::Form<TotpCode>, session: Session, lockout: web::Data<Lockout>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let now = Local::now().timestamp();
+	let pending = session
+		.get::<TotpPending>(TOTP_PENDING)
+		.ok()
+		.flatten()
+		.filter(|x| x.expiry > now)
+		.ok_or(ErrorUnauthorized("ログインからやり直してください"))?;
+	let ip = format!("ip:{}", client_ip(&req));
+	let user = format!("user:{}", pending.name);
+	let keys = [ip.as_str(), user.as_str()];
+	if let Err(wait) = lockout.begin(&keys) {
+		return Ok(too_many_requests(wait));
+	}
+	let pool = pool.as_ref();
+	let record = sqlx::query!("SELECT totp,totp_step FROM user WHERE name=?", pending.name).fetch_optional(pool).await?;
+	let verified = match record.and_then(|x| Some((Totp::from_base32(&x.totp?)?, x.totp_step))) {
+		Some((totp, last)) => match totp.verify(info.code.trim(), now, last) {
+			// 使用済みステップを更新　同時に同じコードが送られても一方のみ成功させる
+			Some(step) => sqlx::query!("UPDATE user SET totp_step=?1 WHERE name=?2 AND (totp_step IS NULL OR totp_step<?1)", step, pending.name)
+				.execute(pool)
+				.await?
+				.rows_affected() > 0,
+			None => use_recovery_code(pool, &pending.name, &info.code).await?,
+		},
+		None => false,
+	};
+	if verified {
+		lockout.success(&keys, Some(&user));
+		session.remove(TOTP_PENDING);
+		Name::save(&session, &pending.name)?;
+		Ok(HttpResponse::NoContent().finish())
+	} else {
+		Err(ErrorUnauthorized("確認コードが正しくありません").into())
+	}
+}
+
+/// リカバリーコードを照合し、一致したものを使用済みとして削除する
+async fn use_recovery_code(pool: &SqlitePool, name: &str, code: &str) -> MessageResult<bool> {
+	let code = normalize_recovery_code(code);
+	for record in sqlx::query!("SELECT id,code FROM recovery_code WHERE user=?", name).fetch_all(pool).await? {
+		if crate::utils::password::verify(&code, &record.code).map_err(ErrorInternalServerError)? {
+			let deleted = sqlx::query!("DELETE FROM recovery_code WHERE id=?", record.id).execute(pool).await?;
+			return Ok(deleted.rows_affected() > 0);
+		}
+	}
+	Ok(false)
+}
+
 // ログアウト
 async fn logout(session: Session) -> MessageResult<impl Responder> {
 	Name::delete(&session);
@@ -57,13 +182,23 @@ async fn logout(session: Session) -> MessageResult<impl Responder> {
 }
 
 // 新規登録
-async fn register(web::Form(info): web::Form<Authorize>, session: Session, state: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
-	if *state != State::Active {
-		return Err(ErrorForbidden("当サイトはクローズしています").into());
+async fn register(
+	req: HttpRequest,
+	web::Form(info): web::Form<Authorize>,
+	session: Session,
+	lockout: web::Data<Lockout>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
+	// ユーザー名の総当たりを防ぐため、重複による失敗をIP単位で記録する（成功したら数えない）
+	let ip = format!("ip:{}", client_ip(&req));
+	let keys = [ip.as_str()];
+	if let Err(wait) = lockout.begin(&keys) {
+		return Ok(too_many_requests(wait));
 	}
 	let hashed = crate::utils::password::hash(&info.password).map_err(|err| ErrorInternalServerError(err))?;
 	match sqlx::query!("INSERT INTO user(name,password) VALUES(?,?)", info.name, hashed).execute(pool.as_ref()).await {
 		Ok(_) => {
+			lockout.success(&keys, None);
 			Name::save(&session, &info.name)?;
 			Ok(HttpResponse::NoContent().finish())
 		}
@@ -71,3 +206,132 @@ async fn register(web::Form(info): web::Form<Authorize>, session: Session, state
 		Err(err) => Err(err.into()),
 	}
 }
+
+// パスワード再設定の申請
+#[derive(Deserialize)]
+struct Forgot {
+	name: String,
+}
+async fn forgot(
+	req: HttpRequest,
+	web::Form(info): web::Form<Forgot>,
+	limit: web::Data<ResetLimit>,
+	public_url: web::Data<PublicUrl>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
+	// 再設定トークン有効期限(秒)
+	const EXPIRY: i64 = 1800;
+
+	if !limit.ip.hit(&client_ip(&req)) {
+		return Err(ErrorTooManyRequests("しばらく時間をおいてから再度お試しください").into());
+	}
+	// アカウントの有無を推測されないよう、以降は結果に関わらず同じレスポンスを返す
+	let pool = pool.as_ref();
+	let webhook = sqlx::query_scalar!("SELECT webhook FROM user WHERE name=?", info.name).fetch_optional(pool).await?.flatten();
+	if let Some(webhook) = webhook
+		&& limit.user.hit(&info.name)
+	{
+		let mut dst = [0u8; 32];
+		OsRng.try_fill_bytes(&mut dst)?;
+		let token = BASE64_URL_SAFE_NO_PAD.encode(dst);
+		let hashed = hash_token(&token);
+		let timestamp = Local::now().timestamp() + EXPIRY;
+		sqlx::query!("INSERT INTO password_reset(token,timestamp,user) VALUES(?,?,?)", hashed, timestamp, info.name)
+			.execute(pool)
+			.await?;
+		// リンクは設定したURLから作る（リクエストのHostは偽装できる）
+		let url = format!("{}/entry/reset?token={token}", public_url.0);
+		// 送信の成否でレスポンス時間が変わらないよう裏で送る
+		actix_web::rt::spawn(async move {
+			let content = format!(
+				"パスワード再設定の申請を受け付けました。\n{}分以内に以下のURLから再設定してください。\n{url}\n\n心当たりがない場合はこのメッセージを無視してください。",
+				EXPIRY / 60
+			);
+			if let Err(err) = Webhook::new(&content, "untroche", None).send(&webhook).await {
+				eprintln!("{err}");
+			}
+		});
+	}
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// パスワード再設定画面
+#[derive(Deserialize)]
+struct ResetForm {
+	token: String,
+}
+async fn reset_form(web::Query(info): web::Query<ResetForm>) -> PageResult<impl Responder> {
+	let html = Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	}
+	.render("html/reset.html", liquid::object!({ "token": &info.token }))?;
+	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
+}
+
+// パスワード再設定
+#[derive(Deserialize, Validation)]
+struct Reset {
+	token: String,
+	#[validation(name = "パスワード", min = 8)]
+	password: String,
+}
+async fn reset(web::Form(info): web::Form<Reset>, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	info.validate().map_err(ErrorBadRequest)?;
+	let pool = pool.as_ref();
+	let timestamp = Local::now().timestamp();
+	sqlx::query!("DELETE FROM password_reset WHERE timestamp<?", timestamp).execute(pool).await?;
+	// トークンは一度きり
+	let hashed = hash_token(&info.token);
+	let user = sqlx::query_scalar!("DELETE FROM password_reset WHERE token=? RETURNING user", hashed)
+		.fetch_optional(pool)
+		.await?
+		.ok_or(ErrorUnauthorized("URLが無効か、有効期限が切れています"))?;
+	let password = crate::utils::password::hash(&info.password).map_err(ErrorInternalServerError)?;
+	let mut tx = pool.begin().await?;
+	sqlx::query!("UPDATE user SET password=? WHERE name=?", password, user).execute(&mut *tx).await?;
+	// 同じユーザーの他の申請も無効にする
+	sqlx::query!("DELETE FROM password_reset WHERE user=?", user).execute(&mut *tx).await?;
+	// 漏洩したセッション・APIトークンが残らないよう全て破棄する
+	SqliteSessionStore::revoke_all(&mut *tx, &user, None).await?;
+	ApiToken::revoke_all(&mut *tx, &user).await?;
+	auditor.user(&user, "user.reset", &user).record(&mut *tx).await?;
+	tx.commit().await?;
+	Ok(HttpResponse::NoContent().finish())
+}
+
+/// 再設定トークンはDBにハッシュ化して保持する
+fn hash_token(token: &str) -> String {
+	BASE64_STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sqlx::sqlite::SqlitePoolOptions;
+
+	#[actix_web::test]
+	async fn recovery_code_is_single_use() {
+		// インメモリDBは接続ごとに別になるので1接続に限る
+		let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+		sqlx::query("CREATE TABLE recovery_code(id INTEGER PRIMARY KEY, user TEXT NOT NULL, code TEXT NOT NULL)").execute(&pool).await.unwrap();
+		let codes = crate::utils::totp::recovery_codes().unwrap();
+		for (user, code) in [("alice", &codes[0]), ("alice", &codes[1]), ("bob", &codes[2])] {
+			let hashed = crate::utils::password::hash(code).unwrap();
+			sqlx::query("INSERT INTO recovery_code(user,code) VALUES(?,?)").bind(user).bind(hashed).execute(&pool).await.unwrap();
+		}
+
+		// 入力の揺れは吸収する
+		assert!(matches!(use_recovery_code(&pool, "alice", &codes[0].to_ascii_uppercase().replace('-', " ")).await, Ok(true)));
+		// 使用済みのコードは2度目は通らない
+		assert!(matches!(use_recovery_code(&pool, "alice", &codes[0]).await, Ok(false)));
+		// 他のユーザーのコードは通らない
+		assert!(matches!(use_recovery_code(&pool, "alice", &codes[2]).await, Ok(false)));
+		// 残りのコードはそれぞれ使える
+		assert!(matches!(use_recovery_code(&pool, "alice", &codes[1]).await, Ok(true)));
+		assert!(matches!(use_recovery_code(&pool, "bob", &codes[2]).await, Ok(true)));
+		let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM recovery_code").fetch_one(&pool).await.unwrap();
+		assert_eq!(left, 0);
+	}
+}
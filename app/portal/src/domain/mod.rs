@@ -1,23 +1,40 @@
 mod auth;
+mod announcement;
 mod entry;
 mod profile;
 mod report;
 mod user;
 
-use actix_cors::Cors;
-use actix_web::{HttpResponse, Responder, mime, web};
+use actix_web::{HttpResponse, Responder, http::Method, mime, web};
+use common::StateRule;
 
-use crate::utils::{PageResult, Template};
+use crate::utils::{PageResult, StatePolicy, Template, app_client::Origins, state::OPEN};
 
-pub fn cfg(cfg: &mut web::ServiceConfig) {
-	cfg.route("", web::get().to(index));
-	cfg.service(web::scope("auth").wrap(Cors::default().allow_any_origin().allow_any_header()).configure(auth::cfg));
+const POLICY: &[StateRule] = &[StateRule::new("", &[Method::GET], OPEN)];
+
+pub fn cfg(cfg: &mut web::ServiceConfig, origins: web::Data<Origins>) {
+	// アプリ直下の空パスは"/"に合わないので、トップだけは"/"で登録する
+	cfg.route("/", web::get().to(index));
+	cfg.service(web::scope("auth").wrap(auth::cors(origins)).configure(auth::cfg));
+	cfg.service(web::scope("announcement").configure(announcement::cfg));
 	cfg.service(web::scope("entry").configure(entry::cfg));
 	cfg.service(web::scope("user").configure(user::cfg));
 	cfg.service(web::scope("profile").configure(profile::cfg));
 	cfg.service(web::scope("report").configure(report::cfg));
 }
 
+/// 状態ポリシー　ルートを追加したら各モジュールの`POLICY`にも宣言すること（宣言の無いルートは拒否される）
+pub fn policy() -> StatePolicy {
+	StatePolicy::new()
+		.scope("", POLICY)
+		.scope("auth", auth::POLICY)
+		.scope("announcement", announcement::POLICY)
+		.scope("entry", entry::POLICY)
+		.scope("user", user::POLICY)
+		.scope("profile", profile::POLICY)
+		.scope("report", report::POLICY)
+}
+
 async fn index() -> PageResult<impl Responder> {
 	let html = Template::Base {
 		nobots: false,
@@ -27,3 +44,34 @@ async fn index() -> PageResult<impl Responder> {
 	.render("html/index.html", liquid::object!({}))?;
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
 }
+
+#[cfg(test)]
+mod tests {
+	use actix_web::web;
+
+	use crate::utils::app_client::Origins;
+
+	/// 実効ポリシーのスナップショット　宣言を変えたら`UPDATE_SNAPSHOT=1 cargo test`で更新し、差分を確認すること
+	const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/domain/policy.snap");
+
+	#[actix_web::test]
+	async fn policy_covers_routes() {
+		let mismatches = super::policy().mismatches(|cfg| super::cfg(cfg, web::Data::new(Origins::default()))).await;
+		assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+	}
+
+	#[test]
+	fn policy_snapshot() {
+		// main.rsと同じく管理画面は対象外
+		let table: String = super::policy()
+			.exempt("admin")
+			.describe()
+			.iter()
+			.map(|x| format!("{}\t{}\t{}\n", x.pattern, x.methods, x.states))
+			.collect();
+		if std::env::var_os("UPDATE_SNAPSHOT").is_some() {
+			std::fs::write(SNAPSHOT, &table).unwrap();
+		}
+		assert_eq!(table, std::fs::read_to_string(SNAPSHOT).unwrap_or_default(), "実効ポリシーがスナップショットと異なります");
+	}
+}
@@ -1,13 +1,35 @@
-use actix_web::{HttpResponse, Responder, error::*, mime, web};
-use common::Webhook;
-use serde::Deserialize;
+use actix_session::Session;
+use actix_web::{HttpResponse, Responder, error::*, http::Method, mime, web};
+use chrono::Local;
+use common::{ApiToken, Auditor, Authorized, Lockout, SqliteSessionStore, StateRule, Webhook, api_token, lockout::too_many_requests};
+use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use validation::Validation;
 
-use crate::utils::{MessageResult, Name, PageResult, State, StateHandle, Template};
+use crate::utils::{
+	MessageResult, Name, PageResult, Template,
+	state::{ACTIVE, OPEN},
+	totp::{Totp, recovery_codes},
+};
+
+/// 登録途中のTOTPシークレットを保持するセッションキー
+const TOTP_ENROLL: &str = "totp-enroll";
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET, Method::DELETE], OPEN),
+	StateRule::new("", &[Method::PATCH], ACTIVE),
+	StateRule::new("me", &[Method::GET], OPEN),
+	StateRule::new("session", &[Method::GET, Method::DELETE], OPEN),
+	StateRule::new("token", &[Method::GET, Method::POST, Method::DELETE], OPEN),
+	StateRule::new("totp", &[Method::GET, Method::POST, Method::PUT, Method::DELETE], OPEN),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(index).patch(patch).delete(delete));
+	cfg.service(web::resource("me").get(me));
+	cfg.service(web::resource("session").get(sessions).delete(revoke));
+	cfg.service(api_token::resource::<String>("token"));
+	cfg.service(web::resource("totp").get(totp_status).post(totp_begin).put(totp_enable).delete(totp_disable));
 }
 
 // 編集・設定画面
@@ -21,6 +43,24 @@ async fn index() -> PageResult<impl Responder> {
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
 }
 
+// 自分の登録情報（APIトークンでも取得可能）
+#[derive(Serialize)]
+struct Me {
+	name: String,
+	profile: String,
+	webhook: Option<String>,
+}
+async fn me(user: Authorized<String>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let name = user.require(api_token::READ)?;
+	let record = sqlx::query!("SELECT name,profile,webhook FROM user WHERE name=?", name).fetch_one(pool.as_ref()).await?;
+	let body = Me {
+		name: record.name,
+		profile: record.profile,
+		webhook: record.webhook,
+	};
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&body)?))
+}
+
 // 更新処理
 #[derive(Deserialize, Validation)]
 struct Patch {
@@ -37,21 +77,31 @@ struct Password {
 	#[validation(name = "パスワード", min = 8)]
 	new: String,
 }
-async fn patch(web::Json(info): web::Json<Patch>, user: Name, state: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
-	if *state != State::Active {
-		return Err(ErrorForbidden("当サイトはクローズしています").into());
-	}
+async fn patch(
+	web::Json(info): web::Json<Patch>,
+	user: Name,
+	session: Session,
+	auditor: Auditor,
+	lockout: web::Data<Lockout>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
 	// SQL構築
 	let mut builder = sqlx::QueryBuilder::new("UPDATE user SET ");
 	let mut sep = builder.separated(',');
 	// 接続
 	let pool = pool.as_ref();
 	// パスワード
+	let revoke = info.password.is_some();
 	if let Some(password) = info.password {
+		let key = format!("user:{}", *user);
+		if let Err(wait) = lockout.begin(&[&key]) {
+			return Ok(too_many_requests(wait));
+		}
 		let hashed = sqlx::query_scalar!("SELECT password FROM user WHERE name=?", *user).fetch_one(pool).await?;
 		if !crate::utils::password::verify(&password.now, &hashed).map_err(|err| ErrorInternalServerError(err))? {
 			return Err(ErrorForbidden("パスワードが正しくありません").into());
 		}
+		lockout.success(&[&key], Some(&key));
 		let hashed = crate::utils::password::hash(&password.new).map_err(|err| ErrorInternalServerError(err))?;
 		sep.push("password=").push_bind_unseparated(hashed);
 	}
@@ -60,7 +110,11 @@ async fn patch(web::Json(info): web::Json<Patch>, user: Name, state: StateHandle
 		// DBに保持するのは生データ、表示時にエスケープ
 		sep.push("profile=").push_bind_unseparated(v);
 	}
-	// ウェブフック
+	// ウェブフック　監査ログに残すため変更前の値を取っておく
+	let webhook = match &info.webhook {
+		Some(v) => Some((sqlx::query_scalar!("SELECT webhook FROM user WHERE name=?", *user).fetch_one(pool).await?, Some(v.clone()).filter(|x| !x.is_empty()))),
+		None => None,
+	};
 	if let Some(v) = info.webhook {
 		// 値がある場合のみテスト後に確定、無ければNULLをセット
 		if !v.is_empty() {
@@ -79,6 +133,15 @@ async fn patch(web::Json(info): web::Json<Patch>, user: Name, state: StateHandle
 	}
 	builder.push(" WHERE name=").push_bind(&*user);
 	builder.build().execute(pool).await?;
+	if let Some((before, after)) = webhook.filter(|(before, after)| before != after) {
+		auditor.user(&user, "user.webhook", &user).change(before.as_deref(), after.as_deref()).record(pool).await?;
+	}
+	// パスワードを変更したら他のセッションはログアウトさせ、トークンも破棄する
+	if revoke {
+		auditor.user(&user, "user.password", &user).record(pool).await?;
+		SqliteSessionStore::revoke_all(pool, &user, SqliteSessionStore::current(&session).as_deref()).await?;
+		ApiToken::revoke_all(pool, &user).await?;
+	}
 	Ok(HttpResponse::NoContent().finish())
 }
 
@@ -87,12 +150,166 @@ async fn patch(web::Json(info): web::Json<Patch>, user: Name, state: StateHandle
 struct Delete {
 	password: String,
 }
-async fn delete(web::Form(info): web::Form<Delete>, user: Name, _: StateHandle, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+async fn delete(
+	web::Form(info): web::Form<Delete>,
+	user: Name,
+	session: Session,
+	auditor: Auditor,
+	lockout: web::Data<Lockout>,
+	pool: web::Data<SqlitePool>,
+) -> MessageResult<impl Responder> {
+	let key = format!("user:{}", *user);
+	if let Err(wait) = lockout.begin(&[&key]) {
+		return Ok(too_many_requests(wait));
+	}
 	let pool = pool.as_ref();
 	let hashed = sqlx::query_scalar!("SELECT password FROM user WHERE name=?", *user).fetch_one(pool).await?;
 	if !crate::utils::password::verify(&info.password, &hashed).map_err(|err| ErrorInternalServerError(err))? {
 		return Err(ErrorForbidden("パスワードが正しくありません").into());
 	}
+	lockout.success(&[&key], Some(&key));
 	sqlx::query!("DELETE FROM user WHERE name=?", *user).execute(pool).await?;
+	auditor.user(&user, "user.delete", &user).record(pool).await?;
+	SqliteSessionStore::revoke_all(pool, &user, None).await?;
+	ApiToken::revoke_all(pool, &user).await?;
+	Name::delete(&session);
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// ログイン中のセッション一覧
+#[derive(Serialize)]
+struct SessionItem {
+	#[serde(flatten)]
+	record: common::session::Record,
+	current: bool,
+}
+async fn sessions(user: Name, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let current = SqliteSessionStore::current(&session);
+	let list: Vec<_> = SqliteSessionStore::list(pool.as_ref(), &user)
+		.await?
+		.into_iter()
+		.map(|record| SessionItem {
+			current: current.as_ref() == Some(&record.id),
+			record,
+		})
+		.collect();
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&list)?))
+}
+
+// セッションの破棄　idが無ければ現在のセッション以外を全て破棄
+#[derive(Deserialize)]
+struct Revoke {
+	id: Option<String>,
+}
+async fn revoke(web::Query(info): web::Query<Revoke>, user: Name, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let pool = pool.as_ref();
+	let current = SqliteSessionStore::current(&session);
+	match info.id {
+		Some(id) => {
+			if !SqliteSessionStore::revoke(pool, &user, &id).await? {
+				return Err(ErrorNotFound("セッションが存在しません").into());
+			}
+			if current.as_ref() == Some(&id) {
+				Name::delete(&session);
+			}
+		}
+		None => SqliteSessionStore::revoke_all(pool, &user, current.as_deref()).await?,
+	}
+	Ok(HttpResponse::NoContent().finish())
+}
+
+// 2段階認証の状態
+#[derive(Serialize)]
+struct TotpStatus {
+	enabled: bool,
+	/// 残りのリカバリーコード数
+	recovery_codes: i64,
+}
+async fn totp_status(user: Name, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let pool = pool.as_ref();
+	let enabled = sqlx::query_scalar!(r#"SELECT totp IS NOT NULL AS "enabled!: bool" FROM user WHERE name=?"#, *user).fetch_one(pool).await?;
+	let recovery_codes = sqlx::query_scalar!("SELECT COUNT(*) FROM recovery_code WHERE user=?", *user).fetch_one(pool).await?;
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&TotpStatus { enabled, recovery_codes })?))
+}
+
+// 2段階認証の登録開始　シークレットはコードを確認できるまでセッションに置いておく
+#[derive(Serialize)]
+struct TotpBegin {
+	secret: String,
+	uri: String,
+}
+async fn totp_begin(user: Name, session: Session, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let enabled = sqlx::query_scalar!(r#"SELECT totp IS NOT NULL AS "enabled!: bool" FROM user WHERE name=?"#, *user).fetch_one(pool.as_ref()).await?;
+	if enabled {
+		return Err(ErrorConflict("2段階認証は既に有効です").into());
+	}
+	let totp = Totp::generate()?;
+	let secret = totp.base32();
+	session.insert(TOTP_ENROLL, &secret)?;
+	let body = TotpBegin {
+		uri: totp.uri("untroche", &user),
+		secret,
+	};
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&body)?))
+}
+
+// 2段階認証の有効化　リカバリーコードはここでのみ表示する
+#[derive(Deserialize)]
+struct TotpEnable {
+	code: String,
+}
+#[derive(Serialize)]
+struct TotpEnabled {
+	recovery_codes: Vec<String>,
+}
+async fn totp_enable(web::Json(info): web::Json<TotpEnable>, user: Name, session: Session, auditor: Auditor, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let totp = session
+		.get::<String>(TOTP_ENROLL)
+		.ok()
+		.flatten()
+		.and_then(|x| Totp::from_base32(&x))
+		.ok_or(ErrorBadRequest("登録を最初からやり直してください"))?;
+	let step = totp.verify(info.code.trim(), Local::now().timestamp(), None).ok_or(ErrorBadRequest("確認コードが正しくありません"))?;
+	let codes = recovery_codes()?;
+	let hashed = codes
+		.iter()
+		.map(|x| crate::utils::password::hash(x))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(ErrorInternalServerError)?;
+	let secret = totp.base32();
+	let mut tx = pool.begin().await?;
+	let updated = sqlx::query!("UPDATE user SET totp=?,totp_step=? WHERE name=? AND totp IS NULL", secret, step, *user)
+		.execute(&mut *tx)
+		.await?;
+	if updated.rows_affected() == 0 {
+		return Err(ErrorConflict("2段階認証は既に有効です").into());
+	}
+	sqlx::query!("DELETE FROM recovery_code WHERE user=?", *user).execute(&mut *tx).await?;
+	for code in hashed {
+		sqlx::query!("INSERT INTO recovery_code(user,code) VALUES(?,?)", *user, code).execute(&mut *tx).await?;
+	}
+	auditor.user(&user, "user.totp", &user).change(None, Some("enabled")).record(&mut *tx).await?;
+	tx.commit().await?;
+	session.remove(TOTP_ENROLL);
+	Ok(HttpResponse::Ok().content_type(mime::APPLICATION_JSON).body(serde_json::to_string(&TotpEnabled { recovery_codes: codes })?))
+}
+
+// 2段階認証の無効化
+async fn totp_disable(web::Form(info): web::Form<Delete>, user: Name, auditor: Auditor, lockout: web::Data<Lockout>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	let key = format!("user:{}", *user);
+	if let Err(wait) = lockout.begin(&[&key]) {
+		return Ok(too_many_requests(wait));
+	}
+	let pool = pool.as_ref();
+	let hashed = sqlx::query_scalar!("SELECT password FROM user WHERE name=?", *user).fetch_one(pool).await?;
+	if !crate::utils::password::verify(&info.password, &hashed).map_err(ErrorInternalServerError)? {
+		return Err(ErrorForbidden("パスワードが正しくありません").into());
+	}
+	lockout.success(&[&key], Some(&key));
+	let mut tx = pool.begin().await?;
+	sqlx::query!("UPDATE user SET totp=NULL,totp_step=NULL WHERE name=?", *user).execute(&mut *tx).await?;
+	sqlx::query!("DELETE FROM recovery_code WHERE user=?", *user).execute(&mut *tx).await?;
+	auditor.user(&user, "user.totp", &user).change(Some("enabled"), None).record(&mut *tx).await?;
+	tx.commit().await?;
 	Ok(HttpResponse::NoContent().finish())
 }
@@ -1,35 +1,104 @@
-use actix_web::{HttpResponse, Responder, mime, web};
+use std::str::FromStr;
+
+use actix_web::{HttpRequest, HttpResponse, Responder, error::*, http::Method, mime, web};
 use chrono::Local;
+use common::{StateRule, client_addr::client_ip};
 use serde::Deserialize;
 use sqlx::SqlitePool;
+use validation::Validation;
+
+use crate::utils::{
+	MessageResult, Name, PageResult, Template, deser_flag,
+	rate_limit::ReportLimit,
+	report::Target,
+	state::{ACTIVE, OPEN},
+};
 
-use crate::utils::{MessageResult, Name, PageResult, Template};
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET], OPEN),
+	StateRule::new("", &[Method::POST], ACTIVE),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(index).post(post));
 }
 
 // 画面表示
-async fn index() -> PageResult<impl Responder> {
+async fn index(user: Option<Name>) -> PageResult<impl Responder> {
 	let html = Template::Base {
 		nobots: true,
 		summary: None,
 		user: None,
 	}
-	.render("html/report.html", liquid::object!({}))?;
+	.render("html/report.html", liquid::object!({ "login": user.is_some() }))?;
 	Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML).body(html))
 }
 
 // 投稿
-#[derive(Deserialize)]
+#[derive(Deserialize, Validation)]
 struct Post {
+	#[serde(default)]
+	title: String,
+	#[serde(default)]
+	category: String,
+	#[validation(name = "内容", min = 1, max = 4000)]
 	body: String,
+	/// 対象の種類　空欄なら対象なし
+	#[serde(default)]
+	target_type: String,
+	#[serde(default)]
+	target: String,
+	/// 解決時にウェブフックで知らせる（ログイン時のみ）
+	#[serde(default, deserialize_with = "deser_flag")]
+	notify: bool,
 }
-async fn post(web::Form(info): web::Form<Post>, user: Option<Name>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+async fn post(req: HttpRequest, web::Form(info): web::Form<Post>, user: Option<Name>, limit: web::Data<ReportLimit>, pool: web::Data<SqlitePool>) -> MessageResult<impl Responder> {
+	info.validate().map_err(ErrorBadRequest)?;
+	// 匿名の通報はIP単位で制限する
+	if user.is_none() && !limit.ip.hit(&client_ip(&req)) {
+		return Err(ErrorTooManyRequests("しばらく時間をおいてから再度お試しください").into());
+	}
+	let pool = pool.as_ref();
+	let target = info.target.trim();
+	let target_type = match info.target_type.as_str() {
+		"" => None,
+		x => Some(Target::from_str(x).map_err(|_| ErrorBadRequest("無効な対象が指定されました"))?),
+	};
+	match target_type {
+		None if !target.is_empty() => return Err(ErrorBadRequest("対象の種類を選択してください").into()),
+		None => (),
+		Some(_) if target.is_empty() => return Err(ErrorBadRequest("対象を入力してください").into()),
+		Some(Target::User) => {
+			sqlx::query_scalar!("SELECT name FROM user WHERE name=?", target)
+				.fetch_optional(pool)
+				.await?
+				.ok_or(ErrorNotFound("対象のユーザーが存在しません"))?;
+		}
+		// アプリ側のDBは参照できないので形式のみ確認する
+		Some(Target::Actor | Target::Timeline) if !target.parse::<i64>().is_ok_and(|x| x > 0) => {
+			return Err(ErrorBadRequest("対象は番号で入力してください").into());
+		}
+		Some(Target::Actor | Target::Timeline) => (),
+	}
+	let target_type = target_type.map(|x| x.to_string());
+	let target = Some(target).filter(|x| !x.is_empty());
+	let body = match (info.title.as_str(), info.category.as_str()) {
+		("", "") => info.body,
+		(title, category) => format!("[{title}/{category}]\n{}", info.body),
+	};
 	let timestamp = Local::now().timestamp();
+	let notify = info.notify && user.is_some();
 	let user = user.as_deref();
-	sqlx::query!("INSERT INTO report(timestamp,user,body) VALUES(?,?,?)", timestamp, user, info.body)
-		.execute(pool.as_ref())
-		.await?;
+	sqlx::query!(
+		"INSERT INTO report(timestamp,user,target_type,target,body,notify) VALUES(?,?,?,?,?,?)",
+		timestamp,
+		user,
+		target_type,
+		target,
+		body,
+		notify
+	)
+	.execute(pool)
+	.await?;
 	Ok(HttpResponse::NoContent().finish())
 }
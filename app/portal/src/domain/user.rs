@@ -1,8 +1,14 @@
-use actix_web::{HttpResponse, Responder, mime, web};
+use actix_web::{HttpResponse, Responder, http::Method, mime, web};
+use common::StateRule;
 use serde::{Deserialize, Serialize};
 use sqlx::{SqlitePool, prelude::FromRow};
 
-use crate::utils::{MessageResult, PageParams, PageResult, Template, template::Summary};
+use crate::utils::{MessageResult, PageParams, PageResult, Template, state::OPEN, template::Summary};
+
+pub const POLICY: &[StateRule] = &[
+	StateRule::new("", &[Method::GET, Method::POST], OPEN),
+	StateRule::new("{name}", &[Method::GET], OPEN),
+];
 
 pub fn cfg(cfg: &mut web::ServiceConfig) {
 	cfg.service(web::resource("").get(list).post(search));
@@ -2,9 +2,9 @@ mod admin;
 mod domain;
 mod utils;
 
-use actix_session::{SessionMiddleware, config::PersistentSession, storage};
+use actix_session::{SessionMiddleware, config::PersistentSession};
 use actix_web::{App, HttpResponse, HttpServer, cookie, middleware, web};
-use common::AdminGuardMiddleware;
+use common::{AdminGuardMiddleware, ImpersonationGuard, Schedule, SqliteSessionStore, admin_page, backup};
 
 const APP_PATH: &str = "app/portal";
 
@@ -13,29 +13,57 @@ async fn main() -> std::io::Result<()> {
 	// 環境変数読み込み
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 	let load_env = |path: &str| std::env::var(path).expect(&format!("`{path}` is undefined"));
+	let db_url = load_env("DATABASE_URL");
+	// `restore <スナップショット>`ならサーバーを起動せずにDBを復元する（サーバーは止めておくこと）
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	if let [command, file] = args.as_slice()
+		&& command == "restore"
+	{
+		return backup::restore(&db_url, file.as_ref()).await.map_err(std::io::Error::other);
+	}
 	let host = load_env("SERVER_HOST");
 	let port = load_env("SERVER_PORT");
-	let db_url = load_env("DATABASE_URL");
+	let public_url = web::Data::new(crate::utils::PublicUrl(load_env("PUBLIC_URL").trim_end_matches('/').to_string()));
 
 	// 設定初期化・必要な変数の読み込み
 	let app = crate::utils::AppData::new(&db_url).await;
+	// 予約された状態変更の実行
+	Schedule::spawn(app.pool.as_ref().clone(), app.state.clone(), crate::utils::STATE, crate::utils::MAINTENANCE);
+	// 定期バックアップ
+	app.backup.spawn(app.pool.as_ref().clone());
+
+	// サーバー状態による制限　管理画面は独自のガードがあるので対象外
+	let policy = domain::policy().exempt("admin");
 
 	// サーバー構築
 	let server = HttpServer::new(move || {
 		let app = app.clone();
-		let session = SessionMiddleware::builder(storage::CookieSessionStore::default(), app.session_key)
+		let session = SessionMiddleware::builder(SqliteSessionStore::new(app.pool.as_ref().clone()), app.session_key)
 			.cookie_secure(false)
 			.session_lifecycle(PersistentSession::default().session_ttl(cookie::time::Duration::days(14)))
 			.build();
 		App::new()
+			.wrap(policy.clone())
+			.wrap(ImpersonationGuard::new("/admin"))
 			.wrap(middleware::Logger::default())
 			.wrap(middleware::NormalizePath::trim())
 			.wrap(session)
 			.default_service(web::to(|| HttpResponse::NotFound()))
-			.app_data(app.pool)
+			.app_data(app.pool.clone())
+			.app_data(web::Data::new(policy.clone()))
 			.app_data(app.state)
-			.service(web::scope("admin").wrap(AdminGuardMiddleware(app.admin_key)).configure(admin::cfg))
-			.configure(domain::cfg)
+			.app_data(app.suspended)
+			.app_data(app.maintenance)
+			.app_data(app.admin_page)
+			.app_data(app.backup)
+			.app_data(app.origins.clone())
+			.app_data(app.reset_limit)
+			.app_data(public_url.clone())
+			.app_data(app.report_limit)
+			.app_data(app.lockout)
+			.service(web::scope("admin/entry").configure(admin_page::entry))
+			.service(web::scope("admin").wrap(AdminGuardMiddleware::new(app.pool.as_ref().clone(), "/admin/entry")).configure(admin::cfg))
+			.configure(|cfg| domain::cfg(cfg, app.origins))
 	});
 	server.bind(format!("{host}:{port}"))?.run().await
 }
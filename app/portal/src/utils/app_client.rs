@@ -0,0 +1,79 @@
+use std::{collections::HashSet, ops::Deref, pin::Pin, sync::RwLock};
+
+use actix_web::{FromRequest, error::*, http::header, web};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::Local;
+use sqlx::SqlitePool;
+
+/// 登録済みクライアントの許可オリジン一覧
+///
+/// CORSの判定は同期的に行われるため、DBの内容をメモリに保持しておく
+#[derive(Default)]
+pub struct Origins(RwLock<HashSet<String>>);
+
+impl Origins {
+	/// DBから読み込み直す　クライアント情報を変更した場合は必ず呼ぶこと
+	pub async fn reload(&self, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+		let records = sqlx::query_scalar!("SELECT origins FROM app_client").fetch_all(pool).await?;
+		let origins = records.iter().flat_map(|x| x.lines()).map(String::from).collect();
+		if let Ok(mut guard) = self.0.write() {
+			*guard = origins;
+		}
+		Ok(())
+	}
+	pub fn contains(&self, origin: &str) -> bool {
+		self.0.read().is_ok_and(|x| x.contains(origin))
+	}
+}
+
+/// Basic認証(client_id:client_secret)で認証済みのクライアント
+///
+/// サーバー間通信用のエンドポイントで使用する
+pub struct AppClient(String);
+
+impl Deref for AppClient {
+	type Target = String;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+impl FromRequest for AppClient {
+	type Error = actix_web::Error;
+	type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+	fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+		let pool = req.app_data::<web::Data<SqlitePool>>().cloned();
+		let credentials = req
+			.headers()
+			.get(header::AUTHORIZATION)
+			.and_then(|x| x.to_str().ok())
+			.and_then(|x| x.strip_prefix("Basic "))
+			.and_then(|x| BASE64_STANDARD.decode(x).ok())
+			.and_then(|x| String::from_utf8(x).ok());
+		Box::pin(async move {
+			let pool = pool.ok_or(ErrorInternalServerError("DBが未定義"))?;
+			let (id, secret) = credentials.as_deref().and_then(|x| x.split_once(':')).ok_or(ErrorUnauthorized("クライアント認証が必要です"))?;
+			let hashed = sqlx::query_scalar!("SELECT secret FROM app_client WHERE id=?", id)
+				.fetch_optional(pool.as_ref())
+				.await
+				.map_err(ErrorInternalServerError)?
+				.flatten()
+				.ok_or(ErrorUnauthorized("クライアント認証に失敗しました"))?;
+			if !crate::utils::password::verify(secret, &hashed).map_err(ErrorInternalServerError)? {
+				log(pool.as_ref(), id, None, "secret").await.map_err(ErrorInternalServerError)?;
+				return Err(ErrorUnauthorized("クライアント認証に失敗しました"));
+			}
+			Ok(Self(id.into()))
+		})
+	}
+}
+
+/// クライアントからのリクエスト結果を記録する
+pub async fn log(pool: &SqlitePool, client: &str, user: Option<&str>, result: &str) -> Result<(), sqlx::Error> {
+	let timestamp = Local::now().timestamp();
+	sqlx::query!("INSERT INTO app_client_log(timestamp,client,user,result) VALUES(?,?,?,?)", timestamp, client, user, result)
+		.execute(pool)
+		.await?;
+	Ok(())
+}
@@ -1,22 +1,32 @@
+pub mod app_client;
 pub mod app_data;
 pub mod error;
 pub mod page_params;
 pub mod password;
+pub mod rate_limit;
+pub mod report;
 pub mod state;
 pub mod tag_format;
 pub mod template;
+pub mod totp;
 
 use html_codec::HTMLEncode;
 use serde::{Deserialize as _, Deserializer};
 
 pub use self::{app_data::AppData, error::*, page_params::PageParams, state::State, tag_format::CommonTag, template::Template};
 
-pub type StateHandle = common::StateHandle<State>;
+pub type StatePolicy = common::StatePolicy<State>;
 pub type Name = common::Identity<String>;
 
 // 変数定義
 pub const STATE: &str = "STATE";
-const KEY: &str = "KEY";
+/// メンテナンスの内容（`common::Maintenance`のJSON）
+pub const MAINTENANCE: &str = "MAINTENANCE";
+pub const KEY: &str = "KEY";
+
+/// 外部から見たポータルのURL（環境変数`PUBLIC_URL`、末尾の`/`なし）　ウェブフックで送るリンクに使う
+/// リクエストの`Host`・`X-Forwarded-Host`は偽装できるので、リンクの生成には使わないこと
+pub struct PublicUrl(pub String);
 
 /// リソースへのパスを生成する
 pub fn resource(path: &str) -> String {
@@ -1,12 +1,12 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, de};
 
 const PAGE_LIMIT: usize = 255;
 
 #[derive(Deserialize)]
 pub struct PageParams {
-	#[serde(default = "usize::default")]
+	#[serde(default = "usize::default", deserialize_with = "deser_usize")]
 	pub page: usize,
-	#[serde(default = "page_limit_default")]
+	#[serde(default = "page_limit_default", deserialize_with = "deser_usize")]
 	pub limit: usize,
 }
 impl PageParams {
@@ -21,3 +21,20 @@ impl PageParams {
 fn page_limit_default() -> usize {
 	20
 }
+
+/// `#[serde(flatten)]`の内側ではクエリの値が文字列のまま渡ってくるので、文字列でも受け付ける
+fn deser_usize<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Value {
+		Number(usize),
+		String(String),
+	}
+	match Value::deserialize(deserializer)? {
+		Value::Number(x) => Ok(x),
+		Value::String(x) => x.parse().map_err(de::Error::custom),
+	}
+}
@@ -0,0 +1,68 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::Mutex,
+};
+
+use chrono::Local;
+
+/// 一定時間内の試行回数をキーごとに制限する
+pub struct RateLimit {
+	limit: usize,
+	window: i64,
+	records: Mutex<HashMap<String, VecDeque<i64>>>,
+}
+
+impl RateLimit {
+	/// `window`秒間に`limit`回まで許可する
+	pub fn new(limit: usize, window: i64) -> Self {
+		Self {
+			limit,
+			window,
+			records: Mutex::new(HashMap::new()),
+		}
+	}
+	/// 試行を記録し、制限内ならtrueを返す
+	pub fn hit(&self, key: &str) -> bool {
+		let now = Local::now().timestamp();
+		let Ok(mut records) = self.records.lock() else {
+			return false;
+		};
+		// 期限切れの記録を掃除
+		records.retain(|_, x| {
+			while x.front().is_some_and(|t| *t <= now - self.window) {
+				x.pop_front();
+			}
+			!x.is_empty()
+		});
+		let record = records.entry(key.into()).or_default();
+		if record.len() >= self.limit {
+			return false;
+		}
+		record.push_back(now);
+		true
+	}
+}
+
+/// パスワード再設定の申請制限
+pub struct ResetLimit {
+	pub ip: RateLimit,
+	pub user: RateLimit,
+}
+impl Default for ResetLimit {
+	fn default() -> Self {
+		Self {
+			ip: RateLimit::new(5, 3600),
+			user: RateLimit::new(3, 3600),
+		}
+	}
+}
+
+/// 通報の投稿制限　ログインしていない場合のみIP単位で制限する
+pub struct ReportLimit {
+	pub ip: RateLimit,
+}
+impl Default for ReportLimit {
+	fn default() -> Self {
+		Self { ip: RateLimit::new(5, 3600) }
+	}
+}
@@ -0,0 +1,90 @@
+use std::{fmt::Display, str::FromStr};
+
+/// 通報の対応状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+	Open,
+	Investigating,
+	Resolved,
+	Rejected,
+}
+
+impl Status {
+	pub const ALL: [Self; 4] = [Self::Open, Self::Investigating, Self::Resolved, Self::Rejected];
+
+	/// 画面表示用
+	pub fn label(&self) -> &'static str {
+		match self {
+			Self::Open => "未対応",
+			Self::Investigating => "調査中",
+			Self::Resolved => "解決",
+			Self::Rejected => "却下",
+		}
+	}
+}
+impl Display for Status {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Open => "open",
+			Self::Investigating => "investigating",
+			Self::Resolved => "resolved",
+			Self::Rejected => "rejected",
+		})
+	}
+}
+impl FromStr for Status {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"open" => Self::Open,
+			"investigating" => Self::Investigating,
+			"resolved" => Self::Resolved,
+			"rejected" => Self::Rejected,
+			_ => return Err(()),
+		})
+	}
+}
+
+/// 通報の対象
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+	/// ポータルのユーザー（名前）
+	User,
+	/// erltodのキャラクター（Eno）
+	Actor,
+	/// erltodの発言（ID）
+	Timeline,
+}
+
+impl Target {
+	/// 画面表示用
+	pub fn label(&self) -> &'static str {
+		match self {
+			Self::User => "ユーザー",
+			Self::Actor => "キャラクター",
+			Self::Timeline => "発言",
+		}
+	}
+}
+impl Display for Target {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::User => "user",
+			Self::Actor => "actor",
+			Self::Timeline => "timeline",
+		})
+	}
+}
+impl FromStr for Target {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"user" => Self::User,
+			"actor" => Self::Actor,
+			"timeline" => Self::Timeline,
+			_ => return Err(()),
+		})
+	}
+}
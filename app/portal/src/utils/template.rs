@@ -1,6 +1,9 @@
+use chrono::{DateTime, Local};
+use common::{Announcement, Impersonation, Maintenance, Schedule, Suspension};
+use html_codec::HTMLEncode as _;
 use serde::Serialize;
 
-use super::resource;
+use super::{CommonTag, resource};
 
 /// テンプレート種別
 pub enum Template {
@@ -32,6 +35,9 @@ impl Template {
 				"nobots": &nobots,
 				"summary": &summary,
 				"user": &user,
+				"schedule": &schedule(),
+				"announcements": &Notice::active(),
+				"impersonation": &impersonation(),
 			})),
 			Template::Popup => parser.parse_file(resource("template/popup.html"))?.render(&liquid::object!({
 				"main": &main,
@@ -47,6 +53,9 @@ impl Template {
 				"nobots": &nobots,
 				"summary": &summary,
 				"user": &user,
+				"schedule": &schedule(),
+				"announcements": &Notice::active(),
+				"impersonation": &impersonation(),
 			})),
 			Template::Popup => parser.parse_file(resource("template/popup.html"))?.render(&liquid::object!({
 				"main": &text,
@@ -55,3 +64,89 @@ impl Template {
 		}
 	}
 }
+
+/// メンテナンス中に表示するページ　`common::MaintenancePage`として登録する
+pub fn maintenance(maintenance: &Maintenance) -> String {
+	let until = maintenance
+		.until
+		.and_then(|x| DateTime::from_timestamp(x, 0))
+		.map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+	let tpl = Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	};
+	tpl.render("html/maintenance.html", liquid::object!({ "message": &maintenance.message, "until": &until }))
+		.unwrap_or_else(|err| format!("メンテナンス中です\n\n\n(liquid error)\n{err}"))
+}
+
+/// 表示用のお知らせ　本文はCommonTagを変換したHTMLにする
+#[derive(Serialize)]
+pub struct Notice {
+	#[serde(flatten)]
+	pub announcement: Announcement,
+	pub html: String,
+}
+impl Notice {
+	/// このアプリに表示中のお知らせ
+	pub fn active() -> Vec<Self> {
+		Self::active_for(env!("CARGO_PKG_NAME"))
+	}
+	pub fn active_for(app: &str) -> Vec<Self> {
+		Announcement::active(app)
+			.into_iter()
+			.map(|x| Self {
+				html: x.body.escape(false).tag(CommonTag).br().into_owned(),
+				announcement: x,
+			})
+			.collect()
+	}
+}
+
+/// 次に予約されている状態変更　レイアウトで告知する
+fn schedule() -> Option<liquid::Object> {
+	Schedule::upcoming().into_iter().next().map(|x| {
+		let at = DateTime::from_timestamp(x.at, 0).map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+		liquid::object!({ "state": &x.state, "at": &at })
+	})
+}
+
+/// 管理者がなりすまし中ならその内容　レイアウトにバナーを出す
+fn impersonation() -> Option<liquid::Object> {
+	Impersonation::current().map(|x| liquid::object!({ "admin": &x.admin, "user": &x.user_key() }))
+}
+
+/// 管理画面のログイン画面など、ナビゲーション無しのページ　`common::AdminPage`として登録する
+pub fn admin_plain(main: &str) -> Result<String, liquid::Error> {
+	Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	}
+	.render_raw(main)
+}
+
+/// 管理画面の共通レイアウト（ナビゲーション付き）　`common::AdminPage`として登録する
+pub fn admin_layout(main: &str) -> Result<String, liquid::Error> {
+	Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	}
+	.render("html/admin/layout.html", liquid::object!({ "main": main }))
+}
+
+/// 利用停止中のユーザーに表示するページ　`common::SuspendedPage`として登録する
+pub fn suspended(suspension: &Suspension) -> String {
+	let expiry = suspension
+		.expiry
+		.and_then(|x| DateTime::from_timestamp(x, 0))
+		.map(|x| x.with_timezone(&Local).format("%Y/%m/%d %H:%M").to_string());
+	let tpl = Template::Base {
+		nobots: true,
+		summary: None,
+		user: None,
+	};
+	tpl.render("html/suspended.html", liquid::object!({ "reason": &suspension.reason, "expiry": &expiry }))
+		.unwrap_or_else(|err| format!("アカウントが利用停止中です\n理由: {}\n\n\n(liquid error)\n{err}", suspension.reason))
+}
@@ -0,0 +1,222 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac as _};
+use rand::{TryRngCore as _, rngs::OsRng};
+use sha1::Sha1;
+use url::Url;
+
+const ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+/// RFC 6238 TOTP（HMAC-SHA1・6桁・30秒）
+///
+/// 時刻は引数で受け取るので、固定した時刻で検証できる
+pub struct Totp {
+	secret: Vec<u8>,
+}
+
+impl Totp {
+	/// 1ステップの秒数
+	pub const PERIOD: i64 = 30;
+	/// 桁数
+	pub const DIGITS: u32 = 6;
+	/// 時刻のずれとして許容する前後のステップ数
+	pub const DRIFT: i64 = 1;
+
+	/// 新しいシークレットを生成する
+	pub fn generate() -> Result<Self, rand::rand_core::OsError> {
+		let mut secret = vec![0u8; 20];
+		OsRng.try_fill_bytes(&mut secret)?;
+		Ok(Self { secret })
+	}
+	/// DBに保持したシークレットから復元する
+	pub fn from_base32(secret: &str) -> Option<Self> {
+		base32::decode(ALPHABET, secret).filter(|x| !x.is_empty()).map(|secret| Self { secret })
+	}
+	pub fn base32(&self) -> String {
+		base32::encode(ALPHABET, &self.secret)
+	}
+
+	/// 認証アプリに登録するためのURI
+	pub fn uri(&self, issuer: &str, account: &str) -> String {
+		let mut url = Url::parse("otpauth://totp/").unwrap();
+		url.set_path(&format!("{issuer}:{account}"));
+		url.query_pairs_mut()
+			.append_pair("secret", &self.base32())
+			.append_pair("issuer", issuer)
+			.append_pair("algorithm", "SHA1")
+			.append_pair("digits", &Self::DIGITS.to_string())
+			.append_pair("period", &Self::PERIOD.to_string());
+		url.to_string()
+	}
+
+	/// UNIXタイムが属するステップ
+	pub fn step(now: i64) -> i64 {
+		now.div_euclid(Self::PERIOD)
+	}
+
+	/// 指定ステップのコード
+	pub fn code(&self, step: i64) -> String {
+		let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC can take key of any size");
+		mac.update(&step.to_be_bytes());
+		let hash = mac.finalize().into_bytes();
+		// dynamic truncation
+		let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+		let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+		format!("{:0width$}", value % 10u32.pow(Self::DIGITS), width = Self::DIGITS as usize)
+	}
+
+	/// コードを照合し、一致したステップを返す
+	///
+	/// `last`以前のステップは使用済みとして扱い、同じコードの再利用を防ぐ
+	pub fn verify(&self, code: &str, now: i64, last: Option<i64>) -> Option<i64> {
+		if code.len() != Self::DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+			return None;
+		}
+		let step = Self::step(now);
+		(step - Self::DRIFT..=step + Self::DRIFT)
+			.filter(|x| last.is_none_or(|last| *x > last))
+			.find(|x| constant_time_eq(self.code(*x).as_bytes(), code.as_bytes()))
+	}
+}
+
+/// リカバリーコードを生成する（表示用・ハッシュ化前）
+pub fn recovery_codes() -> Result<Vec<String>, rand::rand_core::OsError> {
+	// 発行数
+	const COUNT: usize = 10;
+
+	(0..COUNT)
+		.map(|_| {
+			let mut dst = [0u8; 5];
+			OsRng.try_fill_bytes(&mut dst)?;
+			// 5バイト = base32で8文字
+			let code = base32::encode(ALPHABET, &dst).to_ascii_lowercase();
+			Ok(format!("{}-{}", &code[..4], &code[4..]))
+		})
+		.collect()
+}
+
+/// 入力されたリカバリーコードを照合用の形式に揃える
+pub fn normalize_recovery_code(code: &str) -> String {
+	let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase();
+	if code.len() == 8 { format!("{}-{}", &code[..4], &code[4..]) } else { code }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// RFC 6238 付録Bのシークレット（SHA1）
+	fn rfc6238() -> Totp {
+		Totp { secret: b"12345678901234567890".to_vec() }
+	}
+
+	#[test]
+	fn rfc6238_vectors() {
+		// 付録Bの8桁の値の下6桁
+		let totp = rfc6238();
+		for (now, code) in [
+			(59, "287082"),
+			(1111111109, "081804"),
+			(1111111111, "050471"),
+			(1234567890, "005924"),
+			(2000000000, "279037"),
+			(20000000000, "353130"),
+		] {
+			assert_eq!(totp.code(Totp::step(now)), code, "T={now}");
+			assert_eq!(totp.verify(code, now, None), Some(Totp::step(now)), "T={now}");
+		}
+	}
+
+	#[test]
+	fn base32_round_trip() {
+		let totp = Totp::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+		assert_eq!(totp.secret, rfc6238().secret);
+		assert_eq!(totp.base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+		assert!(Totp::from_base32("").is_none());
+		assert!(Totp::from_base32("!!!!").is_none());
+	}
+
+	#[test]
+	fn drift_window() {
+		let totp = rfc6238();
+		let now = 1111111111;
+		let step = Totp::step(now);
+		// 前後DRIFTステップまでは通す
+		assert_eq!(totp.verify(&totp.code(step - Totp::DRIFT), now, None), Some(step - Totp::DRIFT));
+		assert_eq!(totp.verify(&totp.code(step + Totp::DRIFT), now, None), Some(step + Totp::DRIFT));
+		// その外側は通さない
+		assert_eq!(totp.verify(&totp.code(step - Totp::DRIFT - 1), now, None), None);
+		assert_eq!(totp.verify(&totp.code(step + Totp::DRIFT + 1), now, None), None);
+	}
+
+	#[test]
+	fn drift_window_at_step_boundary() {
+		let totp = rfc6238();
+		let step = Totp::step(1111111111);
+		let code = totp.code(step);
+		// ステップの先頭・末尾の時刻と、窓がずれて外れる直前・直後の時刻
+		let first = step * Totp::PERIOD;
+		let last = first + Totp::PERIOD - 1;
+		assert_eq!(totp.verify(&code, first, None), Some(step));
+		assert_eq!(totp.verify(&code, last, None), Some(step));
+		assert_eq!(totp.verify(&code, first - Totp::DRIFT * Totp::PERIOD, None), Some(step));
+		assert_eq!(totp.verify(&code, first - Totp::DRIFT * Totp::PERIOD - 1, None), None);
+		assert_eq!(totp.verify(&code, last + Totp::DRIFT * Totp::PERIOD, None), Some(step));
+		assert_eq!(totp.verify(&code, last + Totp::DRIFT * Totp::PERIOD + 1, None), None);
+	}
+
+	#[test]
+	fn replay_is_rejected() {
+		let totp = rfc6238();
+		let now = 1111111111;
+		let step = Totp::step(now);
+		let code = totp.code(step);
+		// 一度使ったステップとそれ以前は通さない
+		assert_eq!(totp.verify(&code, now, Some(step)), None);
+		assert_eq!(totp.verify(&code, now, Some(step + 1)), None);
+		assert_eq!(totp.verify(&totp.code(step - 1), now, Some(step - 1)), None);
+		// 使用済みより後のステップは通す
+		assert_eq!(totp.verify(&code, now, Some(step - 1)), Some(step));
+		assert_eq!(totp.verify(&totp.code(step + 1), now, Some(step)), Some(step + 1));
+	}
+
+	#[test]
+	fn malformed_code_is_rejected() {
+		let totp = rfc6238();
+		let now = 1111111111;
+		let code = totp.code(Totp::step(now));
+		assert_eq!(totp.verify(&code[1..], now, None), None);
+		assert_eq!(totp.verify(&format!("{code}0"), now, None), None);
+		assert_eq!(totp.verify(&format!(" {}", &code[1..]), now, None), None);
+		assert_eq!(totp.verify("", now, None), None);
+		assert_eq!(totp.verify("05047a", now, None), None);
+	}
+
+	#[test]
+	fn recovery_codes_format() {
+		let codes = recovery_codes().unwrap();
+		assert_eq!(codes.len(), 10);
+		for code in &codes {
+			assert_eq!(code.len(), 9);
+			assert_eq!(&code[4..5], "-");
+			// 発行した形式のまま入力すればそのまま照合できる
+			assert_eq!(&normalize_recovery_code(code), code);
+		}
+		let mut unique = codes.clone();
+		unique.sort();
+		unique.dedup();
+		assert_eq!(unique.len(), codes.len());
+	}
+
+	#[test]
+	fn recovery_code_normalization() {
+		assert_eq!(normalize_recovery_code("abcd-efgh"), "abcd-efgh");
+		assert_eq!(normalize_recovery_code("ABCDEFGH"), "abcd-efgh");
+		assert_eq!(normalize_recovery_code(" abcd efgh\n"), "abcd-efgh");
+		// 長さが違うものは区切らない（照合で一致しない）
+		assert_eq!(normalize_recovery_code("abcd-efg"), "abcdefg");
+	}
+}